@@ -1 +1,46 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use flux::blockchain::block::Block;
+use flux::blockchain::transaction::Transaction;
+use flux::crypto::{Hash, Hashable, KeyPair};
 
+fn sample_block(transactions: usize) -> Block {
+    let keypair = KeyPair::generate();
+    let transactions = (0..transactions as u64)
+        .map(|nonce| {
+            let mut transaction =
+                Transaction::new(keypair.public_key(), keypair.public_key(), 1, nonce);
+            transaction.sign(vec![0u8; 64]);
+            transaction
+        })
+        .collect();
+    let mut block = Block::new(
+        Hash::default(),
+        transactions,
+        1,
+        0,
+        0,
+        keypair.public_key(),
+        0,
+    );
+    block.sign(&keypair);
+    block
+}
+
+fn block_benchmarks(c: &mut Criterion) {
+    let block = sample_block(1000);
+    let bytes = block.encode().unwrap();
+
+    c.bench_function("block hash", |b| b.iter(|| black_box(&block).hash()));
+    c.bench_function("block encode", |b| {
+        b.iter(|| black_box(&block).encode().unwrap())
+    });
+    c.bench_function("block decode", |b| {
+        b.iter(|| Block::decode(black_box(&bytes)).unwrap())
+    });
+    c.bench_function("block body check", |b| {
+        b.iter(|| black_box(&block).has_valid_body())
+    });
+}
+
+criterion_group!(benches, block_benchmarks);
+criterion_main!(benches);
@@ -1,13 +1,18 @@
-use crate::crypto::{Hash, Hashable, PublicKey};
+use crate::crypto::{merkle_root, verify_signature, Hash, Hashable, KeyPair, PublicKey};
 
 use crate::blockchain::transaction::Transaction;
+use crate::consensus::evidence::Evidence;
+use crate::network::protocol::MAX_BLOCK_SIZE;
+use crate::network::wire;
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
     pub header: BlockHeader,
     pub transactions: Vec<Transaction>,
+    pub evidence: Vec<Evidence>,
+    /// The producer's signature over the header hash.
+    pub signature: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,9 +20,22 @@ pub struct BlockHeader {
     pub version: u32,
     pub previous_hash: Hash,
     pub merkle_root: Hash,
+    pub evidence_root: Hash,
     pub timestamp: u64,
     pub height: u64,
+    pub epoch: u64,
     pub validator: PublicKey,
+    /// Tokens minted to the producer and its delegators by this block.
+    pub reward: u64,
+    /// The secret the producer committed to in its previous block.
+    pub randao_reveal: Hash,
+    /// Commitment to the secret the producer will reveal in its next block.
+    pub randao_commitment: Hash,
+    /// The randomness beacon after mixing in `randao_reveal`.
+    pub randomness: Hash,
+    /// Root of the world state this block is applied to, which is the state
+    /// after its parent. See `WorldState::state_root`.
+    pub state_root: Hash,
 }
 
 impl Block {
@@ -25,62 +43,119 @@ impl Block {
         previous_hash: Hash,
         transactions: Vec<Transaction>,
         height: u64,
+        epoch: u64,
+        timestamp: u64,
         validator: PublicKey,
+        reward: u64,
     ) -> Self {
         let header = BlockHeader {
             version: 1,
             previous_hash,
-            merkle_root: Self::calculate_merkle_root(&transactions),
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_secs(),
+            merkle_root: merkle_root(transactions.iter().map(|tx| tx.hash())),
+            evidence_root: Hash::default(),
+            timestamp,
             height,
+            epoch,
             validator,
+            reward,
+            randao_reveal: Hash::default(),
+            randao_commitment: Hash::default(),
+            randomness: Hash::default(),
+            state_root: Hash::default(),
         };
 
         Block {
             header,
             transactions,
+            evidence: Vec::new(),
+            signature: Vec::new(),
         }
     }
 
-    fn calculate_merkle_root(transactions: &[Transaction]) -> Hash {
-        if transactions.is_empty() {
-            return Hash::default();
-        }
+    /// Attaches misbehaviour evidence to be applied with this block.
+    pub fn with_evidence(mut self, evidence: Vec<Evidence>) -> Self {
+        self.header.evidence_root = merkle_root(evidence.iter().map(|evidence| evidence.hash()));
+        self.evidence = evidence;
+        self
+    }
 
-        let mut hashes: Vec<Hash> = transactions.iter().map(|tx| tx.hash()).collect();
+    /// Sets the producer's randomness beacon contribution.
+    pub fn with_randao(mut self, reveal: Hash, commitment: Hash, randomness: Hash) -> Self {
+        self.header.randao_reveal = reveal;
+        self.header.randao_commitment = commitment;
+        self.header.randomness = randomness;
+        self
+    }
 
-        while hashes.len() > 1 {
-            if hashes.len() % 2 != 0 {
-                hashes.push(hashes.last().unwrap().clone());
-            }
+    /// Commits to the state the block is applied to.
+    pub fn with_state_root(mut self, state_root: Hash) -> Self {
+        self.header.state_root = state_root;
+        self
+    }
 
-            let mut new_hashes = Vec::new();
-            for chunk in hashes.chunks(2) {
-                let mut hasher = blake3::Hasher::new();
-                hasher.update(chunk[0].as_bytes());
-                hasher.update(chunk[1].as_bytes());
-                new_hashes.push(Hash::from(hasher.finalize().as_bytes()));
-            }
+    pub fn sign(&mut self, keypair: &KeyPair) {
+        self.signature = keypair.sign(self.hash().as_bytes());
+    }
 
-            hashes = new_hashes;
-        }
+    pub fn verify_signature(&self) -> bool {
+        self.header.verify_signature(&self.signature)
+    }
+
+    /// The block's compact wire encoding.
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        wire::encode(self)
+    }
+
+    /// Decodes a block, refusing input over `MAX_BLOCK_SIZE`.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        wire::decode(bytes, MAX_BLOCK_SIZE)
+    }
 
-        hashes[0]
+    /// Whether the block encodes to at most `MAX_BLOCK_SIZE`. Several blocks
+    /// share one sync response, so each is checked on its own.
+    pub fn is_within_size_limit(&self) -> bool {
+        wire::encoded_size(self).is_ok_and(|size| size <= MAX_BLOCK_SIZE)
+    }
+
+    /// Whether the transactions and evidence match the roots committed to in
+    /// the header. The block hash only covers the header, so a body received
+    /// separately must be checked against it.
+    pub fn has_valid_body(&self) -> bool {
+        self.header.merkle_root == merkle_root(self.transactions.iter().map(|tx| tx.hash()))
+            && self.header.evidence_root
+                == merkle_root(self.evidence.iter().map(|evidence| evidence.hash()))
     }
 }
 
-impl Hashable for Block {
+impl BlockHeader {
+    /// Checks `signature` was made by this header's validator over its hash.
+    pub fn verify_signature(&self, signature: &[u8]) -> bool {
+        verify_signature(&self.validator, self.hash().as_bytes(), signature)
+    }
+}
+
+impl Hashable for BlockHeader {
     fn hash(&self) -> Hash {
         let mut hasher = blake3::Hasher::new();
-        hasher.update(&self.header.version.to_le_bytes());
-        hasher.update(self.header.previous_hash.as_bytes());
-        hasher.update(self.header.merkle_root.as_bytes());
-        hasher.update(&self.header.timestamp.to_le_bytes());
-        hasher.update(&self.header.height.to_le_bytes());
-        hasher.update(self.header.validator.as_bytes());
+        hasher.update(&self.version.to_le_bytes());
+        hasher.update(self.previous_hash.as_bytes());
+        hasher.update(self.merkle_root.as_bytes());
+        hasher.update(self.evidence_root.as_bytes());
+        hasher.update(&self.timestamp.to_le_bytes());
+        hasher.update(&self.height.to_le_bytes());
+        hasher.update(&self.epoch.to_le_bytes());
+        hasher.update(self.validator.as_bytes());
+        hasher.update(&self.reward.to_le_bytes());
+        hasher.update(self.randao_reveal.as_bytes());
+        hasher.update(self.randao_commitment.as_bytes());
+        hasher.update(self.randomness.as_bytes());
+        hasher.update(self.state_root.as_bytes());
         Hash::from(hasher.finalize().as_bytes())
     }
 }
+
+impl Hashable for Block {
+    fn hash(&self) -> Hash {
+        self.header.hash()
+    }
+}
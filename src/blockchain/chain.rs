@@ -1,110 +1,573 @@
+use crate::blockchain::genesis::GenesisConfig;
 use crate::blockchain::transaction::Transaction;
 use crate::blockchain::Block;
-use crate::consensus::ConsensusManager;
-use crate::crypto::{Hash, Hashable, PublicKey};
-use crate::network::P2PNetwork;
-use crate::state::WorldState;
-use std::collections::{HashMap, HashSet};
+use crate::consensus::dpos::EPOCH_LENGTH;
+use crate::consensus::dpos::{epoch_of, epoch_seed, is_epoch_start};
+use crate::consensus::evidence::{Evidence, SignedHeader};
+use crate::consensus::pbft::{QuorumCertificate, Vote};
+use crate::consensus::randomness::{randao_commitment, randao_reveal};
+use crate::consensus::{unix_now, ConsensusEngine};
+use crate::crypto::{BlsKeyPair, Hash, Hashable, KeyPair, PublicKey};
+use crate::network::NetworkHandle;
+use crate::state::liveness::MISSED_SLOT_LOOKBACK;
+use crate::state::{Snapshot, WorldState};
+use log::warn;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, RwLockReadGuard};
+
+/// A snapshot is taken of the state after the last block of each epoch, so
+/// a node restoring it starts on an epoch boundary with a settled validator
+/// set.
+pub const SNAPSHOT_INTERVAL: u64 = EPOCH_LENGTH;
+/// Older snapshots are dropped; peers only need a recent one.
+const SNAPSHOTS_KEPT: usize = 2;
+/// How far ahead of its sender's next nonce a transaction may be and still
+/// wait in the mempool for the ones before it.
+pub const MAX_NONCE_GAP: u64 = 16;
 
 pub struct Blockchain {
     blocks: Arc<RwLock<HashMap<Hash, Block>>>,
+    block_hashes_by_height: Arc<RwLock<HashMap<u64, Hash>>>,
     latest_block_hash: Arc<RwLock<Hash>>,
     world_state: Arc<RwLock<WorldState>>,
-    consensus_manager: Arc<RwLock<ConsensusManager>>,
-    network: Arc<RwLock<Option<Arc<RwLock<P2PNetwork>>>>>,
+    consensus: Arc<RwLock<Box<dyn ConsensusEngine>>>,
+    network: Option<NetworkHandle>,
     mempool: Arc<RwLock<HashSet<Transaction>>>,
+    evidence_pool: Arc<RwLock<Vec<Evidence>>>,
+    /// Recent state snapshots by the height they were taken at.
+    snapshots: Arc<RwLock<BTreeMap<u64, Arc<Snapshot>>>>,
+    signer: Option<KeyPair>,
+    /// The BLS key the signer votes on blocks with.
+    vote_key: Option<BlsKeyPair>,
+    chain_id: String,
+    genesis_hash: Hash,
+    /// The state after the genesis block, to replay from when a fork
+    /// reaches back further than the oldest snapshot.
+    genesis_state: WorldState,
 }
 
 impl Blockchain {
-    pub fn new(consensus_manager: ConsensusManager) -> Self {
+    pub fn new(mut consensus: Box<dyn ConsensusEngine>, genesis: GenesisConfig) -> Self {
+        let mut world_state = WorldState::from_genesis(&genesis)
+            .expect("Genesis validators must be unique with a positive stake");
+
         let genesis_validator = PublicKey::genesis();
-        let genesis_block = Block::new(Hash::default(), vec![], 0, genesis_validator);
+        let genesis_block = Block::new(
+            Hash::default(),
+            vec![],
+            0,
+            0,
+            genesis.timestamp,
+            genesis_validator,
+            0,
+        )
+        .with_state_root(world_state.state_root());
         let genesis_hash = genesis_block.hash();
 
+        world_state
+            .apply_block(&genesis_block, &[])
+            .expect("Genesis block must apply to an empty state");
+        consensus.on_genesis(
+            genesis.timestamp,
+            epoch_seed(0, &genesis_hash),
+            &world_state.stakes(),
+            &world_state.bls_keys(),
+        );
+
+        let genesis_state = world_state.clone();
         let mut blocks = HashMap::new();
-        blocks.insert(genesis_hash.clone(), genesis_block);
+        blocks.insert(genesis_hash, genesis_block);
+        let mut block_hashes_by_height = HashMap::new();
+        block_hashes_by_height.insert(0, genesis_hash);
 
         Blockchain {
             blocks: Arc::new(RwLock::new(blocks)),
+            block_hashes_by_height: Arc::new(RwLock::new(block_hashes_by_height)),
             latest_block_hash: Arc::new(RwLock::new(genesis_hash)),
-            world_state: Arc::new(RwLock::new(WorldState::new())),
-            consensus_manager: Arc::new(RwLock::new(consensus_manager)),
-            network: Arc::new(RwLock::new(None)),
+            world_state: Arc::new(RwLock::new(world_state)),
+            consensus: Arc::new(RwLock::new(consensus)),
+            network: None,
             mempool: Arc::new(RwLock::new(HashSet::new())),
+            evidence_pool: Arc::new(RwLock::new(Vec::new())),
+            snapshots: Arc::new(RwLock::new(BTreeMap::new())),
+            signer: None,
+            vote_key: None,
+            chain_id: genesis.chain_id,
+            genesis_hash,
+            genesis_state,
         }
     }
 
-    pub async fn set_network(&mut self, network: Arc<RwLock<P2PNetwork>>) {
-        let mut net = self.network.write().await;
-        *net = Some(network);
+    /// Sets the validator key this node produces and signs blocks with.
+    pub fn set_signer(&mut self, keypair: KeyPair) {
+        self.signer = Some(keypair);
+    }
+
+    /// Sets the BLS key registered for the signer, so this node votes on
+    /// the blocks it imports.
+    pub fn set_vote_key(&mut self, vote_key: BlsKeyPair) {
+        self.vote_key = Some(vote_key);
+    }
+
+    pub fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    pub fn genesis_hash(&self) -> Hash {
+        self.genesis_hash
+    }
+
+    pub fn set_network(&mut self, network: NetworkHandle) {
+        self.network = Some(network);
     }
 
-    pub async fn get_network(&self) -> Option<Arc<RwLock<P2PNetwork>>> {
-        let net = self.network.read().await;
-        net.as_ref().cloned()
+    pub fn get_network(&self) -> Option<&NetworkHandle> {
+        self.network.as_ref()
     }
+
+    /// Imports a block extending the head and votes on it.
     pub async fn add_block(&self, block: Block) -> Result<(), Box<dyn Error>> {
+        self.import_block(block).await?;
+        self.cast_votes(self.consensus.write().await.as_mut());
+        Ok(())
+    }
+
+    async fn import_block(&self, block: Block) -> Result<(), Box<dyn Error>> {
         let mut blocks = self.blocks.write().await;
+        let mut block_hashes_by_height = self.block_hashes_by_height.write().await;
         let mut latest_block_hash = self.latest_block_hash.write().await;
         let mut world_state = self.world_state.write().await;
-        let mut consensus_manager = self.consensus_manager.write().await;
+        let mut consensus = self.consensus.write().await;
+        let mut evidence_pool = self.evidence_pool.write().await;
+        let mut mempool = self.mempool.write().await;
 
-        if !consensus_manager.on_block_produced(block.clone()) {
-            return Err("Block rejected by consensus".into());
+        if !block.verify_signature() {
+            return Err("Invalid block signature".into());
+        }
+
+        let block_hash = block.hash();
+
+        // A signed block for a height we already have from the same validator
+        // is a double sign, whether or not the new block is otherwise valid.
+        if let Some(existing) = block_hashes_by_height
+            .get(&block.header.height)
+            .and_then(|hash| blocks.get(hash))
+        {
+            if existing.header.validator == block.header.validator && existing.hash() != block_hash
+            {
+                let evidence = Evidence::DoubleSign {
+                    first: Box::new(SignedHeader {
+                        header: existing.header.clone(),
+                        signature: existing.signature.clone(),
+                    }),
+                    second: Box::new(SignedHeader {
+                        header: block.header.clone(),
+                        signature: block.signature.clone(),
+                    }),
+                };
+                if world_state.check_evidence(&evidence).is_ok() {
+                    evidence_pool.push(evidence);
+                }
+                return Err("Conflicting block from the same validator".into());
+            }
         }
 
         if block.header.previous_hash != *latest_block_hash {
             return Err("Invalid previous block hash".into());
         }
 
-        world_state.apply_block(&block)?;
+        apply_with_consensus(&mut world_state, consensus.as_mut(), &block)?;
 
-        let block_hash = block.hash();
-        blocks.insert(block_hash.clone(), block);
+        evidence_pool.retain(|pending| world_state.check_evidence(pending).is_ok());
+        evidence_pool.extend(
+            consensus
+                .take_evidence()
+                .into_iter()
+                .filter(|evidence| world_state.check_evidence(evidence).is_ok()),
+        );
+
+        if let Some(snapshot) = snapshot_after(&block, &world_state) {
+            let mut snapshots = self.snapshots.write().await;
+            snapshots.insert(block.header.height, Arc::new(snapshot));
+            while snapshots.len() > SNAPSHOTS_KEPT {
+                snapshots.pop_first();
+            }
+        }
+
+        for transaction in &block.transactions {
+            mempool.remove(transaction);
+        }
+
+        block_hashes_by_height.insert(block.header.height, block_hash);
+        blocks.insert(block_hash, block);
         *latest_block_hash = block_hash;
 
         Ok(())
     }
 
+    /// Switches the main chain to `branch`, consecutive blocks leaving it
+    /// after the parent of the first, when the branch reaches at least the
+    /// current height. The fork may not be below the finalized height, and
+    /// must be recent enough to replay: the state and consensus are rebuilt
+    /// from the last snapshot before it, or genesis, by applying the main
+    /// chain up to the fork and then the branch. If a branch block is
+    /// rejected the old chain is replayed instead and nothing changes.
+    /// Transactions only on the old branch go back to the mempool.
+    pub async fn reorganize(&self, branch: Vec<Block>) -> Result<(), Box<dyn Error>> {
+        let mut blocks = self.blocks.write().await;
+        let mut block_hashes_by_height = self.block_hashes_by_height.write().await;
+        let mut latest_block_hash = self.latest_block_hash.write().await;
+        let mut world_state = self.world_state.write().await;
+        let mut consensus = self.consensus.write().await;
+        let mut evidence_pool = self.evidence_pool.write().await;
+        let mut mempool = self.mempool.write().await;
+        let mut snapshots = self.snapshots.write().await;
+
+        let first = branch.first().ok_or("Empty branch")?;
+        let fork_height = first
+            .header
+            .height
+            .checked_sub(1)
+            .ok_or("A branch cannot replace genesis")?;
+        if block_hashes_by_height.get(&fork_height) != Some(&first.header.previous_hash) {
+            return Err("Branch does not leave the main chain".into());
+        }
+        let head_height = world_state.height();
+        if fork_height + (branch.len() as u64) < head_height {
+            return Err("Branch is shorter than the main chain".into());
+        }
+        if consensus
+            .finalized_height()
+            .is_some_and(|finalized| fork_height < finalized)
+        {
+            return Err("Branch reverts a finalized block".into());
+        }
+        let mut previous = first.header.previous_hash;
+        for (block, height) in branch.iter().zip(fork_height + 1..) {
+            if block.header.height != height || block.header.previous_hash != previous {
+                return Err("Branch blocks do not follow each other".into());
+            }
+            if !block.verify_signature() || !block.has_valid_body() {
+                return Err("Invalid block in branch".into());
+            }
+            previous = block.hash();
+        }
+
+        let main_chain = |heights: std::ops::RangeInclusive<u64>| {
+            heights
+                .map(|height| {
+                    block_hashes_by_height
+                        .get(&height)
+                        .and_then(|hash| blocks.get(hash))
+                        .cloned()
+                        .ok_or("Fork is older than the blocks this node keeps")
+                })
+                .collect::<Result<Vec<Block>, _>>()
+        };
+        let (base_height, base_state) = match snapshots.range(..=fork_height).next_back() {
+            Some((height, snapshot)) => (*height, snapshot.state()?),
+            None => (0, self.genesis_state.clone()),
+        };
+        let base = main_chain(base_height..=base_height)?.remove(0);
+        let common = main_chain(base_height + 1..=fork_height)?;
+        let old_branch = main_chain(fork_height + 1..=head_height)?;
+
+        let replayed = replay(
+            consensus.as_mut(),
+            base_state.clone(),
+            &base,
+            common.iter().chain(&branch),
+        );
+        let (state, taken) = match replayed {
+            Ok(replayed) => replayed,
+            Err(e) => {
+                // Puts consensus back on the chain it was following.
+                replay(
+                    consensus.as_mut(),
+                    base_state,
+                    &base,
+                    common.iter().chain(&old_branch),
+                )
+                .map_err(|e| format!("Failed to replay the main chain: {}", e))?;
+                return Err(format!("Branch rejected: {}", e).into());
+            }
+        };
+        consensus.take_evidence();
+
+        snapshots.retain(|height, _| *height <= fork_height);
+        snapshots.extend(
+            taken
+                .into_iter()
+                .map(|snapshot| (snapshot.manifest.height, Arc::new(snapshot))),
+        );
+        while snapshots.len() > SNAPSHOTS_KEPT {
+            snapshots.pop_first();
+        }
+
+        let included: HashSet<&Transaction> = branch
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .collect();
+        mempool.retain(|transaction| !included.contains(transaction));
+        for transaction in old_branch.iter().flat_map(|block| &block.transactions) {
+            if !included.contains(transaction)
+                && transaction.nonce >= state.nonce(&transaction.from)
+            {
+                mempool.insert(transaction.clone());
+            }
+        }
+        evidence_pool.retain(|pending| state.check_evidence(pending).is_ok());
+
+        for height in fork_height + 1..=head_height {
+            block_hashes_by_height.remove(&height);
+        }
+        for block in branch {
+            let hash = block.hash();
+            block_hashes_by_height.insert(block.header.height, hash);
+            blocks.insert(hash, block);
+            *latest_block_hash = hash;
+        }
+        *world_state = state;
+        self.cast_votes(consensus.as_mut());
+        Ok(())
+    }
+
+    /// Validates a transaction and adds it to the mempool. Transactions
+    /// received from peers come in here and are forwarded by the network
+    /// layer, so this never publishes; use `submit_transaction` for ones
+    /// created on this node.
     pub async fn add_transaction(&self, transaction: Transaction) -> Result<(), Box<dyn Error>> {
         // Validate transaction
         if !transaction.verify() {
             return Err("Invalid transaction signature".into());
         }
 
+        let (sender_balance, next_nonce) = {
+            let world_state = self.world_state.read().await;
+            let sender = &transaction.from;
+            (
+                world_state
+                    .get_account(sender)
+                    .map_or(0, |account| account.balance),
+                world_state.nonce(sender),
+            )
+        };
+        if transaction.nonce < next_nonce {
+            return Err("Nonce already used".into());
+        }
+        if transaction.nonce > next_nonce + MAX_NONCE_GAP {
+            return Err("Nonce too far ahead".into());
+        }
+
         // Check if the sender has sufficient balance
-        let sender_balance = self.get_account_balance(&transaction.from).await;
-        if sender_balance < transaction.amount {
+        if sender_balance < transaction.spend() {
             return Err("Insufficient balance".into());
         }
 
-        self.add_to_mempool(transaction.clone()).await?;
+        self.add_to_mempool(transaction).await?;
 
-        // Broadcast transaction to network
-        if let Some(network) = self.get_network().await {
-            let mut network = network.write().await;
-            network.broadcast_transaction(transaction).await?;
-        } else {
-            return Err("Network not initialized".into());
+        if self.consensus.read().await.seals_on_transaction() {
+            self.mine_block().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds a transaction created on this node and publishes it to peers.
+    pub async fn submit_transaction(&self, transaction: Transaction) -> Result<(), Box<dyn Error>> {
+        self.add_transaction(transaction.clone()).await?;
+
+        match self.get_network() {
+            Some(network) => network.announce_transaction(transaction.hash())?,
+            None => return Err("Network not initialized".into()),
         }
 
         Ok(())
     }
 
+    /// Verifies misbehaviour evidence and queues it for inclusion in a block
+    /// we produce. Like `add_transaction`, forwarding it is left to the
+    /// network layer.
+    pub async fn add_evidence(&self, evidence: Evidence) -> Result<(), Box<dyn Error>> {
+        self.world_state.read().await.check_evidence(&evidence)?;
+
+        let mut evidence_pool = self.evidence_pool.write().await;
+        let hash = evidence.hash();
+        if evidence_pool.iter().any(|pending| pending.hash() == hash) {
+            return Err("Evidence already pending".into());
+        }
+        evidence_pool.push(evidence);
+        Ok(())
+    }
+
+    /// Counts a finality vote gossiped by a peer, casting any votes this
+    /// node owes as a result, and returns whether it completed a
+    /// certificate. Fails if the vote is not signed by a validator's vote
+    /// key; one for a round already over is taken but not counted.
+    pub async fn add_vote(&self, vote: Vote) -> Result<bool, Box<dyn Error>> {
+        let signed = self
+            .world_state
+            .read()
+            .await
+            .staking()
+            .bls_key(&vote.validator)
+            .is_some_and(|bls_key| vote.verify(bls_key));
+        if !signed {
+            return Err("Vote is not signed by a validator".into());
+        }
+
+        let mut consensus = self.consensus.write().await;
+        let certified = consensus.on_vote(vote);
+        self.cast_votes(consensus.as_mut());
+        Ok(certified)
+    }
+
+    /// The consensus engine, for checks that cannot await, such as those the
+    /// sync manager makes while validating a response.
+    pub async fn consensus(&self) -> RwLockReadGuard<'_, Box<dyn ConsensusEngine>> {
+        self.consensus.read().await
+    }
+
+    /// The main chain's block hashes by height, held like `consensus` for
+    /// callers that need to look several up without awaiting.
+    pub async fn block_hashes_by_height(&self) -> RwLockReadGuard<'_, HashMap<u64, Hash>> {
+        self.block_hashes_by_height.read().await
+    }
+
+    /// How often this node should try to produce a block, as decided by the
+    /// consensus engine.
+    pub async fn block_interval(&self) -> Option<std::time::Duration> {
+        self.consensus.read().await.block_interval()
+    }
+
     pub async fn get_latest_block(&self) -> Block {
         let latest_block_hash = self.latest_block_hash.read().await;
         let blocks = self.blocks.read().await;
         blocks.get(&latest_block_hash).unwrap().clone()
     }
 
+    /// The aggregate PBFT commit certificate finalizing `hash`, if this node
+    /// has seen a quorum of commits for it.
+    pub async fn get_commit_certificate(&self, hash: &Hash) -> Option<QuorumCertificate> {
+        self.consensus.read().await.commit_certificate(hash)
+    }
+
     pub async fn get_block_by_hash(&self, hash: &Hash) -> Option<Block> {
         let blocks = self.blocks.read().await;
         blocks.get(hash).cloned()
     }
 
+    /// The snapshot whose state root the main-chain header `next_hash`
+    /// commits to, with that signed header.
+    pub async fn get_snapshot_before(
+        &self,
+        next_hash: &Hash,
+    ) -> Option<(Arc<Snapshot>, SignedHeader)> {
+        let next = self.get_block_by_hash(next_hash).await?;
+        let height = next.header.height.checked_sub(1)?;
+        let on_main_chain = self
+            .block_hashes_by_height
+            .read()
+            .await
+            .get(&next.header.height)
+            == Some(next_hash);
+        if !on_main_chain {
+            return None;
+        }
+        let snapshot = self.get_snapshot(height).await?;
+        Some((
+            snapshot,
+            SignedHeader {
+                header: next.header,
+                signature: next.signature,
+            },
+        ))
+    }
+
+    pub async fn get_snapshot(&self, height: u64) -> Option<Arc<Snapshot>> {
+        self.snapshots.read().await.get(&height).cloned()
+    }
+
+    /// Starts a chain that is still at genesis from a snapshot instead of
+    /// replaying every block. `head` is the block the snapshot was taken
+    /// after; it becomes the head, and only blocks after it are imported.
+    /// Blocks in between are never downloaded, so this node cannot serve
+    /// them to peers.
+    pub async fn restore_snapshot(
+        &self,
+        state: WorldState,
+        head: Block,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut blocks = self.blocks.write().await;
+        let mut block_hashes_by_height = self.block_hashes_by_height.write().await;
+        let mut latest_block_hash = self.latest_block_hash.write().await;
+        let mut world_state = self.world_state.write().await;
+        let mut consensus = self.consensus.write().await;
+
+        if *latest_block_hash != self.genesis_hash {
+            return Err("Only a chain still at genesis can be restored from a snapshot".into());
+        }
+        let head_hash = head.hash();
+        if head_hash != state.get_last_block_hash() || head.header.height != state.height() {
+            return Err("Snapshot was not taken after the given block".into());
+        }
+        if !head.verify_signature() || !head.has_valid_body() {
+            return Err("Invalid snapshot block".into());
+        }
+        let next_height = head.header.height + 1;
+        if !is_epoch_start(next_height) {
+            return Err("Snapshots must end an epoch".into());
+        }
+
+        let next_epoch = epoch_of(next_height);
+        consensus.on_epoch_boundary(
+            next_epoch,
+            epoch_seed(next_epoch, &state.randomness()),
+            &state.stakes(),
+            &state.bls_keys(),
+        );
+        consensus.on_snapshot_restored(&head);
+
+        // Kept like one taken locally, so forks above it can be replayed.
+        let snapshot = Snapshot::new(&state)?;
+        self.snapshots
+            .write()
+            .await
+            .insert(head.header.height, Arc::new(snapshot));
+
+        block_hashes_by_height.insert(head.header.height, head_hash);
+        blocks.insert(head_hash, head);
+        *latest_block_hash = head_hash;
+        *world_state = state;
+        Ok(())
+    }
+
+    /// Up to `count` main chain blocks starting at height `start`.
+    pub async fn get_blocks_by_range(&self, start: u64, count: u64) -> Vec<Block> {
+        let blocks = self.blocks.read().await;
+        let block_hashes_by_height = self.block_hashes_by_height.read().await;
+        (start..start.saturating_add(count))
+            .map_while(|height| block_hashes_by_height.get(&height))
+            .filter_map(|hash| blocks.get(hash).cloned())
+            .collect()
+    }
+
+    /// Up to `count` signed headers of the main chain starting at `start`.
+    pub async fn get_headers(&self, start: u64, count: u64) -> Vec<SignedHeader> {
+        let blocks = self.blocks.read().await;
+        let block_hashes_by_height = self.block_hashes_by_height.read().await;
+        (start..start.saturating_add(count))
+            .map_while(|height| block_hashes_by_height.get(&height))
+            .filter_map(|hash| blocks.get(hash))
+            .map(|block| SignedHeader {
+                header: block.header.clone(),
+                signature: block.signature.clone(),
+            })
+            .collect()
+    }
+
     pub async fn get_account_balance(&self, public_key: &PublicKey) -> u64 {
         let world_state = self.world_state.read().await;
         world_state
@@ -112,6 +575,11 @@ impl Blockchain {
             .map_or(0, |account| account.balance)
     }
 
+    pub async fn get_total_supply(&self) -> u64 {
+        let world_state = self.world_state.read().await;
+        world_state.total_supply()
+    }
+
     async fn add_to_mempool(&self, transaction: Transaction) -> Result<(), Box<dyn Error>> {
         let mut mempool = self.mempool.write().await;
         if mempool.insert(transaction.clone()) {
@@ -121,40 +589,434 @@ impl Blockchain {
         }
     }
 
-    pub async fn mine_block(&mut self) -> Result<Block, Box<dyn Error>> {
+    pub async fn mine_block(&self) -> Result<Block, Box<dyn Error>> {
         // Get pending transactions from mempool
-        let transactions = self.get_transactions_from_mempool().await?;
+        let candidates = self.get_transactions_from_mempool().await?;
 
         // Create a new block
         let previous_hash = self.get_latest_block_hash().await?;
-        let height = self.get_chain_length().await?;
-        let miner_address = self.get_miner_address(); // You need to implement this method
+        let height = self.get_latest_block().await.header.height + 1;
+        let signer = self.get_signer()?;
+        let miner_address = signer.public_key();
 
-        let new_block = Block::new(previous_hash, transactions, height, miner_address);
+        let now = unix_now();
+        if !self
+            .consensus
+            .read()
+            .await
+            .can_produce_block(&miner_address, now)
+        {
+            return Err("Not scheduled to produce a block in the current slot".into());
+        }
+
+        let (reward, randao, state_root, transactions, stale) = {
+            let world_state = self.world_state.read().await;
+            // Only transactions that apply go in, since one that fails would
+            // get the whole block rejected.
+            let (transactions, stale) = world_state.select_transactions(height, candidates);
+            let randao = world_state.randao();
+            let (reveal, next_index) = match randao.next_reveal_index(&miner_address) {
+                Some(index) => (randao_reveal(signer, index), index + 1),
+                None => (Hash::default(), 0),
+            };
+            let commitment = randao_commitment(&randao_reveal(signer, next_index));
+            let randomness = randao.next_mix(&miner_address, &reveal);
+            (
+                world_state.expected_reward(now),
+                (reveal, commitment, randomness),
+                world_state.state_root(),
+                transactions,
+                stale,
+            )
+        };
+        if !stale.is_empty() {
+            let mut mempool = self.mempool.write().await;
+            for transaction in &stale {
+                mempool.remove(transaction);
+            }
+        }
+        let evidence = self.get_evidence_for_block().await;
+        let mut new_block = Block::new(
+            previous_hash,
+            transactions,
+            height,
+            epoch_of(height),
+            now,
+            miner_address,
+            reward,
+        )
+        .with_evidence(evidence)
+        .with_randao(randao.0, randao.1, randao.2)
+        .with_state_root(state_root);
+        new_block.sign(signer);
 
         // Add the new block to the chain
-        self.add_block(new_block.clone()).await?;
+        self.import_block(new_block.clone()).await?;
+
+        // Blocks we produce are the only ones this node publishes itself.
+        // They go out before our vote on them, which peers would otherwise
+        // have to hold until the block arrives.
+        if let Some(network) = self.get_network() {
+            network.broadcast_block(new_block.clone())?;
+        }
+        self.cast_votes(self.consensus.write().await.as_mut());
 
         Ok(new_block)
     }
 
+    /// Everything waiting in the mempool, for rebuilding compact blocks.
+    pub async fn get_mempool_transactions(&self) -> Vec<Transaction> {
+        self.mempool.read().await.iter().cloned().collect()
+    }
+
+    /// The mempool transactions with the given hashes, in that order.
+    /// Ones not in the mempool are left out.
+    pub async fn get_mempool_transactions_by_hash(&self, hashes: &[Hash]) -> Vec<Transaction> {
+        let mempool = self.mempool.read().await;
+        let by_hash: HashMap<Hash, &Transaction> = mempool
+            .iter()
+            .map(|transaction| (transaction.hash(), transaction))
+            .collect();
+        hashes
+            .iter()
+            .filter_map(|hash| by_hash.get(hash).map(|transaction| (*transaction).clone()))
+            .collect()
+    }
+
+    /// The mempool ordered by sender and nonce, so each sender's
+    /// transactions come in the order they must apply.
     async fn get_transactions_from_mempool(&self) -> Result<Vec<Transaction>, Box<dyn Error>> {
         let mempool = self.mempool.read().await;
-        Ok(mempool.iter().cloned().collect())
+        let mut transactions: Vec<Transaction> = mempool.iter().cloned().collect();
+        transactions.sort_by(|a, b| (&a.from, a.nonce).cmp(&(&b.from, b.nonce)));
+        Ok(transactions)
     }
 
     async fn get_latest_block_hash(&self) -> Result<Hash, Box<dyn Error>> {
         let latest_block_hash = self.latest_block_hash.read().await;
-        Ok(latest_block_hash.clone())
+        Ok(*latest_block_hash)
     }
 
-    async fn get_chain_length(&self) -> Result<u64, Box<dyn Error>> {
-        let blocks = self.blocks.read().await;
-        Ok(blocks.len() as u64)
+    /// Pending evidence, at most one item per offender since a validator can
+    /// only be slashed once.
+    async fn get_evidence_for_block(&self) -> Vec<Evidence> {
+        let evidence_pool = self.evidence_pool.read().await;
+        let mut offenders = HashSet::new();
+        evidence_pool
+            .iter()
+            .filter(|evidence| offenders.insert(evidence.offender().clone()))
+            .cloned()
+            .collect()
+    }
+
+    /// Signs the votes this node owes on the head block, if it votes, and
+    /// gossips them.
+    fn cast_votes(&self, consensus: &mut dyn ConsensusEngine) {
+        let (Some(signer), Some(vote_key)) = (&self.signer, &self.vote_key) else {
+            return;
+        };
+        let votes = consensus.cast_votes(&signer.public_key(), vote_key);
+        if let Some(network) = self.get_network() {
+            for vote in votes {
+                if let Err(e) = network.broadcast_vote(vote) {
+                    warn!("Failed to publish vote: {}", e);
+                }
+            }
+        }
+    }
+
+    fn get_signer(&self) -> Result<&KeyPair, Box<dyn Error>> {
+        self.signer
+            .as_ref()
+            .ok_or_else(|| "No validator key configured".into())
+    }
+}
+
+/// Checks `block` with consensus and applies it to `world_state`, moving
+/// consensus to it. Consensus only moves once the block has applied in full;
+/// a failed block leaves both untouched.
+fn apply_with_consensus(
+    world_state: &mut WorldState,
+    consensus: &mut dyn ConsensusEngine,
+    block: &Block,
+) -> Result<(), Box<dyn Error>> {
+    let missed = consensus.missed_producers(block, MISSED_SLOT_LOOKBACK);
+    if !consensus.is_valid_block(block) {
+        return Err("Block rejected by consensus".into());
+    }
+
+    let jailed = world_state.apply_block(block, &missed)?;
+    consensus.on_block_produced(block);
+
+    for validator in &jailed {
+        consensus.remove_validator(validator, block);
+    }
+
+    for evidence in &block.evidence {
+        consensus.remove_validator(evidence.offender(), block);
+    }
+
+    // Stake changes recorded during the epoch only take effect once the
+    // last block of the epoch has been applied.
+    let next_height = block.header.height + 1;
+    if is_epoch_start(next_height) {
+        let next_epoch = epoch_of(next_height);
+        consensus.on_epoch_boundary(
+            next_epoch,
+            epoch_seed(next_epoch, &world_state.randomness()),
+            &world_state.stakes(),
+            &world_state.bls_keys(),
+        );
+    }
+    Ok(())
+}
+
+/// The snapshot due after `block`, if it ends a snapshot interval.
+fn snapshot_after(block: &Block, world_state: &WorldState) -> Option<Snapshot> {
+    if !(block.header.height + 1).is_multiple_of(SNAPSHOT_INTERVAL) {
+        return None;
+    }
+    Snapshot::new(world_state)
+        .map_err(|e| warn!("Failed to snapshot state: {}", e))
+        .ok()
+}
+
+/// Resets consensus to `base`, the block `state` was taken after, and
+/// applies `blocks` on top. Returns the resulting state and the snapshots
+/// taken along the way.
+fn replay<'a>(
+    consensus: &mut dyn ConsensusEngine,
+    mut state: WorldState,
+    base: &Block,
+    blocks: impl Iterator<Item = &'a Block>,
+) -> Result<(WorldState, Vec<Snapshot>), Box<dyn Error>> {
+    if base.header.height == 0 {
+        consensus.on_genesis(
+            base.header.timestamp,
+            epoch_seed(0, &base.hash()),
+            &state.stakes(),
+            &state.bls_keys(),
+        );
+    } else {
+        let next_epoch = epoch_of(base.header.height + 1);
+        consensus.on_epoch_boundary(
+            next_epoch,
+            epoch_seed(next_epoch, &state.randomness()),
+            &state.stakes(),
+            &state.bls_keys(),
+        );
+        consensus.on_snapshot_restored(base);
+    }
+
+    let mut snapshots = Vec::new();
+    for block in blocks {
+        apply_with_consensus(&mut state, consensus, block)?;
+        if let Some(snapshot) = snapshot_after(block, &state) {
+            snapshots.push(snapshot);
+        }
+    }
+    Ok((state, snapshots))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::{InstantSeal, SealMode};
+
+    fn dev_chain(genesis_time: u64, balances: Vec<(PublicKey, u64)>) -> Blockchain {
+        chain_with_authority(KeyPair::generate(), genesis_time, balances)
+    }
+
+    fn chain_with_authority(
+        authority: KeyPair,
+        genesis_time: u64,
+        balances: Vec<(PublicKey, u64)>,
+    ) -> Blockchain {
+        let consensus = InstantSeal::new(authority.public_key(), SealMode::Instant);
+        let genesis = GenesisConfig::new("test", genesis_time, balances, Vec::new());
+        let mut blockchain = Blockchain::new(Box::new(consensus), genesis);
+        blockchain.set_signer(authority);
+        blockchain
+    }
+
+    #[tokio::test]
+    async fn a_block_the_state_rejects_does_not_advance_consensus() {
+        let now = unix_now();
+        let blockchain = dev_chain(now - 100, Vec::new());
+        let signer = blockchain.get_signer().unwrap();
+
+        // Acceptable to consensus, but commits to the wrong state root.
+        let mut block = Block::new(
+            blockchain.genesis_hash(),
+            Vec::new(),
+            1,
+            0,
+            now + 1,
+            signer.public_key(),
+            0,
+        );
+        block.sign(signer);
+        assert!(blockchain.add_block(block).await.is_err());
+
+        // An earlier timestamp is still fine, since the rejected block never
+        // became the head.
+        blockchain.mine_block().await.unwrap();
+        assert_eq!(blockchain.get_latest_block().await.header.height, 1);
+    }
+
+    #[tokio::test]
+    async fn the_first_block_at_a_height_stays_the_head() {
+        let now = unix_now();
+        let blockchain = dev_chain(now - 100, Vec::new());
+        let head = blockchain.mine_block().await.unwrap();
+        let signer = blockchain.get_signer().unwrap();
+
+        // A sibling of the head from the same producer is a double sign and
+        // never replaces it.
+        let mut sibling = Block::new(
+            blockchain.genesis_hash(),
+            Vec::new(),
+            1,
+            0,
+            head.header.timestamp + 1,
+            signer.public_key(),
+            0,
+        )
+        .with_state_root(head.header.state_root);
+        sibling.sign(signer);
+        assert!(blockchain.add_block(sibling).await.is_err());
+        assert_eq!(blockchain.get_latest_block().await.hash(), head.hash());
+
+        // The chain only grows on top of its head.
+        blockchain.mine_block().await.unwrap();
+        let latest = blockchain.get_latest_block().await;
+        assert_eq!(latest.header.height, 2);
+        assert_eq!(latest.header.previous_hash, head.hash());
     }
-    fn get_miner_address(&self) -> PublicKey {
-        // Implement this method to return the miner's public key
-        // This could be a fixed value or derived from a configuration
-        unimplemented!()
+
+    fn transfer(from: &KeyPair, amount: u64, nonce: u64) -> Transaction {
+        let mut transaction =
+            Transaction::new(from.public_key(), PublicKey::genesis(), amount, nonce);
+        transaction.sign(from.sign(transaction.hash().as_bytes()));
+        transaction
+    }
+
+    #[tokio::test]
+    async fn blocks_only_include_transactions_that_apply_in_nonce_order() {
+        let sender = KeyPair::generate();
+        let blockchain = dev_chain(unix_now() - 100, vec![(sender.public_key(), 100)]);
+
+        // Each admission seals a block. One arriving ahead of its
+        // predecessor waits instead of breaking block production.
+        blockchain
+            .add_transaction(transfer(&sender, 10, 1))
+            .await
+            .unwrap();
+        assert!(blockchain.get_latest_block().await.transactions.is_empty());
+        blockchain
+            .add_transaction(transfer(&sender, 10, 0))
+            .await
+            .unwrap();
+        let head = blockchain.get_latest_block().await;
+        let nonces: Vec<u64> = head.transactions.iter().map(|tx| tx.nonce).collect();
+        assert_eq!(nonces, vec![0, 1]);
+        assert!(blockchain.get_mempool_transactions().await.is_empty());
+
+        assert!(blockchain
+            .add_transaction(transfer(&sender, 5, 1))
+            .await
+            .is_err());
+        assert!(blockchain
+            .add_transaction(transfer(&sender, 5, 3 + MAX_NONCE_GAP))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn transactions_that_no_longer_apply_are_dropped_from_the_mempool() {
+        let sender = KeyPair::generate();
+        let blockchain = dev_chain(unix_now() - 100, vec![(sender.public_key(), 100)]);
+
+        // Two spends of the same nonce; whichever is mined, the other is
+        // stale from then on.
+        let first = transfer(&sender, 10, 0);
+        let second = transfer(&sender, 20, 0);
+        blockchain.add_to_mempool(first).await.unwrap();
+        blockchain.add_to_mempool(second).await.unwrap();
+
+        assert_eq!(blockchain.mine_block().await.unwrap().transactions.len(), 1);
+        assert_eq!(blockchain.get_mempool_transactions().await.len(), 1);
+        assert!(blockchain
+            .mine_block()
+            .await
+            .unwrap()
+            .transactions
+            .is_empty());
+        assert!(blockchain.get_mempool_transactions().await.is_empty());
+    }
+
+    /// Two chains from the same genesis and authority, for building forks.
+    fn forked_chains(balances: Vec<(PublicKey, u64)>) -> (Blockchain, Blockchain) {
+        let secret = [7; 32];
+        let genesis_time = unix_now() - 100;
+        let chain = |balances| {
+            let authority = KeyPair::from_secret_key(&secret).unwrap();
+            chain_with_authority(authority, genesis_time, balances)
+        };
+        (chain(balances.clone()), chain(balances))
+    }
+
+    #[tokio::test]
+    async fn a_longer_branch_replaces_the_blocks_above_the_fork() {
+        let sender = KeyPair::generate();
+        let (ours, theirs) = forked_chains(vec![(sender.public_key(), 100)]);
+        let shared = ours.mine_block().await.unwrap();
+        theirs.add_block(shared).await.unwrap();
+
+        // Our blocks 2 and 3 include a spend; theirs, 2 to 4, do not.
+        let spend = transfer(&sender, 10, 0);
+        ours.add_transaction(spend.clone()).await.unwrap();
+        ours.mine_block().await.unwrap();
+        let mut branch = Vec::new();
+        for _ in 0..3 {
+            branch.push(theirs.mine_block().await.unwrap());
+        }
+
+        // A branch shorter than our chain is refused.
+        assert!(ours.reorganize(branch[..1].to_vec()).await.is_err());
+        ours.reorganize(branch.clone()).await.unwrap();
+
+        assert_eq!(ours.get_latest_block().await.hash(), branch[2].hash());
+        assert_eq!(ours.get_account_balance(&sender.public_key()).await, 100);
+        // The spend only made it into the abandoned block, so it waits again.
+        assert_eq!(ours.get_mempool_transactions().await, vec![spend]);
+        ours.mine_block().await.unwrap();
+        assert_eq!(ours.get_account_balance(&sender.public_key()).await, 90);
+    }
+
+    #[tokio::test]
+    async fn a_branch_with_a_rejected_block_leaves_the_chain_as_it_was() {
+        let (ours, theirs) = forked_chains(Vec::new());
+        let head = ours.mine_block().await.unwrap();
+        let signer = theirs.get_signer().unwrap();
+
+        // Consensus accepts the block, but it commits to the wrong state.
+        let first = theirs.mine_block().await.unwrap();
+        let mut second = Block::new(
+            first.hash(),
+            Vec::new(),
+            2,
+            0,
+            first.header.timestamp,
+            signer.public_key(),
+            0,
+        );
+        second.sign(signer);
+
+        assert!(ours.reorganize(vec![first, second]).await.is_err());
+        assert_eq!(ours.get_latest_block().await.hash(), head.hash());
+        // Consensus was put back on the old chain, so it still grows.
+        let next = ours.mine_block().await.unwrap();
+        assert_eq!(next.header.previous_hash, head.hash());
     }
 }
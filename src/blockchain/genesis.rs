@@ -0,0 +1,45 @@
+use crate::crypto::{BlsPublicKey, BlsSignature, PublicKey};
+use crate::state::rewards::RewardSchedule;
+
+/// A validator bonded at genesis, with the BLS key it votes with.
+#[derive(Debug, Clone)]
+pub struct GenesisValidator {
+    pub public_key: PublicKey,
+    pub bls_key: BlsPublicKey,
+    pub proof_of_possession: BlsSignature,
+    pub stake: u64,
+    /// Share of block rewards kept before delegators are paid, in basis points.
+    pub commission_bps: u64,
+}
+
+/// Parameters every node must agree on to produce the same genesis state.
+#[derive(Debug, Clone, Default)]
+pub struct GenesisConfig {
+    /// Distinguishes networks, such as a testnet and mainnet, that may share
+    /// a genesis timestamp. Peers on a different chain are disconnected.
+    pub chain_id: String,
+    /// Unix time in seconds of the genesis block; slot 0 starts here.
+    pub timestamp: u64,
+    /// Initial spendable balances.
+    pub balances: Vec<(PublicKey, u64)>,
+    /// Initial validator stakes, which form the active set for epoch 0.
+    pub validators: Vec<GenesisValidator>,
+    pub rewards: RewardSchedule,
+}
+
+impl GenesisConfig {
+    pub fn new(
+        chain_id: &str,
+        timestamp: u64,
+        balances: Vec<(PublicKey, u64)>,
+        validators: Vec<GenesisValidator>,
+    ) -> Self {
+        GenesisConfig {
+            chain_id: chain_id.to_string(),
+            timestamp,
+            balances,
+            validators,
+            rewards: RewardSchedule::default(),
+        }
+    }
+}
@@ -1,7 +1,9 @@
 pub mod block;
 pub mod chain;
+pub mod genesis;
 pub mod transaction;
 
 pub use block::Block;
 pub use chain::Blockchain;
-pub use transaction::Transaction;
+pub use genesis::{GenesisConfig, GenesisValidator};
+pub use transaction::{Transaction, TransactionKind};
@@ -1,9 +1,48 @@
-use crate::crypto::{Hash, Hashable, PublicKey};
+use crate::crypto::{verify_signature, BlsPublicKey, BlsSignature, Hash, Hashable, PublicKey};
+use crate::network::protocol::MAX_TRANSACTION_SIZE;
+use crate::network::wire;
 use serde::{Deserialize, Serialize};
 use std::hash::{Hash as StdHash, Hasher};
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TransactionKind {
+    /// Moves `amount` from `from` to `to`.
+    Transfer,
+    /// Registers `from` as a validator with `amount` bonded as self-stake.
+    /// `bls_key` signs its PBFT votes; the proof of possession shows the
+    /// sender holds the matching secret key. The validator keeps
+    /// `commission_bps` of its block rewards before sharing the rest.
+    RegisterValidator {
+        bls_key: BlsPublicKey,
+        proof_of_possession: BlsSignature,
+        commission_bps: u64,
+    },
+    /// Bonds `amount` of `from`'s balance to validator `to`.
+    Delegate,
+    /// Starts unbonding `amount` of `from`'s delegation to validator `to`.
+    Undelegate,
+    /// Returns all of `from`'s unbonded tokens whose unbonding period is over.
+    ClaimUnbonded,
+    /// Asks for validator `from` to be scheduled again after being jailed.
+    Unjail,
+}
+
+impl TransactionKind {
+    fn tag(&self) -> u8 {
+        match self {
+            TransactionKind::Transfer => 0,
+            TransactionKind::RegisterValidator { .. } => 1,
+            TransactionKind::Delegate => 2,
+            TransactionKind::Undelegate => 3,
+            TransactionKind::ClaimUnbonded => 4,
+            TransactionKind::Unjail => 5,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
+    pub kind: TransactionKind,
     pub from: PublicKey,
     pub to: PublicKey,
     pub amount: u64,
@@ -13,6 +52,7 @@ pub struct Transaction {
 
 impl StdHash for Transaction {
     fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind.hash(state);
         self.from.hash(state);
         self.to.hash(state);
         self.amount.hash(state);
@@ -22,7 +62,8 @@ impl StdHash for Transaction {
 
 impl PartialEq for Transaction {
     fn eq(&self, other: &Self) -> bool {
-        self.from == other.from
+        self.kind == other.kind
+            && self.from == other.from
             && self.to == other.to
             && self.amount == other.amount
             && self.nonce == other.nonce
@@ -33,7 +74,79 @@ impl Eq for Transaction {}
 
 impl Transaction {
     pub fn new(from: PublicKey, to: PublicKey, amount: u64, nonce: u64) -> Self {
+        Self::with_kind(TransactionKind::Transfer, from, to, amount, nonce)
+    }
+
+    pub fn register_validator(
+        validator: PublicKey,
+        bls_key: BlsPublicKey,
+        proof_of_possession: BlsSignature,
+        self_stake: u64,
+        commission_bps: u64,
+        nonce: u64,
+    ) -> Self {
+        Self::with_kind(
+            TransactionKind::RegisterValidator {
+                bls_key,
+                proof_of_possession,
+                commission_bps,
+            },
+            validator.clone(),
+            validator,
+            self_stake,
+            nonce,
+        )
+    }
+
+    pub fn delegate(delegator: PublicKey, validator: PublicKey, amount: u64, nonce: u64) -> Self {
+        Self::with_kind(
+            TransactionKind::Delegate,
+            delegator,
+            validator,
+            amount,
+            nonce,
+        )
+    }
+
+    pub fn undelegate(delegator: PublicKey, validator: PublicKey, amount: u64, nonce: u64) -> Self {
+        Self::with_kind(
+            TransactionKind::Undelegate,
+            delegator,
+            validator,
+            amount,
+            nonce,
+        )
+    }
+
+    pub fn claim_unbonded(delegator: PublicKey, nonce: u64) -> Self {
+        Self::with_kind(
+            TransactionKind::ClaimUnbonded,
+            delegator.clone(),
+            delegator,
+            0,
+            nonce,
+        )
+    }
+
+    pub fn unjail(validator: PublicKey, nonce: u64) -> Self {
+        Self::with_kind(
+            TransactionKind::Unjail,
+            validator.clone(),
+            validator,
+            0,
+            nonce,
+        )
+    }
+
+    fn with_kind(
+        kind: TransactionKind,
+        from: PublicKey,
+        to: PublicKey,
+        amount: u64,
+        nonce: u64,
+    ) -> Self {
         Transaction {
+            kind,
             from,
             to,
             amount,
@@ -42,20 +155,56 @@ impl Transaction {
         }
     }
 
+    /// The amount taken out of the sender's spendable balance.
+    pub fn spend(&self) -> u64 {
+        match self.kind {
+            TransactionKind::Transfer
+            | TransactionKind::RegisterValidator { .. }
+            | TransactionKind::Delegate => self.amount,
+            TransactionKind::Undelegate
+            | TransactionKind::ClaimUnbonded
+            | TransactionKind::Unjail => 0,
+        }
+    }
+
     pub fn sign(&mut self, signature: Vec<u8>) {
         self.signature = signature;
     }
 
+    /// The transaction's compact wire encoding.
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        wire::encode(self)
+    }
+
+    /// Decodes a transaction, refusing input over `MAX_TRANSACTION_SIZE`.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        wire::decode(bytes, MAX_TRANSACTION_SIZE)
+    }
+
+    pub fn is_within_size_limit(&self) -> bool {
+        wire::encoded_size(self).is_ok_and(|size| size <= MAX_TRANSACTION_SIZE)
+    }
+
+    /// Checks the transaction was signed over its hash by `from`.
     pub fn verify(&self) -> bool {
-        // Implement signature verification logic here
-        // This would typically involve checking the signature against the transaction data and the 'from' public key
-        true // Placeholder
+        verify_signature(&self.from, Hashable::hash(self).as_bytes(), &self.signature)
     }
 }
 
 impl Hashable for Transaction {
     fn hash(&self) -> Hash {
         let mut hasher = blake3::Hasher::new();
+        hasher.update(&[self.kind.tag()]);
+        if let TransactionKind::RegisterValidator {
+            bls_key,
+            proof_of_possession,
+            commission_bps,
+        } = &self.kind
+        {
+            hasher.update(bls_key.as_bytes());
+            hasher.update(proof_of_possession.as_bytes());
+            hasher.update(&commission_bps.to_le_bytes());
+        }
         hasher.update(self.from.as_bytes());
         hasher.update(self.to.as_bytes());
         hasher.update(&self.amount.to_le_bytes());
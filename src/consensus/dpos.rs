@@ -1,82 +1,460 @@
 // src/consensus/dpos.rs
 
-use crate::blockchain::block::Block;
-use crate::crypto::PublicKey;
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::crypto::{Hash, PublicKey};
 use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
-const BLOCK_TIME: Duration = Duration::from_secs(3);
-const VALIDATOR_COUNT: usize = 21;
+pub const BLOCK_TIME: Duration = Duration::from_secs(3);
+pub const VALIDATOR_COUNT: usize = 21;
+/// Number of blocks between validator set rotations.
+pub const EPOCH_LENGTH: u64 = 210;
 
+pub fn epoch_of(height: u64) -> u64 {
+    height / EPOCH_LENGTH
+}
+
+/// Whether the block at `height` is the first block of a new epoch.
+pub fn is_epoch_start(height: u64) -> bool {
+    height.is_multiple_of(EPOCH_LENGTH)
+}
+
+/// Derives the seed for `epoch` from the randomness beacon as of the last
+/// block before it (the genesis hash for epoch 0).
+pub fn epoch_seed(epoch: u64, randomness: &Hash) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"flux-epoch-seed");
+    hasher.update(&epoch.to_le_bytes());
+    hasher.update(randomness.as_bytes());
+    Hash::from(hasher.finalize().as_bytes())
+}
+
+/// The active set and seed of one epoch. Everything needed to answer "who
+/// should have produced slot N" without any local state.
+#[derive(Debug, Clone)]
+pub struct EpochSchedule {
+    pub epoch: u64,
+    pub seed: Hash,
+    /// Active validators ordered by stake descending, ties broken by public key.
+    pub validators: Vec<PublicKey>,
+    /// Validators removed mid-epoch, with the first slot they lost. Their
+    /// remaining slots stay in the rotation and are skipped.
+    pub excluded: HashMap<PublicKey, u64>,
+}
+
+impl EpochSchedule {
+    pub fn new(epoch: u64, seed: Hash, stakes: &HashMap<PublicKey, u64>) -> Self {
+        let mut validators: Vec<_> = stakes.iter().collect();
+        validators.sort_by(|a, b| {
+            b.1.cmp(a.1)
+                .then_with(|| a.0.as_bytes().cmp(b.0.as_bytes()))
+        });
+        EpochSchedule {
+            epoch,
+            seed,
+            validators: validators
+                .into_iter()
+                .take(VALIDATOR_COUNT)
+                .map(|(k, _)| k.clone())
+                .collect(),
+            excluded: HashMap::new(),
+        }
+    }
+
+    pub fn is_excluded(&self, validator: &PublicKey, slot: u64) -> bool {
+        self.excluded
+            .get(validator)
+            .is_some_and(|from_slot| slot >= *from_slot)
+    }
+
+    /// Each round of `validators.len()` slots is a seeded permutation of the
+    /// active set, so every validator gets exactly one slot per round.
+    pub fn producer_for_slot(&self, slot: u64) -> Option<&PublicKey> {
+        if self.validators.is_empty() {
+            return None;
+        }
+        let count = self.validators.len() as u64;
+        let round = slot / count;
+        let order = self.round_order(round);
+        Some(&self.validators[order[(slot % count) as usize]])
+    }
+
+    fn round_order(&self, round: u64) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.validators.len()).collect();
+        for i in (1..order.len()).rev() {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(self.seed.as_bytes());
+            hasher.update(&round.to_le_bytes());
+            hasher.update(&(i as u64).to_le_bytes());
+            let digest = hasher.finalize();
+            let mut word = [0u8; 8];
+            word.copy_from_slice(&digest.as_bytes()[..8]);
+            let j = (u64::from_le_bytes(word) % (i as u64 + 1)) as usize;
+            order.swap(i, j);
+        }
+        order
+    }
+}
+
+/// Time is divided into `BLOCK_TIME` slots counted from the genesis
+/// timestamp. Each slot has exactly one scheduled producer; a slot whose
+/// producer stays silent is simply skipped by the next block.
 pub struct DPoS {
-    validators: HashMap<PublicKey, u64>,
-    active_validators: Vec<PublicKey>,
+    schedule: EpochSchedule,
+    history: HashMap<u64, EpochSchedule>,
     total_stake: u64,
-    current_validator_index: usize,
-    last_block_time: Instant,
+    genesis_time: u64,
+    /// Slot of the head block, `None` while the head is genesis, which
+    /// takes no slot.
+    last_slot: Option<u64>,
+}
+
+impl Default for DPoS {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DPoS {
     pub fn new() -> Self {
         DPoS {
-            validators: HashMap::new(),
-            active_validators: Vec::new(),
+            schedule: EpochSchedule::new(0, Hash::default(), &HashMap::new()),
+            history: HashMap::new(),
             total_stake: 0,
-            current_validator_index: 0,
-            last_block_time: Instant::now(),
+            genesis_time: 0,
+            last_slot: None,
         }
     }
 
-    pub fn add_validator(&mut self, public_key: PublicKey, stake: u64) {
-        self.validators.insert(public_key.clone(), stake);
-        self.total_stake += stake;
-        self.update_active_validators();
+    pub fn set_genesis_time(&mut self, genesis_time: u64) {
+        self.genesis_time = genesis_time;
+        self.last_slot = None;
     }
 
-    pub fn remove_validator(&mut self, public_key: &PublicKey) {
-        if let Some(stake) = self.validators.remove(public_key) {
-            self.total_stake -= stake;
-            self.update_active_validators();
-        }
+    /// The slot containing `timestamp`, or `None` if it predates genesis.
+    pub fn slot_at(&self, timestamp: u64) -> Option<u64> {
+        timestamp
+            .checked_sub(self.genesis_time)
+            .map(|elapsed| elapsed / BLOCK_TIME.as_secs())
+    }
+
+    pub fn slot_start(&self, slot: u64) -> u64 {
+        self.genesis_time + slot * BLOCK_TIME.as_secs()
+    }
+
+    pub fn last_slot(&self) -> Option<u64> {
+        self.last_slot
+    }
+
+    /// Whether `slot` comes after the head's slot, so a block may claim it.
+    fn is_open(&self, slot: u64) -> bool {
+        self.last_slot.is_none_or(|last_slot| slot > last_slot)
+    }
+
+    /// Recomputes the active set for `epoch` from the stake table recorded in
+    /// the world state. This is the only place the schedule changes.
+    pub fn on_epoch_boundary(&mut self, epoch: u64, seed: Hash, stakes: &HashMap<PublicKey, u64>) {
+        self.total_stake = stakes.values().sum();
+        self.schedule = EpochSchedule::new(epoch, seed, stakes);
+        self.history.insert(epoch, self.schedule.clone());
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.schedule.epoch
     }
 
-    pub fn update_stake(&mut self, public_key: &PublicKey, new_stake: u64) {
-        if let Some(old_stake) = self.validators.get_mut(public_key) {
-            self.total_stake = self.total_stake - *old_stake + new_stake;
-            *old_stake = new_stake;
-            self.update_active_validators();
+    /// Validators currently allowed to produce blocks and vote.
+    pub fn active_validators(&self) -> Vec<PublicKey> {
+        self.schedule
+            .validators
+            .iter()
+            .filter(|validator| !self.schedule.excluded.contains_key(*validator))
+            .cloned()
+            .collect()
+    }
+
+    /// Removes a validator from the rest of the current epoch, starting at
+    /// `from_slot`. Applied while importing a block, so every node excludes
+    /// it at the same point.
+    pub fn exclude_validator(&mut self, validator: &PublicKey, from_slot: u64) {
+        if !self.schedule.validators.contains(validator) {
+            return;
         }
+        self.schedule
+            .excluded
+            .entry(validator.clone())
+            .or_insert(from_slot);
+        self.history
+            .insert(self.schedule.epoch, self.schedule.clone());
+    }
+
+    pub fn total_stake(&self) -> u64 {
+        self.total_stake
+    }
+
+    pub fn schedule_for_epoch(&self, epoch: u64) -> Option<&EpochSchedule> {
+        self.history.get(&epoch)
     }
 
-    fn update_active_validators(&mut self) {
-        let mut validators: Vec<_> = self.validators.iter().collect();
-        validators.sort_by(|a, b| b.1.cmp(a.1));
-        self.active_validators = validators
-            .into_iter()
-            .take(VALIDATOR_COUNT)
-            .map(|(k, _)| k.clone())
-            .collect();
+    /// Producers of the slots skipped between the current head and `block`,
+    /// ignoring validators already excluded. Only the most recent
+    /// `max_slots` gaps are considered, enough to fill any liveness window.
+    pub fn missed_producers(&self, block: &Block, max_slots: u64) -> Vec<PublicKey> {
+        let slot = match self.slot_at(block.header.timestamp) {
+            Some(slot) if self.is_open(slot) => slot,
+            _ => return Vec::new(),
+        };
+        let first_open = self.last_slot.map_or(0, |last_slot| last_slot + 1);
+        let first_missed = first_open.max(slot.saturating_sub(max_slots));
+        (first_missed..slot)
+            .filter_map(|missed| {
+                self.schedule
+                    .producer_for_slot(missed)
+                    .filter(|producer| !self.schedule.is_excluded(producer, missed))
+                    .cloned()
+            })
+            .collect()
     }
 
-    pub fn get_next_validator(&mut self) -> PublicKey {
-        let validator = self.active_validators[self.current_validator_index].clone();
-        self.current_validator_index =
-            (self.current_validator_index + 1) % self.active_validators.len();
-        validator
+    pub fn scheduled_producer(&self, slot: u64) -> Option<PublicKey> {
+        self.schedule.producer_for_slot(slot).cloned()
     }
 
+    /// The validator scheduled for the slot of the header's timestamp.
+    /// Depends only on the header itself and recorded epoch schedules, so it
+    /// can be evaluated for any historical block.
+    pub fn expected_producer(&self, header: &BlockHeader) -> Option<&PublicKey> {
+        let slot = self.slot_at(header.timestamp)?;
+        let schedule = self.schedule_for_epoch(header.epoch)?;
+        schedule
+            .producer_for_slot(slot)
+            .filter(|producer| !schedule.is_excluded(producer, slot))
+    }
+
+    /// Whether the header is in the right epoch for its height and comes
+    /// from the validator scheduled for its slot. Headers in epochs whose
+    /// schedule is not known yet never pass.
+    pub fn is_scheduled_producer(&self, header: &BlockHeader) -> bool {
+        header.epoch == epoch_of(header.height)
+            && self.expected_producer(header) == Some(&header.validator)
+    }
+
+    /// Checks a block that extends the current head: it must belong to the
+    /// right epoch, come from a later slot than its parent and be produced by
+    /// that slot's scheduled validator. Any slots in between were missed.
     pub fn is_valid_block_producer(&self, block: &Block) -> bool {
-        let expected_validator = &self.active_validators[self.current_validator_index];
-        &block.header.validator == expected_validator
+        match self.slot_at(block.header.timestamp) {
+            Some(slot) if self.is_open(slot) => self.is_scheduled_producer(&block.header),
+            _ => false,
+        }
     }
 
-    pub fn can_produce_block(&self) -> bool {
-        Instant::now().duration_since(self.last_block_time) >= BLOCK_TIME
+    /// Whether `public_key` owns the slot at `now` and that slot is still open.
+    pub fn can_produce_block(&self, public_key: &PublicKey, now: u64) -> bool {
+        match self.slot_at(now) {
+            Some(slot) if self.is_open(slot) => {
+                self.schedule.producer_for_slot(slot) == Some(public_key)
+                    && !self.schedule.is_excluded(public_key, slot)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn on_block_produced(&mut self, block: &Block) {
+        if let Some(slot) = self.slot_at(block.header.timestamp) {
+            self.last_slot = Some(slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KeyPair;
+
+    fn validators(count: usize) -> Vec<PublicKey> {
+        (0..count)
+            .map(|_| KeyPair::generate().public_key())
+            .collect()
+    }
+
+    fn equal_stakes(validators: &[PublicKey]) -> HashMap<PublicKey, u64> {
+        validators
+            .iter()
+            .map(|validator| (validator.clone(), 100))
+            .collect()
+    }
+
+    fn seed(byte: u8) -> Hash {
+        Hash::from([byte; 32])
+    }
+
+    fn block_in_slot(dpos: &DPoS, slot: u64, producer: &PublicKey) -> Block {
+        let height = slot + 1;
+        let timestamp = dpos.slot_start(slot);
+        Block::new(
+            Hash::default(),
+            Vec::new(),
+            height,
+            epoch_of(height),
+            timestamp,
+            producer.clone(),
+            0,
+        )
+    }
+
+    fn producers(schedule: &EpochSchedule) -> Vec<PublicKey> {
+        (0..EPOCH_LENGTH)
+            .map(|slot| schedule.producer_for_slot(slot).unwrap().clone())
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_producers() {
+        let mut keys = validators(VALIDATOR_COUNT);
+        let stakes = equal_stakes(&keys);
+        // A map with the same entries can iterate in a different order.
+        keys.reverse();
+        let reinserted = equal_stakes(&keys);
+
+        let first = EpochSchedule::new(1, seed(1), &stakes);
+        let second = EpochSchedule::new(1, seed(1), &reinserted);
+        assert_eq!(producers(&first), producers(&second));
+    }
+
+    #[test]
+    fn different_seed_gives_different_producers() {
+        let stakes = equal_stakes(&validators(VALIDATOR_COUNT));
+        let first = EpochSchedule::new(1, seed(1), &stakes);
+        let second = EpochSchedule::new(1, seed(2), &stakes);
+        assert_eq!(first.validators, second.validators);
+        assert_ne!(producers(&first), producers(&second));
+    }
+
+    #[test]
+    fn every_validator_produces_once_per_round() {
+        let stakes = equal_stakes(&validators(VALIDATOR_COUNT));
+        let schedule = EpochSchedule::new(0, seed(3), &stakes);
+        for round in producers(&schedule).chunks(VALIDATOR_COUNT) {
+            let mut round = round.to_vec();
+            round.sort();
+            round.dedup();
+            assert_eq!(round.len(), VALIDATOR_COUNT);
+        }
+    }
+
+    #[test]
+    fn ties_are_broken_by_public_key() {
+        let mut keys = validators(5);
+        let mut stakes = equal_stakes(&keys);
+        let richest = keys.pop().unwrap();
+        stakes.insert(richest.clone(), 200);
+
+        let schedule = EpochSchedule::new(0, seed(1), &stakes);
+        keys.sort();
+        assert_eq!(schedule.validators[0], richest);
+        assert_eq!(schedule.validators[1..], keys[..]);
+    }
+
+    #[test]
+    fn only_the_top_validators_are_active() {
+        let keys = validators(VALIDATOR_COUNT + 1);
+        let mut stakes = equal_stakes(&keys);
+        stakes.insert(keys[0].clone(), 1);
+
+        let schedule = EpochSchedule::new(0, seed(1), &stakes);
+        assert_eq!(schedule.validators.len(), VALIDATOR_COUNT);
+        assert!(!schedule.validators.contains(&keys[0]));
+    }
+
+    #[test]
+    fn excluded_validators_are_skipped_from_their_slot_on() {
+        let stakes = equal_stakes(&validators(VALIDATOR_COUNT));
+        let mut dpos = DPoS::new();
+        dpos.on_epoch_boundary(0, seed(1), &stakes);
+
+        let offender = dpos.scheduled_producer(5).unwrap();
+        let later_slot = (6..EPOCH_LENGTH)
+            .find(|slot| dpos.scheduled_producer(*slot).as_ref() == Some(&offender))
+            .unwrap();
+        dpos.exclude_validator(&offender, 6);
+
+        let block_at = |slot| block_in_slot(&dpos, slot, &offender);
+        assert_eq!(dpos.expected_producer(&block_at(5).header), Some(&offender));
+        assert_eq!(dpos.expected_producer(&block_at(later_slot).header), None);
+        assert!(!dpos.active_validators().contains(&offender));
+        assert!(!dpos.can_produce_block(&offender, dpos.slot_start(later_slot)));
+
+        // A skipped slot of an excluded validator does not count as missed.
+        dpos.on_block_produced(&block_in_slot(&dpos, 5, &offender));
+        let next = block_in_slot(&dpos, later_slot + 1, &offender);
+        assert!(!dpos
+            .missed_producers(&next, EPOCH_LENGTH)
+            .contains(&offender));
+    }
+
+    #[test]
+    fn only_the_most_recent_skipped_slots_count_as_missed() {
+        let stakes = equal_stakes(&validators(VALIDATOR_COUNT));
+        let mut dpos = DPoS::new();
+        dpos.on_epoch_boundary(0, seed(1), &stakes);
+        let producer = dpos.scheduled_producer(0).unwrap();
+        dpos.on_block_produced(&block_in_slot(&dpos, 0, &producer));
+
+        let after_stall = block_in_slot(&dpos, 1_000, &producer);
+        let missed = dpos.missed_producers(&after_stall, VALIDATOR_COUNT as u64);
+        assert_eq!(missed.len(), VALIDATOR_COUNT);
+        let short_gap = block_in_slot(&dpos, 3, &producer);
+        assert_eq!(
+            dpos.missed_producers(&short_gap, VALIDATOR_COUNT as u64)
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn slots_are_counted_from_genesis() {
+        let mut dpos = DPoS::new();
+        let genesis_time = 1_000;
+        let block_time = BLOCK_TIME.as_secs();
+        dpos.set_genesis_time(genesis_time);
+
+        assert_eq!(dpos.slot_at(genesis_time - 1), None);
+        assert_eq!(dpos.slot_at(genesis_time), Some(0));
+        assert_eq!(dpos.slot_at(genesis_time + block_time - 1), Some(0));
+        assert_eq!(dpos.slot_at(genesis_time + block_time), Some(1));
+        assert_eq!(dpos.slot_start(7), genesis_time + 7 * block_time);
+        assert_eq!(dpos.slot_at(dpos.slot_start(7)), Some(7));
+    }
+
+    #[test]
+    fn the_first_slot_after_genesis_can_be_produced() {
+        let stakes = equal_stakes(&validators(VALIDATOR_COUNT));
+        let mut dpos = DPoS::new();
+        dpos.set_genesis_time(1_000);
+        dpos.on_epoch_boundary(0, seed(1), &stakes);
+        assert_eq!(dpos.last_slot(), None);
+
+        let first = dpos.scheduled_producer(0).unwrap();
+        let block = block_in_slot(&dpos, 0, &first);
+        assert!(dpos.can_produce_block(&first, dpos.slot_start(0)));
+        assert!(dpos.is_valid_block_producer(&block));
+        assert!(dpos.missed_producers(&block, EPOCH_LENGTH).is_empty());
+
+        dpos.on_block_produced(&block);
+        assert_eq!(dpos.last_slot(), Some(0));
+        assert!(!dpos.is_valid_block_producer(&block));
+        assert!(!dpos.can_produce_block(&first, dpos.slot_start(0)));
     }
 
-    pub fn on_block_produced(&mut self) {
-        self.last_block_time = Instant::now();
-        self.current_validator_index =
-            (self.current_validator_index + 1) % self.active_validators.len();
+    #[test]
+    fn epochs_start_every_epoch_length_blocks() {
+        assert_eq!(epoch_of(0), 0);
+        assert_eq!(epoch_of(EPOCH_LENGTH - 1), 0);
+        assert_eq!(epoch_of(EPOCH_LENGTH), 1);
+        assert!(is_epoch_start(0));
+        assert!(!is_epoch_start(EPOCH_LENGTH - 1));
+        assert!(is_epoch_start(2 * EPOCH_LENGTH));
+        assert_ne!(epoch_seed(1, &seed(1)), epoch_seed(2, &seed(1)));
     }
 }
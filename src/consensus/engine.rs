@@ -0,0 +1,100 @@
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::consensus::evidence::Evidence;
+use crate::consensus::pbft::{QuorumCertificate, Vote};
+use crate::crypto::{BlsKeyPair, BlsPublicKey, Hash, PublicKey};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The rules `Blockchain` needs from a consensus algorithm: who may produce
+/// the next block, whether an incoming block is acceptable, and how the
+/// validator set follows the stake table.
+pub trait ConsensusEngine: Send + Sync {
+    /// Sets up epoch 0 from the genesis timestamp, seed and stake table.
+    fn on_genesis(
+        &mut self,
+        genesis_time: u64,
+        seed: Hash,
+        stakes: &HashMap<PublicKey, u64>,
+        bls_keys: &HashMap<PublicKey, BlsPublicKey>,
+    );
+
+    /// Rotates the validator set. `bls_keys` are the vote keys of the
+    /// validators in `stakes`.
+    fn on_epoch_boundary(
+        &mut self,
+        epoch: u64,
+        seed: Hash,
+        stakes: &HashMap<PublicKey, u64>,
+        bls_keys: &HashMap<PublicKey, BlsPublicKey>,
+    );
+
+    fn can_produce_block(&self, public_key: &PublicKey, now: u64) -> bool;
+
+    /// Whether a block extending the current head is acceptable. Does not
+    /// change the engine, so a block can still be rejected by the world state
+    /// afterwards.
+    fn is_valid_block(&self, block: &Block) -> bool;
+
+    /// Whether the header's validator was entitled to produce it, judged
+    /// from the header alone. Used to check headers during sync before
+    /// their blocks are downloaded; only headers in epochs whose validator
+    /// set is known can pass.
+    fn is_scheduled_producer(&self, header: &BlockHeader) -> bool;
+
+    /// Advances the engine's view of the head to a block that passed
+    /// `is_valid_block` and was applied to the world state.
+    fn on_block_produced(&mut self, block: &Block);
+
+    /// Validators whose turn was skipped between the head and `block`.
+    /// Called before `on_block_produced`.
+    fn missed_producers(&self, _block: &Block, _max_slots: u64) -> Vec<PublicKey> {
+        Vec::new()
+    }
+
+    /// Picks up after `head` when the chain starts from a snapshot rather
+    /// than replaying from genesis. `on_epoch_boundary` has already been
+    /// called for the epoch after it.
+    fn on_snapshot_restored(&mut self, _head: &Block) {}
+
+    /// Stops a slashed or jailed validator from producing after `block`.
+    fn remove_validator(&mut self, _validator: &PublicKey, _block: &Block) {}
+
+    /// Counts a vote from a peer and returns whether it completed a
+    /// certificate.
+    fn on_vote(&mut self, _vote: Vote) -> bool {
+        false
+    }
+
+    /// Signs the votes `validator` owes for the head block with its vote
+    /// key, to be gossiped. They are counted as if received.
+    fn cast_votes(&mut self, _validator: &PublicKey, _vote_key: &BlsKeyPair) -> Vec<Vote> {
+        Vec::new()
+    }
+
+    /// The aggregate commit certificate finalizing `block_hash`, if it is
+    /// the most recently finalized block.
+    fn commit_certificate(&self, _block_hash: &Hash) -> Option<QuorumCertificate> {
+        None
+    }
+
+    /// Height of the highest block with a commit certificate. The chain is
+    /// never reorganized below it.
+    fn finalized_height(&self) -> Option<u64> {
+        None
+    }
+
+    /// Misbehaviour detected by the engine since the last call.
+    fn take_evidence(&mut self) -> Vec<Evidence> {
+        Vec::new()
+    }
+
+    /// How often the node should attempt to produce a block, or `None` if it
+    /// only produces in response to transactions.
+    fn block_interval(&self) -> Option<Duration>;
+
+    /// Whether every accepted transaction should immediately be sealed into
+    /// a block.
+    fn seals_on_transaction(&self) -> bool {
+        false
+    }
+}
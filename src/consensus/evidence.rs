@@ -0,0 +1,212 @@
+use crate::blockchain::block::BlockHeader;
+use crate::consensus::pbft::Vote;
+use crate::crypto::{BlsPublicKey, Hash, Hashable, PublicKey};
+use serde::{Deserialize, Serialize};
+
+/// A header together with the producer signature that was gossiped with it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedHeader {
+    pub header: BlockHeader,
+    pub signature: Vec<u8>,
+}
+
+/// Proof that a validator signed two conflicting messages for the same height.
+/// Either piece alone is a valid signed message; together they are slashable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Evidence {
+    DoubleSign {
+        first: Box<SignedHeader>,
+        second: Box<SignedHeader>,
+    },
+    DoubleVote {
+        first: Box<Vote>,
+        second: Box<Vote>,
+    },
+}
+
+impl Evidence {
+    pub fn offender(&self) -> &PublicKey {
+        match self {
+            Evidence::DoubleSign { first, .. } => &first.header.validator,
+            Evidence::DoubleVote { first, .. } => &first.validator,
+        }
+    }
+
+    pub fn height(&self) -> u64 {
+        match self {
+            Evidence::DoubleSign { first, .. } => first.header.height,
+            Evidence::DoubleVote { first, .. } => first.height,
+        }
+    }
+
+    /// Checks the evidence is self-consistent and correctly signed. Votes are
+    /// BLS-signed, so double-vote evidence needs the offender's BLS key.
+    pub fn verify(&self, bls_key: Option<&BlsPublicKey>) -> Result<(), String> {
+        match self {
+            Evidence::DoubleSign { first, second } => {
+                if first.header.validator != second.header.validator {
+                    return Err("Headers are from different validators".to_string());
+                }
+                if first.header.height != second.header.height {
+                    return Err("Headers are at different heights".to_string());
+                }
+                if first.header.hash() == second.header.hash() {
+                    return Err("Headers are identical".to_string());
+                }
+                if !first.header.verify_signature(&first.signature)
+                    || !second.header.verify_signature(&second.signature)
+                {
+                    return Err("Invalid header signature".to_string());
+                }
+            }
+            Evidence::DoubleVote { first, second } => {
+                if first.validator != second.validator {
+                    return Err("Votes are from different validators".to_string());
+                }
+                if first.kind != second.kind
+                    || first.height != second.height
+                    || first.view != second.view
+                {
+                    return Err("Votes are for different rounds".to_string());
+                }
+                if first.block_hash == second.block_hash {
+                    return Err("Votes are for the same block".to_string());
+                }
+                let bls_key = bls_key.ok_or("Offender has no BLS key")?;
+                if !first.verify(bls_key) || !second.verify(bls_key) {
+                    return Err("Invalid vote signature".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Hashable for Evidence {
+    fn hash(&self) -> Hash {
+        let mut hasher = blake3::Hasher::new();
+        match self {
+            Evidence::DoubleSign { first, second } => {
+                hasher.update(&[0]);
+                hasher.update(first.header.hash().as_bytes());
+                hasher.update(second.header.hash().as_bytes());
+            }
+            Evidence::DoubleVote { first, second } => {
+                hasher.update(&[1]);
+                hasher.update(first.validator.as_bytes());
+                hasher.update(&first.signing_bytes());
+                hasher.update(&second.signing_bytes());
+            }
+        }
+        Hash::from(hasher.finalize().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::Block;
+    use crate::consensus::pbft::VoteKind;
+    use crate::crypto::{BlsKeyPair, KeyPair};
+
+    fn signed_header(keypair: &KeyPair, height: u64, timestamp: u64) -> Box<SignedHeader> {
+        let mut block = Block::new(
+            Hash::default(),
+            Vec::new(),
+            height,
+            0,
+            timestamp,
+            keypair.public_key(),
+            0,
+        );
+        block.sign(keypair);
+        Box::new(SignedHeader {
+            header: block.header,
+            signature: block.signature,
+        })
+    }
+
+    fn vote(keypair: &BlsKeyPair, validator: &PublicKey, block_hash: Hash) -> Box<Vote> {
+        Box::new(Vote::new(
+            VoteKind::Commit,
+            3,
+            0,
+            block_hash,
+            validator.clone(),
+            keypair,
+        ))
+    }
+
+    #[test]
+    fn two_headers_at_one_height_are_a_double_sign() {
+        let keypair = KeyPair::generate();
+        let evidence = Evidence::DoubleSign {
+            first: signed_header(&keypair, 3, 1),
+            second: signed_header(&keypair, 3, 2),
+        };
+        evidence.verify(None).unwrap();
+        assert_eq!(evidence.offender(), &keypair.public_key());
+        assert_eq!(evidence.height(), 3);
+    }
+
+    #[test]
+    fn headers_that_do_not_conflict_are_not_evidence() {
+        let keypair = KeyPair::generate();
+        let other = KeyPair::generate();
+        let cases = [
+            (signed_header(&keypair, 3, 1), signed_header(&keypair, 3, 1)),
+            (signed_header(&keypair, 3, 1), signed_header(&keypair, 4, 2)),
+            (signed_header(&keypair, 3, 1), signed_header(&other, 3, 2)),
+        ];
+        for (first, second) in cases {
+            assert!(Evidence::DoubleSign { first, second }.verify(None).is_err());
+        }
+    }
+
+    #[test]
+    fn forged_header_signatures_are_rejected() {
+        let keypair = KeyPair::generate();
+        let mut second = signed_header(&keypair, 3, 2);
+        second.signature = KeyPair::generate().sign(second.header.hash().as_bytes());
+        let evidence = Evidence::DoubleSign {
+            first: signed_header(&keypair, 3, 1),
+            second,
+        };
+        assert!(evidence.verify(None).is_err());
+    }
+
+    #[test]
+    fn double_votes_are_checked_against_the_offenders_bls_key() {
+        let validator = KeyPair::generate().public_key();
+        let bls = BlsKeyPair::generate();
+        let evidence = Evidence::DoubleVote {
+            first: vote(&bls, &validator, Hash::from([1; 32])),
+            second: vote(&bls, &validator, Hash::from([2; 32])),
+        };
+        evidence.verify(Some(&bls.public_key())).unwrap();
+        assert!(evidence.verify(None).is_err());
+        assert!(evidence
+            .verify(Some(&BlsKeyPair::generate().public_key()))
+            .is_err());
+
+        let same_block = Evidence::DoubleVote {
+            first: vote(&bls, &validator, Hash::from([1; 32])),
+            second: vote(&bls, &validator, Hash::from([1; 32])),
+        };
+        assert!(same_block.verify(Some(&bls.public_key())).is_err());
+
+        // Voting again after the height was proposed anew is allowed.
+        let other_view = Evidence::DoubleVote {
+            first: vote(&bls, &validator, Hash::from([1; 32])),
+            second: Box::new(Vote::new(
+                VoteKind::Commit,
+                3,
+                1,
+                Hash::from([2; 32]),
+                validator.clone(),
+                &bls,
+            )),
+        };
+        assert!(other_view.verify(Some(&bls.public_key())).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::consensus::dpos::epoch_of;
+use crate::consensus::engine::ConsensusEngine;
+use crate::consensus::{unix_now, MAX_CLOCK_DRIFT_SECS};
+use crate::crypto::{BlsPublicKey, Hash, PublicKey};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy)]
+pub enum SealMode {
+    /// Seal a block as soon as a transaction is accepted.
+    Instant,
+    /// Seal a block on a fixed interval, whether or not it has transactions.
+    Interval(Duration),
+}
+
+/// Single-authority engine for local development: one key produces every
+/// block and no quorum is needed.
+pub struct InstantSeal {
+    authority: PublicKey,
+    mode: SealMode,
+    last_timestamp: u64,
+}
+
+impl InstantSeal {
+    pub fn new(authority: PublicKey, mode: SealMode) -> Self {
+        InstantSeal {
+            authority,
+            mode,
+            last_timestamp: 0,
+        }
+    }
+}
+
+impl ConsensusEngine for InstantSeal {
+    fn on_genesis(
+        &mut self,
+        genesis_time: u64,
+        _seed: Hash,
+        _stakes: &HashMap<PublicKey, u64>,
+        _bls_keys: &HashMap<PublicKey, BlsPublicKey>,
+    ) {
+        self.last_timestamp = genesis_time;
+    }
+
+    fn on_epoch_boundary(
+        &mut self,
+        _epoch: u64,
+        _seed: Hash,
+        _stakes: &HashMap<PublicKey, u64>,
+        _bls_keys: &HashMap<PublicKey, BlsPublicKey>,
+    ) {
+    }
+
+    fn on_snapshot_restored(&mut self, head: &Block) {
+        self.last_timestamp = head.header.timestamp;
+    }
+
+    fn can_produce_block(&self, public_key: &PublicKey, _now: u64) -> bool {
+        public_key == &self.authority
+    }
+
+    fn is_scheduled_producer(&self, header: &BlockHeader) -> bool {
+        header.validator == self.authority && header.epoch == epoch_of(header.height)
+    }
+
+    fn is_valid_block(&self, block: &Block) -> bool {
+        self.is_scheduled_producer(&block.header)
+            && block.header.timestamp >= self.last_timestamp
+            && block.header.timestamp <= unix_now() + MAX_CLOCK_DRIFT_SECS
+    }
+
+    fn on_block_produced(&mut self, block: &Block) {
+        self.last_timestamp = block.header.timestamp;
+    }
+
+    fn block_interval(&self) -> Option<Duration> {
+        match self.mode {
+            SealMode::Instant => None,
+            SealMode::Interval(interval) => Some(interval),
+        }
+    }
+
+    fn seals_on_transaction(&self) -> bool {
+        matches!(self.mode, SealMode::Instant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KeyPair;
+
+    fn block(producer: &PublicKey, height: u64, timestamp: u64) -> Block {
+        Block::new(
+            Hash::default(),
+            Vec::new(),
+            height,
+            epoch_of(height),
+            timestamp,
+            producer.clone(),
+            0,
+        )
+    }
+
+    fn engine(authority: &PublicKey, genesis_time: u64) -> InstantSeal {
+        let mut engine = InstantSeal::new(authority.clone(), SealMode::Instant);
+        engine.on_genesis(
+            genesis_time,
+            Hash::default(),
+            &HashMap::new(),
+            &HashMap::new(),
+        );
+        engine
+    }
+
+    #[test]
+    fn only_the_authority_produces() {
+        let authority = KeyPair::generate().public_key();
+        let other = KeyPair::generate().public_key();
+        let now = unix_now();
+        let engine = engine(&authority, now - 10);
+
+        assert!(engine.can_produce_block(&authority, now));
+        assert!(!engine.can_produce_block(&other, now));
+        assert!(engine.is_valid_block(&block(&authority, 1, now)));
+        assert!(!engine.is_valid_block(&block(&other, 1, now)));
+
+        let mut wrong_epoch = block(&authority, 1, now);
+        wrong_epoch.header.epoch = 1;
+        assert!(!engine.is_scheduled_producer(&wrong_epoch.header));
+    }
+
+    #[test]
+    fn timestamps_never_go_backwards_or_into_the_future() {
+        let authority = KeyPair::generate().public_key();
+        let now = unix_now();
+        let mut engine = engine(&authority, now - 10);
+
+        assert!(!engine.is_valid_block(&block(&authority, 1, now - 11)));
+        assert!(!engine.is_valid_block(&block(&authority, 1, now + 60)));
+
+        engine.on_block_produced(&block(&authority, 1, now - 5));
+        assert!(engine.is_valid_block(&block(&authority, 2, now - 5)));
+        assert!(!engine.is_valid_block(&block(&authority, 2, now - 6)));
+    }
+
+    #[test]
+    fn the_mode_decides_when_blocks_are_sealed() {
+        let authority = KeyPair::generate().public_key();
+        let instant = InstantSeal::new(authority.clone(), SealMode::Instant);
+        assert!(instant.seals_on_transaction());
+        assert_eq!(instant.block_interval(), None);
+
+        let interval = Duration::from_secs(5);
+        let timed = InstantSeal::new(authority, SealMode::Interval(interval));
+        assert!(!timed.seals_on_transaction());
+        assert_eq!(timed.block_interval(), Some(interval));
+    }
+}
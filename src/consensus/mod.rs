@@ -1,66 +1,286 @@
 pub mod dpos;
+pub mod engine;
+pub mod evidence;
+pub mod instant_seal;
 pub mod pbft;
+pub mod randomness;
 
-use self::dpos::DPoS;
-use self::pbft::PBFT;
-use crate::blockchain::block::Block;
-use crate::crypto::PublicKey;
-use std::collections::HashSet;
+pub use self::engine::ConsensusEngine;
+pub use self::instant_seal::{InstantSeal, SealMode};
 
+use self::dpos::{DPoS, BLOCK_TIME};
+use self::evidence::Evidence;
+use self::pbft::{QuorumCertificate, Vote, VoteKind, PBFT};
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::crypto::{BlsKeyPair, BlsPublicKey, Hash, PublicKey};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How far ahead of the local clock a block timestamp may be before the
+/// block is treated as coming from a future slot.
+pub(crate) const MAX_CLOCK_DRIFT_SECS: u64 = 1;
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// DPoS block production with PBFT finality. Finality does not hold up
+/// production: blocks are voted on as they arrive, see `PBFT`.
 pub struct ConsensusManager {
     dpos: DPoS,
     pbft: PBFT,
 }
 
+impl Default for ConsensusManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ConsensusManager {
-    pub fn new(validators: HashSet<PublicKey>) -> Self {
+    pub fn new() -> Self {
         ConsensusManager {
             dpos: DPoS::new(),
-            pbft: PBFT::new(validators),
+            pbft: PBFT::new(),
         }
     }
 
-    pub fn add_validator(&mut self, public_key: PublicKey, stake: u64) {
-        self.dpos.add_validator(public_key, stake);
+    pub fn current_epoch(&self) -> u64 {
+        self.dpos.epoch()
     }
 
-    pub fn remove_validator(&mut self, public_key: &PublicKey) {
-        self.dpos.remove_validator(public_key);
+    pub fn slot_at(&self, timestamp: u64) -> Option<u64> {
+        self.dpos.slot_at(timestamp)
     }
 
-    pub fn update_stake(&mut self, public_key: &PublicKey, new_stake: u64) {
-        self.dpos.update_stake(public_key, new_stake);
+    pub fn scheduled_producer(&self, slot: u64) -> Option<PublicKey> {
+        self.dpos.scheduled_producer(slot)
     }
 
-    pub fn can_produce_block(&self) -> bool {
-        self.dpos.can_produce_block()
+    pub fn on_prepare_message(&mut self, vote: Vote) -> bool {
+        self.pbft.on_prepare_message(vote)
     }
 
-    pub fn get_next_validator(&mut self) -> PublicKey {
-        self.dpos.get_next_validator()
+    pub fn on_commit_message(&mut self, vote: Vote) -> bool {
+        self.pbft.on_commit_message(vote)
     }
+}
 
-    pub fn on_block_produced(&mut self, block: Block) -> bool {
-        if !self.dpos.is_valid_block_producer(&block) {
-            return false;
+impl ConsensusEngine for ConsensusManager {
+    fn on_genesis(
+        &mut self,
+        genesis_time: u64,
+        seed: Hash,
+        stakes: &HashMap<PublicKey, u64>,
+        bls_keys: &HashMap<PublicKey, BlsPublicKey>,
+    ) {
+        self.dpos.set_genesis_time(genesis_time);
+        self.on_epoch_boundary(0, seed, stakes, bls_keys);
+    }
+
+    /// Rotates the DPoS active set and the PBFT voter set together so both
+    /// follow the stake table as of the end of the previous epoch. Voters
+    /// keep the DPoS ranking order, which certificate bitmaps index into.
+    fn on_epoch_boundary(
+        &mut self,
+        epoch: u64,
+        seed: Hash,
+        stakes: &HashMap<PublicKey, u64>,
+        bls_keys: &HashMap<PublicKey, BlsPublicKey>,
+    ) {
+        self.dpos.on_epoch_boundary(epoch, seed, stakes);
+        self.pbft.set_validators(
+            self.dpos
+                .active_validators()
+                .into_iter()
+                .filter_map(|validator| {
+                    let bls_key = bls_keys.get(&validator)?.clone();
+                    Some((validator, bls_key))
+                })
+                .collect(),
+        );
+    }
+
+    fn can_produce_block(&self, public_key: &PublicKey, now: u64) -> bool {
+        self.dpos.can_produce_block(public_key, now)
+    }
+
+    fn is_valid_block(&self, block: &Block) -> bool {
+        // The only wall-clock check: a block may not claim a slot that has
+        // not started yet, or a producer could pre-sign all its future slots.
+        self.dpos.is_valid_block_producer(block)
+            && block.header.timestamp <= unix_now() + MAX_CLOCK_DRIFT_SECS
+    }
+
+    fn is_scheduled_producer(&self, header: &BlockHeader) -> bool {
+        self.dpos.is_scheduled_producer(header)
+    }
+
+    /// Opens the PBFT round for the block, in the view of its slot: two
+    /// proposals at one height come from different slots unless their
+    /// producer signed both.
+    fn on_block_produced(&mut self, block: &Block) {
+        let view = self.dpos.slot_at(block.header.timestamp).unwrap_or(0);
+        self.pbft.on_propose_block(block.clone(), view);
+        self.dpos.on_block_produced(block);
+    }
+
+    fn on_snapshot_restored(&mut self, head: &Block) {
+        self.dpos.on_block_produced(head);
+    }
+
+    fn missed_producers(&self, block: &Block, max_slots: u64) -> Vec<PublicKey> {
+        self.dpos.missed_producers(block, max_slots)
+    }
+
+    /// Drops a slashed or jailed validator from block production and voting
+    /// for the rest of the epoch, starting after the slot of `block`.
+    fn remove_validator(&mut self, validator: &PublicKey, block: &Block) {
+        if let Some(slot) = self.dpos.slot_at(block.header.timestamp) {
+            self.dpos.exclude_validator(validator, slot + 1);
         }
+        self.pbft.remove_validator(validator);
+    }
 
-        self.dpos.on_block_produced();
-        self.pbft.on_propose_block(block)
+    fn on_vote(&mut self, vote: Vote) -> bool {
+        match vote.kind {
+            VoteKind::Prepare => self.on_prepare_message(vote),
+            VoteKind::Commit => self.on_commit_message(vote),
+        }
     }
-    pub fn on_prepare_message(
-        &mut self,
-        validator: PublicKey,
-        block_hash: crate::crypto::Hash,
-    ) -> bool {
-        self.pbft.on_prepare_message(validator, block_hash)
+
+    /// Votes only on a block from the current or the previous slot, so a
+    /// node catching up does not vote in rounds the network has left.
+    fn cast_votes(&mut self, validator: &PublicKey, vote_key: &BlsKeyPair) -> Vec<Vote> {
+        let recent = match (self.pbft.view(), self.dpos.slot_at(unix_now())) {
+            (Some(view), Some(slot)) => slot <= view + 1,
+            _ => false,
+        };
+        if !recent {
+            return Vec::new();
+        }
+        self.pbft.cast_votes(validator, vote_key)
     }
 
-    pub fn on_commit_message(
-        &mut self,
-        validator: PublicKey,
-        block_hash: crate::crypto::Hash,
-    ) -> bool {
-        self.pbft.on_commit_message(validator, block_hash)
+    fn commit_certificate(&self, block_hash: &Hash) -> Option<QuorumCertificate> {
+        self.pbft.commit_certificate(block_hash).cloned()
+    }
+
+    fn finalized_height(&self) -> Option<u64> {
+        self.pbft.finalized_height()
+    }
+
+    /// Double-vote evidence detected by PBFT since the last call.
+    fn take_evidence(&mut self) -> Vec<Evidence> {
+        self.pbft.take_evidence()
+    }
+
+    fn block_interval(&self) -> Option<Duration> {
+        Some(BLOCK_TIME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::dpos::epoch_of;
+    use crate::crypto::{BlsKeyPair, Hashable, KeyPair};
+
+    const VALIDATORS: usize = 5;
+
+    /// A manager whose genesis was `slots` slots ago, with `VALIDATORS`
+    /// equally staked validators of which the first has no vote key.
+    fn manager(slots: u64) -> (ConsensusManager, Vec<PublicKey>) {
+        let validators: Vec<PublicKey> = (0..VALIDATORS)
+            .map(|_| KeyPair::generate().public_key())
+            .collect();
+        let stakes = validators
+            .iter()
+            .map(|validator| (validator.clone(), 100))
+            .collect();
+        let bls_keys = validators[1..]
+            .iter()
+            .map(|validator| (validator.clone(), BlsKeyPair::generate().public_key()))
+            .collect();
+        let mut manager = ConsensusManager::new();
+        let genesis_time = unix_now() - slots * BLOCK_TIME.as_secs();
+        manager.on_genesis(genesis_time, Hash::default(), &stakes, &bls_keys);
+        (manager, validators)
+    }
+
+    /// A block in `slot` by its scheduled producer.
+    fn block_in_slot(manager: &ConsensusManager, slot: u64) -> Block {
+        let height = slot + 1;
+        Block::new(
+            Hash::default(),
+            Vec::new(),
+            height,
+            epoch_of(height),
+            manager.dpos.slot_start(slot),
+            manager.scheduled_producer(slot).unwrap(),
+            0,
+        )
+    }
+
+    #[test]
+    fn only_validators_with_a_vote_key_vote() {
+        let (manager, validators) = manager(10);
+        let voters: Vec<&PublicKey> = manager
+            .pbft
+            .validators()
+            .iter()
+            .map(|(validator, _)| validator)
+            .collect();
+        assert_eq!(voters.len(), VALIDATORS - 1);
+        assert!(!voters.contains(&&validators[0]));
+    }
+
+    #[test]
+    fn blocks_follow_each_other_without_waiting_for_finality() {
+        let (mut manager, _) = manager(10);
+        for slot in 0..5 {
+            let block = block_in_slot(&manager, slot);
+            assert!(manager.is_valid_block(&block));
+            manager.on_block_produced(&block);
+        }
+        assert!(manager
+            .commit_certificate(&block_in_slot(&manager, 4).hash())
+            .is_none());
+
+        // A slot already built on cannot be claimed again.
+        assert!(!manager.is_valid_block(&block_in_slot(&manager, 4)));
+    }
+
+    #[test]
+    fn a_block_from_a_future_slot_is_rejected() {
+        let (manager, _) = manager(10);
+        let block = block_in_slot(&manager, 20);
+        assert!(manager.is_scheduled_producer(&block.header));
+        assert!(!manager.is_valid_block(&block));
+    }
+
+    #[test]
+    fn a_removed_validator_stops_producing_but_keeps_its_voter_position() {
+        let (mut manager, _) = manager(10);
+        let voters = manager.pbft.validators().to_vec();
+        let first = block_in_slot(&manager, 0);
+        manager.on_block_produced(&first);
+
+        let offender = (1..10)
+            .map(|slot| manager.scheduled_producer(slot).unwrap())
+            .find(|producer| voters.iter().any(|(voter, _)| voter == producer))
+            .unwrap();
+        manager.remove_validator(&offender, &first);
+
+        let slots: Vec<u64> = (1..10)
+            .filter(|slot| manager.scheduled_producer(*slot).as_ref() == Some(&offender))
+            .collect();
+        for slot in slots {
+            assert!(!manager.is_scheduled_producer(&block_in_slot(&manager, slot).header));
+        }
+        assert_eq!(manager.pbft.validators(), &voters[..]);
     }
 }
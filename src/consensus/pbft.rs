@@ -1,5 +1,10 @@
 use crate::blockchain::block::Block;
-use crate::crypto::{Hash, PublicKey};
+use crate::consensus::evidence::Evidence;
+use crate::crypto::{
+    aggregate_bls_signatures, verify_aggregate_bls_signature, verify_bls_signature, BlsKeyPair,
+    BlsPublicKey, BlsSignature, Hash, Hashable, PublicKey,
+};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
 #[derive(Debug, Clone, PartialEq)]
@@ -9,72 +14,464 @@ enum PbftState {
     Commit,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VoteKind {
+    Prepare,
+    Commit,
+}
+
+/// How many votes of one validator that arrive before the block they are
+/// for are kept until it does: a prepare and a commit for a couple of views.
+const MAX_PENDING_VOTES_PER_VALIDATOR: usize = 4;
+
+/// The message every validator signs for a given round. It does not include
+/// the signer, so votes for the same block can be aggregated.
+fn vote_message(kind: VoteKind, height: u64, view: u64, block_hash: &Hash) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + 8 + 8 + 32);
+    bytes.push(match kind {
+        VoteKind::Prepare => 0,
+        VoteKind::Commit => 1,
+    });
+    bytes.extend_from_slice(&height.to_le_bytes());
+    bytes.extend_from_slice(&view.to_le_bytes());
+    bytes.extend_from_slice(block_hash.as_bytes());
+    bytes
+}
+
+/// A validator's BLS-signed prepare or commit vote for a block at a height.
+/// The view tells apart rounds for different proposals at the same height.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Vote {
+    pub kind: VoteKind,
+    pub height: u64,
+    pub view: u64,
+    pub block_hash: Hash,
+    pub validator: PublicKey,
+    pub signature: BlsSignature,
+}
+
+impl Vote {
+    pub fn new(
+        kind: VoteKind,
+        height: u64,
+        view: u64,
+        block_hash: Hash,
+        validator: PublicKey,
+        bls_keypair: &BlsKeyPair,
+    ) -> Self {
+        Vote {
+            kind,
+            height,
+            view,
+            block_hash,
+            validator,
+            signature: bls_keypair.sign(&vote_message(kind, height, view, &block_hash)),
+        }
+    }
+
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        vote_message(self.kind, self.height, self.view, &self.block_hash)
+    }
+
+    /// The round the vote is for, ordered by height and then view.
+    fn round(&self) -> (u64, u64) {
+        (self.height, self.view)
+    }
+
+    pub fn verify(&self, bls_key: &BlsPublicKey) -> bool {
+        verify_bls_signature(bls_key, &self.signing_bytes(), &self.signature)
+    }
+}
+
+/// A quorum of votes for one block compressed into a single aggregate BLS
+/// signature. `signers` is a bitmap over the epoch's ordered validator list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuorumCertificate {
+    pub kind: VoteKind,
+    pub height: u64,
+    pub view: u64,
+    pub block_hash: Hash,
+    pub signers: Vec<u8>,
+    pub signature: BlsSignature,
+}
+
+impl QuorumCertificate {
+    pub fn has_signed(&self, index: usize) -> bool {
+        self.signers
+            .get(index / 8)
+            .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+    }
+
+    pub fn signer_count(&self) -> usize {
+        self.signers
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    /// Checks the certificate against the validator list it was built from:
+    /// more than two thirds signed and the aggregate signature is valid.
+    pub fn verify(&self, validators: &[(PublicKey, BlsPublicKey)]) -> bool {
+        if self.signers.len() != validators.len().div_ceil(8)
+            || self.signer_count() <= 2 * validators.len() / 3
+        {
+            return false;
+        }
+        let signer_keys: Vec<BlsPublicKey> = validators
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| self.has_signed(*index))
+            .map(|(_, (_, bls_key))| bls_key.clone())
+            .collect();
+        verify_aggregate_bls_signature(
+            &signer_keys,
+            &vote_message(self.kind, self.height, self.view, &self.block_hash),
+            &self.signature,
+        )
+    }
+}
+
+/// Finality for the blocks DPoS produces: a round per block, in which
+/// prepare and then commit votes are aggregated into certificates.
+///
+/// Each round is identified by the block's height and a view, the slot it
+/// was proposed in, so a proposal that replaces another at the same height
+/// is voted on separately. Validators cast their votes with `cast_votes`
+/// once they have applied the block and gossip them; block production never
+/// waits for a round to finish. A new block starts a new round, abandoning
+/// one that did not commit.
 pub struct PBFT {
-    validators: HashSet<PublicKey>,
+    /// The epoch's voters in the DPoS order; their positions index
+    /// certificate bitmaps, so the list stays fixed for the whole epoch.
+    validators: Vec<(PublicKey, BlsPublicKey)>,
+    /// Voters removed during the epoch. They keep their bitmap position and
+    /// count towards the quorum size, but their votes are refused.
+    excluded: HashSet<PublicKey>,
     state: PbftState,
     current_block: Option<Block>,
-    prepare_messages: HashMap<PublicKey, Hash>,
-    commit_messages: HashMap<PublicKey, Hash>,
+    view: u64,
+    prepare_messages: HashMap<PublicKey, Vote>,
+    commit_messages: HashMap<PublicKey, Vote>,
+    prepare_certificate: Option<QuorumCertificate>,
+    /// The certificate of the highest committed block. Blocks below it are
+    /// final by extension, so older certificates are not kept.
+    commit_certificate: Option<QuorumCertificate>,
+    /// Verified votes for rounds after the current one, replayed when their
+    /// block is proposed. Gossip does not keep votes behind their block.
+    pending_votes: Vec<Vote>,
+    /// The round this node last voted in and the block it voted for, so a
+    /// round replayed after a reorganization is never voted on differently.
+    last_voted: Option<((u64, u64), Hash)>,
+    evidence: Vec<Evidence>,
+}
+
+impl Default for PBFT {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PBFT {
-    pub fn new(validators: HashSet<PublicKey>) -> Self {
+    pub fn new() -> Self {
         PBFT {
-            validators,
+            validators: Vec::new(),
+            excluded: HashSet::new(),
             state: PbftState::PrePrepare,
             current_block: None,
+            view: 0,
             prepare_messages: HashMap::new(),
             commit_messages: HashMap::new(),
+            prepare_certificate: None,
+            commit_certificate: None,
+            pending_votes: Vec::new(),
+            last_voted: None,
+            evidence: Vec::new(),
         }
     }
 
-    pub fn on_propose_block(&mut self, block: Block) -> bool {
-        if self.state != PbftState::PrePrepare {
+    /// Starts a new epoch's voter list, readmitting excluded validators
+    /// that are still in it.
+    pub fn set_validators(&mut self, validators: Vec<(PublicKey, BlsPublicKey)>) {
+        self.validators = validators;
+        self.excluded.clear();
+    }
+
+    pub fn validators(&self) -> &[(PublicKey, BlsPublicKey)] {
+        &self.validators
+    }
+
+    /// Refuses the validator's votes for the rest of the epoch and drops
+    /// those it cast in the current round.
+    pub fn remove_validator(&mut self, validator: &PublicKey) {
+        self.excluded.insert(validator.clone());
+        self.prepare_messages.remove(validator);
+        self.commit_messages.remove(validator);
+    }
+
+    /// Starts the round for a block above the highest committed one, in
+    /// `view`, and counts the votes for it that arrived early.
+    pub fn on_propose_block(&mut self, block: Block, view: u64) -> bool {
+        if self
+            .finalized_height()
+            .is_some_and(|height| block.header.height <= height)
+        {
             return false;
         }
 
+        self.reset();
+        let round = (block.header.height, view);
         self.current_block = Some(block);
+        self.view = view;
         self.state = PbftState::Prepare;
+
+        let pending = std::mem::take(&mut self.pending_votes);
+        let (ready, later): (Vec<Vote>, Vec<Vote>) = pending
+            .into_iter()
+            .filter(|vote| vote.round() >= round)
+            .partition(|vote| vote.round() == round);
+        self.pending_votes = later;
+        for vote in ready {
+            match vote.kind {
+                VoteKind::Prepare => self.on_prepare_message(vote),
+                VoteKind::Commit => self.on_commit_message(vote),
+            };
+        }
         true
     }
 
-    pub fn on_prepare_message(&mut self, validator: PublicKey, block_hash: Hash) -> bool {
-        if self.state != PbftState::Prepare {
+    /// Counts a prepare vote and returns whether it completed the prepare
+    /// certificate.
+    pub fn on_prepare_message(&mut self, vote: Vote) -> bool {
+        if !self.accept_vote(&vote, VoteKind::Prepare) || self.keep_for_later(&vote) {
             return false;
         }
 
-        if !self.validators.contains(&validator) {
+        if self.state != PbftState::Prepare || !self.record_vote(vote) {
             return false;
         }
 
-        self.prepare_messages.insert(validator, block_hash);
-
-        if self.prepare_messages.len() > 2 * self.validators.len() / 3 {
+        if let Some(certificate) = self.try_certify(VoteKind::Prepare) {
+            self.prepare_certificate = Some(certificate);
             self.state = PbftState::Commit;
+            // Commits that arrived ahead of the prepare quorum may already
+            // finalize the block.
+            self.try_commit();
             return true;
         }
 
         false
     }
 
-    pub fn on_commit_message(&mut self, validator: PublicKey, block_hash: Hash) -> bool {
-        if self.state != PbftState::Commit {
+    /// Counts a commit vote and returns whether it finalized the block.
+    /// Commits are collected during the prepare phase too, since peers that
+    /// reached the prepare quorum first send them before ours forms.
+    pub fn on_commit_message(&mut self, vote: Vote) -> bool {
+        if !self.accept_vote(&vote, VoteKind::Commit) || self.keep_for_later(&vote) {
             return false;
         }
 
-        if !self.validators.contains(&validator) {
+        if self.state == PbftState::PrePrepare || !self.record_vote(vote) {
             return false;
         }
 
-        self.commit_messages.insert(validator, block_hash);
+        self.state == PbftState::Commit && self.try_commit()
+    }
 
-        if self.commit_messages.len() > 2 * self.validators.len() / 3 {
-            // Block is finalized
-            self.reset();
-            return true;
+    /// Signs the votes `validator` still owes in the current round with its
+    /// vote key and counts them like votes from peers, returning them to be
+    /// gossiped. A prepare vote that completes the prepare certificate is
+    /// followed by the commit vote.
+    pub fn cast_votes(&mut self, validator: &PublicKey, bls_keypair: &BlsKeyPair) -> Vec<Vote> {
+        let mut votes = Vec::new();
+        while let Some(block) = &self.current_block {
+            let (kind, messages) = match self.state {
+                PbftState::Prepare => (VoteKind::Prepare, &self.prepare_messages),
+                PbftState::Commit => (VoteKind::Commit, &self.commit_messages),
+                PbftState::PrePrepare => break,
+            };
+            if messages.contains_key(validator) {
+                break;
+            }
+
+            let round = (block.header.height, self.view);
+            let block_hash = block.hash();
+            if self.last_voted.is_some_and(|(voted, hash)| {
+                voted > round || (voted == round && hash != block_hash)
+            }) {
+                break;
+            }
+
+            let vote = Vote::new(
+                kind,
+                round.0,
+                round.1,
+                block_hash,
+                validator.clone(),
+                bls_keypair,
+            );
+            if !self.accept_vote(&vote, kind) {
+                break;
+            }
+            self.last_voted = Some((round, block_hash));
+            votes.push(vote.clone());
+            match kind {
+                VoteKind::Prepare => self.on_prepare_message(vote),
+                VoteKind::Commit => self.on_commit_message(vote),
+            };
         }
+        votes
+    }
 
-        false
+    /// The view of the round in progress, if there is one.
+    pub fn view(&self) -> Option<u64> {
+        self.current_block.as_ref().map(|_| self.view)
+    }
+
+    /// The prepare certificate of the block currently being committed.
+    pub fn prepare_certificate(&self) -> Option<&QuorumCertificate> {
+        self.prepare_certificate.as_ref()
+    }
+
+    /// The finality certificate for `block_hash`, if it is the highest
+    /// committed block.
+    pub fn commit_certificate(&self, block_hash: &Hash) -> Option<&QuorumCertificate> {
+        self.commit_certificate
+            .as_ref()
+            .filter(|certificate| certificate.block_hash == *block_hash)
+    }
+
+    /// Height of the highest committed block.
+    pub fn finalized_height(&self) -> Option<u64> {
+        self.commit_certificate
+            .as_ref()
+            .map(|certificate| certificate.height)
+    }
+
+    /// Drains double-vote evidence collected since the last call.
+    pub fn take_evidence(&mut self) -> Vec<Evidence> {
+        std::mem::take(&mut self.evidence)
+    }
+
+    /// The vote key of a validator allowed to vote in this epoch.
+    fn bls_key(&self, validator: &PublicKey) -> Option<&BlsPublicKey> {
+        if self.excluded.contains(validator) {
+            return None;
+        }
+        self.validators
+            .iter()
+            .find(|(public_key, _)| public_key == validator)
+            .map(|(_, bls_key)| bls_key)
+    }
+
+    fn accept_vote(&self, vote: &Vote, kind: VoteKind) -> bool {
+        vote.kind == kind
+            && self
+                .bls_key(&vote.validator)
+                .is_some_and(|bls_key| vote.verify(bls_key))
+    }
+
+    /// Whether a vote is for a round after the current one, or after the
+    /// finalized height when no round is open. Those for the next height
+    /// are queued, a few per validator.
+    fn keep_for_later(&mut self, vote: &Vote) -> bool {
+        let (ahead, height) = match &self.current_block {
+            Some(block) => (
+                vote.round() > (block.header.height, self.view),
+                block.header.height,
+            ),
+            None => {
+                let height = self.finalized_height().unwrap_or(0);
+                (vote.height > height, height)
+            }
+        };
+        let queued = self
+            .pending_votes
+            .iter()
+            .filter(|pending| pending.validator == vote.validator)
+            .count();
+        if ahead && vote.height <= height + 1 && queued < MAX_PENDING_VOTES_PER_VALIDATOR {
+            self.pending_votes.push(vote.clone());
+        }
+        ahead
+    }
+
+    fn try_commit(&mut self) -> bool {
+        match self.try_certify(VoteKind::Commit) {
+            Some(certificate) => {
+                // Block is finalized
+                self.commit_certificate = Some(certificate);
+                self.reset();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stores the first vote of each validator for the current round and
+    /// returns whether it is for the proposed block. A second vote for a
+    /// different block is recorded as evidence instead.
+    fn record_vote(&mut self, vote: Vote) -> bool {
+        let (height, block_hash) = match &self.current_block {
+            Some(block) => (block.header.height, block.hash()),
+            None => return false,
+        };
+        if vote.round() != (height, self.view) {
+            return false;
+        }
+
+        let messages = match vote.kind {
+            VoteKind::Prepare => &mut self.prepare_messages,
+            VoteKind::Commit => &mut self.commit_messages,
+        };
+
+        if let Some(previous) = messages.get(&vote.validator) {
+            if previous.block_hash != vote.block_hash {
+                self.evidence.push(Evidence::DoubleVote {
+                    first: Box::new(previous.clone()),
+                    second: Box::new(vote),
+                });
+            }
+            return false;
+        }
+
+        let for_current = vote.block_hash == block_hash;
+        messages.insert(vote.validator.clone(), vote);
+        for_current
+    }
+
+    /// Aggregates the votes for the current block into a certificate once
+    /// more than two thirds of the validators have voted for it.
+    fn try_certify(&self, kind: VoteKind) -> Option<QuorumCertificate> {
+        let block = self.current_block.as_ref()?;
+        let block_hash = block.hash();
+        let messages = match kind {
+            VoteKind::Prepare => &self.prepare_messages,
+            VoteKind::Commit => &self.commit_messages,
+        };
+
+        let mut signers = vec![0u8; self.validators.len().div_ceil(8)];
+        let mut signatures = Vec::new();
+        for (index, (validator, _)) in self.validators.iter().enumerate() {
+            if let Some(vote) = messages.get(validator) {
+                if vote.block_hash == block_hash {
+                    signers[index / 8] |= 1 << (index % 8);
+                    signatures.push(vote.signature.clone());
+                }
+            }
+        }
+
+        if signatures.len() <= 2 * self.validators.len() / 3 {
+            return None;
+        }
+
+        Some(QuorumCertificate {
+            kind,
+            height: block.header.height,
+            view: self.view,
+            block_hash,
+            signers,
+            signature: aggregate_bls_signatures(&signatures)?,
+        })
     }
 
     fn reset(&mut self) {
@@ -82,5 +479,315 @@ impl PBFT {
         self.current_block = None;
         self.prepare_messages.clear();
         self.commit_messages.clear();
+        self.prepare_certificate = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KeyPair;
+
+    struct Voter {
+        public_key: PublicKey,
+        bls: BlsKeyPair,
+    }
+
+    fn voters(count: usize) -> Vec<Voter> {
+        (0..count)
+            .map(|_| Voter {
+                public_key: KeyPair::generate().public_key(),
+                bls: BlsKeyPair::generate(),
+            })
+            .collect()
+    }
+
+    fn epoch_list(voters: &[Voter]) -> Vec<(PublicKey, BlsPublicKey)> {
+        voters
+            .iter()
+            .map(|voter| (voter.public_key.clone(), voter.bls.public_key()))
+            .collect()
+    }
+
+    fn pbft_for(voters: &[Voter]) -> PBFT {
+        let mut pbft = PBFT::new();
+        pbft.set_validators(epoch_list(voters));
+        pbft
+    }
+
+    fn block(height: u64, producer: &Voter) -> Block {
+        Block::new(
+            Hash::default(),
+            Vec::new(),
+            height,
+            0,
+            height * 3,
+            producer.public_key.clone(),
+            0,
+        )
+    }
+
+    /// Blocks are proposed in a view equal to their height unless a test
+    /// says otherwise.
+    fn view_of(block: &Block) -> u64 {
+        block.header.height
+    }
+
+    fn vote(voter: &Voter, kind: VoteKind, block: &Block) -> Vote {
+        Vote::new(
+            kind,
+            block.header.height,
+            view_of(block),
+            block.hash(),
+            voter.public_key.clone(),
+            &voter.bls,
+        )
+    }
+
+    /// Proposes `block` and has every voter in `signers` prepare and commit
+    /// it, returning whether it was finalized.
+    fn run_round(pbft: &mut PBFT, block: &Block, signers: &[&Voter]) -> bool {
+        assert!(pbft.on_propose_block(block.clone(), view_of(block)));
+        for voter in signers {
+            pbft.on_prepare_message(vote(voter, VoteKind::Prepare, block));
+        }
+        signers
+            .iter()
+            .any(|voter| pbft.on_commit_message(vote(voter, VoteKind::Commit, block)))
+    }
+
+    #[test]
+    fn a_quorum_of_votes_finalizes_the_block_with_a_verifiable_certificate() {
+        let voters = voters(4);
+        let mut pbft = pbft_for(&voters);
+        let block = block(1, &voters[0]);
+
+        // Two of four is not more than two thirds.
+        assert!(!run_round(&mut pbft, &block, &[&voters[0], &voters[1]]));
+        assert!(pbft.commit_certificate(&block.hash()).is_none());
+
+        let mut pbft = pbft_for(&voters);
+        assert!(run_round(
+            &mut pbft,
+            &block,
+            &[&voters[0], &voters[1], &voters[2]]
+        ));
+        let certificate = pbft.commit_certificate(&block.hash()).unwrap();
+        assert_eq!(certificate.signer_count(), 3);
+        assert!(!certificate.has_signed(3));
+        assert!(certificate.verify(&epoch_list(&voters)));
+        assert_eq!(pbft.finalized_height(), Some(1));
+    }
+
+    #[test]
+    fn a_certificate_only_verifies_against_its_own_voters() {
+        let voters = voters(4);
+        let mut pbft = pbft_for(&voters);
+        let block = block(1, &voters[0]);
+        assert!(run_round(
+            &mut pbft,
+            &block,
+            &[&voters[0], &voters[1], &voters[2]]
+        ));
+        let certificate = pbft.commit_certificate(&block.hash()).unwrap().clone();
+
+        let mut reordered = epoch_list(&voters);
+        reordered.swap(0, 3);
+        assert!(!certificate.verify(&reordered));
+
+        let mut fewer_signers = certificate.clone();
+        fewer_signers.signers[0] &= !1;
+        assert!(!fewer_signers.verify(&epoch_list(&voters)));
+
+        let mut other_block = certificate;
+        other_block.block_hash = Hash::default();
+        assert!(!other_block.verify(&epoch_list(&voters)));
+    }
+
+    #[test]
+    fn removing_a_validator_keeps_every_bitmap_position() {
+        let voters = voters(4);
+        let mut pbft = pbft_for(&voters);
+        pbft.remove_validator(&voters[1].public_key);
+
+        // The removed validator's vote is refused, leaving two of four.
+        let refused = block(1, &voters[0]);
+        assert!(!run_round(
+            &mut pbft,
+            &refused,
+            &[&voters[0], &voters[1], &voters[2]]
+        ));
+
+        // The quorum is still counted over all four.
+        let block = block(2, &voters[2]);
+        assert!(run_round(
+            &mut pbft,
+            &block,
+            &[&voters[0], &voters[2], &voters[3]]
+        ));
+        let certificate = pbft.commit_certificate(&block.hash()).unwrap();
+        assert!(certificate.has_signed(0));
+        assert!(!certificate.has_signed(1));
+        assert!(certificate.has_signed(2) && certificate.has_signed(3));
+        assert!(certificate.verify(&epoch_list(&voters)));
+    }
+
+    #[test]
+    fn a_new_block_replaces_a_round_that_did_not_commit() {
+        let voters = voters(4);
+        let mut pbft = pbft_for(&voters);
+        let stalled = block(1, &voters[0]);
+        assert!(!run_round(&mut pbft, &stalled, &[&voters[0]]));
+
+        let next = block(2, &voters[1]);
+        assert!(run_round(
+            &mut pbft,
+            &next,
+            &[&voters[0], &voters[1], &voters[2]]
+        ));
+        assert_eq!(pbft.finalized_height(), Some(2));
+    }
+
+    #[test]
+    fn only_the_highest_commit_certificate_is_kept() {
+        let voters = voters(4);
+        let signers = [&voters[0], &voters[1], &voters[2]];
+        let mut pbft = pbft_for(&voters);
+        let first = block(1, &voters[0]);
+        let second = block(2, &voters[1]);
+        assert!(run_round(&mut pbft, &first, &signers));
+        assert!(run_round(&mut pbft, &second, &signers));
+
+        assert!(pbft.commit_certificate(&first.hash()).is_none());
+        assert!(pbft.commit_certificate(&second.hash()).is_some());
+        assert!(!pbft.on_propose_block(first.clone(), view_of(&first)));
+    }
+
+    #[test]
+    fn a_second_vote_for_another_block_is_evidence() {
+        let voters = voters(4);
+        let mut pbft = pbft_for(&voters);
+        let block = block(1, &voters[0]);
+        let mut conflicting = block.clone();
+        conflicting.header.timestamp += 1;
+
+        assert!(pbft.on_propose_block(block.clone(), view_of(&block)));
+        pbft.on_prepare_message(vote(&voters[2], VoteKind::Prepare, &block));
+        assert!(pbft.take_evidence().is_empty());
+        pbft.on_prepare_message(vote(&voters[2], VoteKind::Prepare, &conflicting));
+
+        let evidence = pbft.take_evidence();
+        assert_eq!(evidence.len(), 1);
+        assert_eq!(evidence[0].offender(), &voters[2].public_key);
+    }
+
+    #[test]
+    fn votes_for_another_view_at_the_same_height_are_not_evidence() {
+        let voters = voters(4);
+        let mut pbft = pbft_for(&voters);
+        let first = block(1, &voters[0]);
+        let mut replacement = first.clone();
+        replacement.header.timestamp += 3;
+
+        assert!(pbft.on_propose_block(first.clone(), 1));
+        pbft.on_prepare_message(vote(&voters[2], VoteKind::Prepare, &first));
+        assert!(pbft.on_propose_block(replacement.clone(), 2));
+        for voter in &voters[..3] {
+            pbft.on_prepare_message(Vote::new(
+                VoteKind::Prepare,
+                1,
+                2,
+                replacement.hash(),
+                voter.public_key.clone(),
+                &voter.bls,
+            ));
+        }
+
+        assert!(pbft.take_evidence().is_empty());
+        let certificate = pbft.prepare_certificate().unwrap();
+        assert_eq!((certificate.height, certificate.view), (1, 2));
+        assert!(certificate.verify(&epoch_list(&voters)));
+    }
+
+    #[test]
+    fn votes_that_arrive_before_their_block_are_counted_once_it_does() {
+        let voters = voters(4);
+        let mut pbft = pbft_for(&voters);
+        let block = block(1, &voters[0]);
+        for voter in &voters[..3] {
+            assert!(!pbft.on_commit_message(vote(voter, VoteKind::Commit, &block)));
+            assert!(!pbft.on_prepare_message(vote(voter, VoteKind::Prepare, &block)));
+        }
+        // Votes too far ahead are not kept.
+        let later = self::block(3, &voters[1]);
+        pbft.on_prepare_message(vote(&voters[0], VoteKind::Prepare, &later));
+
+        assert!(pbft.on_propose_block(block.clone(), view_of(&block)));
+        assert!(pbft.commit_certificate(&block.hash()).is_some());
+        assert!(pbft.pending_votes.is_empty());
+    }
+
+    #[test]
+    fn validators_exchanging_their_votes_finalize_the_block() {
+        let voters = voters(4);
+        let block = block(1, &voters[0]);
+        let mut nodes: Vec<PBFT> = voters[..3].iter().map(|_| pbft_for(&voters)).collect();
+        for node in &mut nodes {
+            node.on_propose_block(block.clone(), view_of(&block));
+        }
+
+        // Each node votes in turn and hears every vote cast so far, as if
+        // the votes were gossiped.
+        let mut gossiped: Vec<Vote> = Vec::new();
+        for _ in 0..3 {
+            for (index, node) in nodes.iter_mut().enumerate() {
+                for vote in &gossiped {
+                    match vote.kind {
+                        VoteKind::Prepare => node.on_prepare_message(vote.clone()),
+                        VoteKind::Commit => node.on_commit_message(vote.clone()),
+                    };
+                }
+                let voter = &voters[index];
+                gossiped.extend(node.cast_votes(&voter.public_key, &voter.bls));
+            }
+        }
+
+        for node in &nodes {
+            let certificate = node.commit_certificate(&block.hash()).unwrap();
+            assert!(certificate.verify(&epoch_list(&voters)));
+        }
+        // Nobody voted twice.
+        assert_eq!(gossiped.len(), 6);
+    }
+
+    #[test]
+    fn a_replayed_round_is_not_voted_on_for_another_block() {
+        let voters = voters(4);
+        let mut pbft = pbft_for(&voters);
+        let block = block(1, &voters[0]);
+        let mut sibling = block.clone();
+        sibling.header.timestamp += 1;
+
+        pbft.on_propose_block(block.clone(), 1);
+        assert_eq!(
+            pbft.cast_votes(&voters[1].public_key, &voters[1].bls).len(),
+            1
+        );
+        pbft.on_propose_block(block.clone(), 1);
+        assert_eq!(
+            pbft.cast_votes(&voters[1].public_key, &voters[1].bls).len(),
+            1
+        );
+        pbft.on_propose_block(sibling, 1);
+        assert!(pbft
+            .cast_votes(&voters[1].public_key, &voters[1].bls)
+            .is_empty());
+
+        // Someone without a vote key in the epoch casts nothing.
+        let outsider = self::voters(1).remove(0);
+        pbft.on_propose_block(block, 2);
+        assert!(pbft
+            .cast_votes(&outsider.public_key, &outsider.bls)
+            .is_empty());
     }
 }
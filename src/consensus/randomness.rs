@@ -0,0 +1,24 @@
+use crate::crypto::{Hash, KeyPair};
+
+/// The secret a validator reveals in the `index`-th block it produces.
+/// Derived from a signature so it needs no extra storage, and only becomes
+/// public when revealed. ed25519 signing is deterministic, so the producer
+/// can always recompute a reveal it committed to earlier.
+pub fn randao_reveal(keypair: &KeyPair, index: u64) -> Hash {
+    let mut message = b"flux-randao".to_vec();
+    message.extend_from_slice(&index.to_le_bytes());
+    Hash::from(blake3::hash(&keypair.sign(&message)).as_bytes())
+}
+
+/// The commitment published one block ahead of its reveal.
+pub fn randao_commitment(reveal: &Hash) -> Hash {
+    Hash::from(blake3::hash(reveal.as_bytes()).as_bytes())
+}
+
+/// Folds a reveal into the running randomness beacon.
+pub fn randao_mix(previous: &Hash, reveal: &Hash) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(previous.as_bytes());
+    hasher.update(reveal.as_bytes());
+    Hash::from(hasher.finalize().as_bytes())
+}
@@ -0,0 +1,253 @@
+use blst::min_pk::{AggregateSignature, PublicKey as BlstPublicKey, SecretKey, Signature};
+use blst::BLST_ERROR;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+/// Domain separation tags from the IETF BLS signature draft, proof of
+/// possession scheme with public keys in G1 and signatures in G2.
+const SIGNATURE_DST: &[u8] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+const POP_DST: &[u8] = b"BLS_POP_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+
+/// Compressed 48-byte BLS12-381 public key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BlsPublicKey(Vec<u8>);
+
+/// Compressed 96-byte BLS12-381 signature, individual or aggregated.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BlsSignature(Vec<u8>);
+
+impl BlsPublicKey {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn point(&self) -> Option<BlstPublicKey> {
+        BlstPublicKey::key_validate(&self.0).ok()
+    }
+}
+
+impl BlsSignature {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn point(&self) -> Option<Signature> {
+        Signature::sig_validate(&self.0, true).ok()
+    }
+}
+
+pub struct BlsKeyPair {
+    secret: SecretKey,
+    public: BlsPublicKey,
+}
+
+impl BlsKeyPair {
+    pub fn generate() -> Self {
+        let mut ikm = [0u8; 32];
+        OsRng.fill_bytes(&mut ikm);
+        Self::from_seed(&ikm).expect("32 bytes of key material is enough")
+    }
+
+    /// Derives a key pair from at least 32 bytes of secret key material.
+    pub fn from_seed(ikm: &[u8]) -> Result<Self, String> {
+        let secret =
+            SecretKey::key_gen(ikm, &[]).map_err(|e| format!("invalid key material: {:?}", e))?;
+        let public = BlsPublicKey(secret.sk_to_pk().compress().to_vec());
+        Ok(BlsKeyPair { secret, public })
+    }
+
+    pub fn public_key(&self) -> BlsPublicKey {
+        self.public.clone()
+    }
+
+    pub fn sign(&self, message: &[u8]) -> BlsSignature {
+        BlsSignature(
+            self.secret
+                .sign(message, SIGNATURE_DST, &[])
+                .compress()
+                .to_vec(),
+        )
+    }
+
+    /// Signs the public key itself. Validators publish this when registering
+    /// so that aggregate verification is safe against rogue-key attacks.
+    pub fn proof_of_possession(&self) -> BlsSignature {
+        BlsSignature(
+            self.secret
+                .sign(self.public.as_bytes(), POP_DST, &[])
+                .compress()
+                .to_vec(),
+        )
+    }
+}
+
+pub fn verify_bls_signature(
+    public_key: &BlsPublicKey,
+    message: &[u8],
+    signature: &BlsSignature,
+) -> bool {
+    match (public_key.point(), signature.point()) {
+        (Some(pk), Some(sig)) => {
+            sig.verify(false, message, SIGNATURE_DST, &[], &pk, false) == BLST_ERROR::BLST_SUCCESS
+        }
+        _ => false,
+    }
+}
+
+pub fn verify_proof_of_possession(public_key: &BlsPublicKey, proof: &BlsSignature) -> bool {
+    match (public_key.point(), proof.point()) {
+        (Some(pk), Some(sig)) => {
+            sig.verify(false, public_key.as_bytes(), POP_DST, &[], &pk, false)
+                == BLST_ERROR::BLST_SUCCESS
+        }
+        _ => false,
+    }
+}
+
+/// Combines signatures over the same message into one.
+pub fn aggregate_bls_signatures(signatures: &[BlsSignature]) -> Option<BlsSignature> {
+    let points = signatures
+        .iter()
+        .map(BlsSignature::point)
+        .collect::<Option<Vec<_>>>()?;
+    let refs: Vec<&Signature> = points.iter().collect();
+    let aggregate = AggregateSignature::aggregate(&refs, false).ok()?;
+    Some(BlsSignature(aggregate.to_signature().compress().to_vec()))
+}
+
+/// Verifies an aggregate signature by `public_keys` over a single message.
+/// The keys must have had their proofs of possession checked.
+pub fn verify_aggregate_bls_signature(
+    public_keys: &[BlsPublicKey],
+    message: &[u8],
+    signature: &BlsSignature,
+) -> bool {
+    let points = match public_keys
+        .iter()
+        .map(BlsPublicKey::point)
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(points) => points,
+        None => return false,
+    };
+    let refs: Vec<&BlstPublicKey> = points.iter().collect();
+    match signature.point() {
+        Some(sig) => {
+            sig.fast_aggregate_verify(false, message, SIGNATURE_DST, &refs)
+                == BLST_ERROR::BLST_SUCCESS
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] = b"commit 42";
+
+    #[test]
+    fn a_signature_only_verifies_for_its_key_and_message() {
+        let keypair = BlsKeyPair::generate();
+        let signature = keypair.sign(MESSAGE);
+
+        assert!(verify_bls_signature(
+            &keypair.public_key(),
+            MESSAGE,
+            &signature
+        ));
+        assert!(!verify_bls_signature(
+            &keypair.public_key(),
+            b"commit 43",
+            &signature
+        ));
+        assert!(!verify_bls_signature(
+            &BlsKeyPair::generate().public_key(),
+            MESSAGE,
+            &signature
+        ));
+        assert!(!verify_bls_signature(
+            &keypair.public_key(),
+            MESSAGE,
+            &BlsSignature(vec![0; 96])
+        ));
+    }
+
+    #[test]
+    fn keys_derive_deterministically_from_enough_seed() {
+        let seed = [7u8; 32];
+        assert_eq!(
+            BlsKeyPair::from_seed(&seed).unwrap().public_key(),
+            BlsKeyPair::from_seed(&seed).unwrap().public_key()
+        );
+        assert!(BlsKeyPair::from_seed(&seed[..16]).is_err());
+    }
+
+    #[test]
+    fn a_proof_of_possession_is_not_an_ordinary_signature() {
+        let keypair = BlsKeyPair::generate();
+        let public_key = keypair.public_key();
+
+        assert!(verify_proof_of_possession(
+            &public_key,
+            &keypair.proof_of_possession()
+        ));
+        assert!(!verify_proof_of_possession(
+            &BlsKeyPair::generate().public_key(),
+            &keypair.proof_of_possession()
+        ));
+        // Signing the key bytes under the vote domain does not prove
+        // possession.
+        assert!(!verify_proof_of_possession(
+            &public_key,
+            &keypair.sign(public_key.as_bytes())
+        ));
+    }
+
+    #[test]
+    fn an_aggregate_verifies_against_exactly_its_signers() {
+        let keypairs: Vec<BlsKeyPair> = (0..4).map(|_| BlsKeyPair::generate()).collect();
+        let public_keys: Vec<BlsPublicKey> = keypairs.iter().map(BlsKeyPair::public_key).collect();
+        let signatures: Vec<BlsSignature> = keypairs
+            .iter()
+            .map(|keypair| keypair.sign(MESSAGE))
+            .collect();
+        let aggregate = aggregate_bls_signatures(&signatures).unwrap();
+
+        assert!(verify_aggregate_bls_signature(
+            &public_keys,
+            MESSAGE,
+            &aggregate
+        ));
+        assert!(!verify_aggregate_bls_signature(
+            &public_keys[..3],
+            MESSAGE,
+            &aggregate
+        ));
+        assert!(!verify_aggregate_bls_signature(
+            &public_keys,
+            b"commit 43",
+            &aggregate
+        ));
+
+        let partial = aggregate_bls_signatures(&signatures[..3]).unwrap();
+        assert!(verify_aggregate_bls_signature(
+            &public_keys[..3],
+            MESSAGE,
+            &partial
+        ));
+        assert!(!verify_aggregate_bls_signature(
+            &public_keys,
+            MESSAGE,
+            &partial
+        ));
+    }
+
+    #[test]
+    fn nothing_and_malformed_signatures_do_not_aggregate() {
+        assert!(aggregate_bls_signatures(&[]).is_none());
+        let valid = BlsKeyPair::generate().sign(MESSAGE);
+        assert!(aggregate_bls_signatures(&[valid, BlsSignature(vec![1; 96])]).is_none());
+    }
+}
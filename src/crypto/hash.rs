@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Hash([u8; 32]);
 
 impl Hash {
@@ -30,12 +31,6 @@ impl From<&[u8]> for Hash {
     }
 }
 
-impl Default for Hash {
-    fn default() -> Self {
-        Hash([0u8; 32])
-    }
-}
-
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for byte in self.0.iter() {
@@ -45,6 +40,90 @@ impl fmt::Display for Hash {
     }
 }
 
+/// Parses the 64 hex digits `Display` writes.
+impl FromStr for Hash {
+    type Err = String;
+
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(format!("Expected 64 hex digits, got {:?}", hex));
+        }
+        let mut bytes = [0u8; 32];
+        for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).expect("Checked to be ASCII");
+            *byte = u8::from_str_radix(digits, 16)
+                .map_err(|_| format!("Invalid hex digits {:?}", digits))?;
+        }
+        Ok(Hash(bytes))
+    }
+}
+
 pub trait Hashable {
     fn hash(&self) -> Hash;
 }
+
+/// Binary Merkle root of `hashes`. The last hash of an odd level is carried
+/// up unpaired rather than duplicated, so a list that repeats its last entry
+/// does not share a root with the list without it. An empty list has the
+/// default hash as its root.
+pub fn merkle_root(hashes: impl Iterator<Item = Hash>) -> Hash {
+    let mut hashes: Vec<Hash> = hashes.collect();
+    if hashes.is_empty() {
+        return Hash::default();
+    }
+
+    while hashes.len() > 1 {
+        let mut new_hashes = Vec::new();
+        for chunk in hashes.chunks(2) {
+            match chunk {
+                [left, right] => {
+                    let mut hasher = blake3::Hasher::new();
+                    hasher.update(left.as_bytes());
+                    hasher.update(right.as_bytes());
+                    new_hashes.push(Hash::from(hasher.finalize().as_bytes()));
+                }
+                [odd] => new_hashes.push(*odd),
+                _ => unreachable!(),
+            }
+        }
+
+        hashes = new_hashes;
+    }
+
+    hashes[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(byte: u8) -> Hash {
+        Hash::from([byte; 32])
+    }
+
+    #[test]
+    fn repeating_the_last_hash_changes_the_root() {
+        let odd = vec![leaf(1), leaf(2), leaf(3)];
+        let mut padded = odd.clone();
+        padded.push(leaf(3));
+
+        assert_ne!(
+            merkle_root(odd.into_iter()),
+            merkle_root(padded.into_iter())
+        );
+    }
+
+    #[test]
+    fn a_single_hash_is_its_own_root() {
+        assert_eq!(merkle_root(std::iter::once(leaf(7))), leaf(7));
+        assert_eq!(merkle_root(std::iter::empty()), Hash::default());
+    }
+
+    #[test]
+    fn a_hash_parses_back_from_its_display() {
+        let hash = leaf(200);
+        assert_eq!(hash.to_string().parse::<Hash>(), Ok(hash));
+        assert!(hash.to_string()[1..].parse::<Hash>().is_err());
+        assert!("zz".repeat(32).parse::<Hash>().is_err());
+    }
+}
@@ -2,6 +2,7 @@ use ed25519_dalek::{Keypair, PublicKey as EdPublicKey, SecretKey, Signature, Sig
 use rand::rngs::OsRng;
 use serde::de::{Error as DeError, Visitor};
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 
@@ -25,6 +26,19 @@ impl Hash for PublicKey {
     }
 }
 
+/// Orders keys by their bytes, so maps keyed by them have a canonical order.
+impl Ord for PublicKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl PartialOrd for PublicKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Serialize for PublicKey {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -1,6 +1,11 @@
+mod bls;
 mod hash;
 mod keys;
 
 // Re-export the main types and functions for easier use
-pub use hash::{Hash, Hashable};
+pub use bls::{
+    aggregate_bls_signatures, verify_aggregate_bls_signature, verify_bls_signature,
+    verify_proof_of_possession, BlsKeyPair, BlsPublicKey, BlsSignature,
+};
+pub use hash::{merkle_root, Hash, Hashable};
 pub use keys::{verify_signature, KeyPair, PublicKey};
@@ -1,72 +1,104 @@
-use flux::blockchain::Blockchain;
-use flux::consensus::ConsensusManager;
-use flux::network::P2PNetwork;
-use flux::state::WorldState;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use std::error::Error;
-use std::collections::HashSet;
+use flux::blockchain::{Blockchain, GenesisConfig};
+use flux::consensus::{unix_now, ConsensusEngine, ConsensusManager, InstantSeal, SealMode};
+use flux::crypto::{Hashable, KeyPair};
+use flux::network::{NetworkConfig, NetworkEvent, P2PNetwork, SyncMode};
 use log::{error, info};
-use flux::crypto::{PublicKey, Hashable};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{broadcast, RwLock};
+
+const CHAIN_ID: &str = "flux";
+/// Development chains never pair with nodes on the real network.
+const DEV_CHAIN_ID: &str = "flux-dev";
+
+/// Balance credited to the authority key of a `--dev` node at genesis.
+const DEV_BALANCE: u64 = 1_000_000_000;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // Initialize logging
     env_logger::init();
 
-    // Create WorldState
-    let world_state = Arc::new(RwLock::new(WorldState::new()));
-
-    // Create ConsensusManager
-    let validators = HashSet::new(); // Initialize with actual validators
-    let consensus_manager = ConsensusManager::new(validators);
+    // Pick the consensus engine: `--dev` runs a single-authority chain that
+    // seals every transaction, `--dev-interval=<secs>` seals on a timer.
+    let keypair = KeyPair::generate();
+    let (consensus, genesis) = match dev_seal_mode() {
+        Some(mode) => {
+            info!(
+                "Starting development node, authority {:?}",
+                keypair.public_key()
+            );
+            let authority = keypair.public_key();
+            let engine: Box<dyn ConsensusEngine> =
+                Box::new(InstantSeal::new(authority.clone(), mode));
+            let genesis = GenesisConfig::new(
+                DEV_CHAIN_ID,
+                unix_now(),
+                vec![(authority, DEV_BALANCE)],
+                vec![],
+            );
+            (engine, genesis)
+        }
+        None => {
+            let engine: Box<dyn ConsensusEngine> = Box::new(ConsensusManager::new());
+            let genesis = GenesisConfig {
+                chain_id: CHAIN_ID.to_string(),
+                ..GenesisConfig::default()
+            };
+            (engine, genesis)
+        }
+    };
 
     // Create Blockchain without P2PNetwork
-    let blockchain = Arc::new(RwLock::new(Blockchain::new(consensus_manager)));
-
-    // Create P2PNetwork with a reference to the blockchain
-    let p2p_network = Arc::new(RwLock::new(P2PNetwork::new(blockchain.clone()).await?));
+    let mut chain = Blockchain::new(consensus, genesis);
+    chain.set_signer(keypair);
+    let blockchain = Arc::new(RwLock::new(chain));
 
-    // Update the Blockchain with the P2PNetwork
-    {
-        let mut blockchain_write = blockchain.write().await;
-        blockchain_write.set_network(Arc::clone(&p2p_network)).await;
-    }
+    // Create P2PNetwork with a reference to the blockchain, and give the
+    // blockchain a handle for publishing what it produces
+    let network = P2PNetwork::new(blockchain.clone(), network_config()?).await?;
+    blockchain.write().await.set_network(network.handle());
 
-    // Spawn the P2P network task
-    let blockchain_for_network = blockchain.clone();
+    // Log what arrives from peers
+    let mut events = network.subscribe();
     tokio::spawn(async move {
         loop {
-            if let Some(network) = blockchain_for_network.read().await.get_network().await {
-                let mut network = network.write().await;
-                if let Err(e) = network.run().await {
-                    error!("Network error: {}", e);
-                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            match events.recv().await {
+                Ok(event) => log_network_event(&event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    info!("Skipped {} network events", missed);
                 }
-            } else {
-                error!("Network not initialized");
-                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                Err(broadcast::error::RecvError::Closed) => break,
             }
         }
     });
 
+    // The network task owns the swarm; nothing else ever locks it
+    tokio::spawn(async move {
+        if let Err(e) = network.run().await {
+            error!("Network error: {}", e);
+        }
+    });
+
     // Main loop
     loop {
-        // Mine a new block every 10 seconds
-        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
-        
-        let mut blockchain = blockchain.write().await;
-        match blockchain.mine_block().await {
-            Ok(block) => info!("Mined new block: {:?}", block.hash()),
-            Err(e) => error!("Failed to mine block: {}", e),
+        // Try to produce a block at the interval the consensus engine asks
+        // for. Engines that seal on transactions have no interval.
+        let interval = blockchain.read().await.block_interval().await;
+        tokio::time::sleep(interval.unwrap_or(tokio::time::Duration::from_secs(1))).await;
+
+        let blockchain = blockchain.read().await;
+        if interval.is_some() {
+            match blockchain.mine_block().await {
+                Ok(block) => info!("Mined new block: {:?}", block.hash()),
+                Err(e) => error!("Failed to mine block: {}", e),
+            }
         }
 
         // Example: Process pending transactions
         process_pending_transactions(&blockchain).await;
 
-        // Example: Sync with other nodes
-        sync_with_network(&blockchain).await;
-
         // Check for shutdown signal
         if should_shutdown() {
             info!("Shutting down node");
@@ -77,18 +109,84 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-async fn process_pending_transactions(blockchain: &Blockchain) {
+async fn process_pending_transactions(_blockchain: &Blockchain) {
     // Implementation to process pending transactions
     // This could involve selecting transactions from a mempool and including them in the next block
 }
 
-async fn sync_with_network(blockchain: &Blockchain) {
-    // Implementation to sync the blockchain with other nodes in the network
-    // This could involve requesting missing blocks or broadcasting new blocks
+fn log_network_event(event: &NetworkEvent) {
+    match event {
+        NetworkEvent::BlockImported { peer, block } => info!(
+            "Imported block {} at height {} from {}",
+            block.hash(),
+            block.header.height,
+            peer
+        ),
+        NetworkEvent::TransactionReceived { peer, transaction } => {
+            info!("Received transaction {} from {}", transaction.hash(), peer)
+        }
+        NetworkEvent::EvidenceReceived { peer, evidence } => info!(
+            "Received evidence against {:?} from {}",
+            evidence.offender(),
+            peer
+        ),
+        NetworkEvent::Synced { height } => info!("Synced to height {}", height),
+        NetworkEvent::SnapshotRestored { height } => {
+            info!("Restored state from a snapshot at height {}", height)
+        }
+        NetworkEvent::PeerConnected { peer, address } => {
+            info!("Connected to {} at {}", peer, address)
+        }
+        NetworkEvent::PeerDisconnected { peer } => info!("Disconnected from {}", peer),
+    }
+}
+
+fn dev_seal_mode() -> Option<SealMode> {
+    std::env::args().find_map(|arg| {
+        if arg == "--dev" {
+            Some(SealMode::Instant)
+        } else {
+            arg.strip_prefix("--dev-interval=")
+                .and_then(|secs| secs.parse().ok())
+                .map(|secs| SealMode::Interval(tokio::time::Duration::from_secs(secs)))
+        }
+    })
+}
+
+/// Reads `--listen=<multiaddr>` and `--bootstrap=<multiaddr>`, both of which
+/// may be repeated, `--data-dir=<path>` and `--sync=<full|snapshot:<hash>>`,
+/// where the hash is the snapshot sync checkpoint.
+fn network_config() -> Result<NetworkConfig, Box<dyn Error>> {
+    let mut config = NetworkConfig::default();
+    let mut listen_addresses = Vec::new();
+    for arg in std::env::args() {
+        if let Some(address) = arg.strip_prefix("--listen=") {
+            listen_addresses.push(address.parse()?);
+        } else if let Some(address) = arg.strip_prefix("--bootstrap=") {
+            config.bootstrap_peers.push(address.parse()?);
+        } else if let Some(dir) = arg.strip_prefix("--data-dir=") {
+            config.data_dir = PathBuf::from(dir);
+        } else if let Some(mode) = arg.strip_prefix("--sync=") {
+            config.sync_mode = match mode.split_once(':') {
+                None if mode == "full" => SyncMode::Full,
+                None if mode == "snapshot" => {
+                    return Err("Snapshot sync needs a checkpoint: --sync=snapshot:<hash>".into())
+                }
+                Some(("snapshot", checkpoint)) => SyncMode::Snapshot {
+                    checkpoint: checkpoint.parse()?,
+                },
+                _ => return Err(format!("Unknown sync mode {}", mode).into()),
+            };
+        }
+    }
+    if !listen_addresses.is_empty() {
+        config.listen_addresses = listen_addresses;
+    }
+    Ok(config)
 }
 
 fn should_shutdown() -> bool {
     // Implementation to check if the node should shut down
     // This could involve checking for a specific file, receiving a signal, etc.
     false // Placeholder
-}
\ No newline at end of file
+}
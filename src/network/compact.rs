@@ -0,0 +1,137 @@
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::blockchain::transaction::Transaction;
+use crate::consensus::evidence::Evidence;
+use crate::crypto::{Hash, Hashable};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A transaction's hash shortened to 8 bytes. It is salted with the block
+/// hash, so transactions crafted to collide in one block do not collide in
+/// the next.
+pub type ShortId = u64;
+
+pub fn short_id(block_hash: &Hash, transaction_hash: &Hash) -> ShortId {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(block_hash.as_bytes());
+    hasher.update(transaction_hash.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hasher.finalize().as_bytes()[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// A block announced by its header and short transaction ids. Peers rebuild
+/// the body from their mempools and only fetch what they are missing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub signature: Vec<u8>,
+    /// Sent in full: evidence is rare and seldom already known.
+    pub evidence: Vec<Evidence>,
+    pub short_ids: Vec<ShortId>,
+}
+
+impl CompactBlock {
+    pub fn from_block(block: &Block) -> Self {
+        let block_hash = block.hash();
+        CompactBlock {
+            header: block.header.clone(),
+            signature: block.signature.clone(),
+            evidence: block.evidence.clone(),
+            short_ids: block
+                .transactions
+                .iter()
+                .map(|transaction| short_id(&block_hash, &transaction.hash()))
+                .collect(),
+        }
+    }
+
+    pub fn verify_signature(&self) -> bool {
+        self.header.verify_signature(&self.signature)
+    }
+
+    /// Fills in whatever transactions `candidates` has. A short id matching
+    /// several candidates is left missing, so the sender resolves it.
+    pub fn reconstruct(self, candidates: impl IntoIterator<Item = Transaction>) -> PartialBlock {
+        let block_hash = self.hash();
+        let mut by_short_id: HashMap<ShortId, Option<Transaction>> = HashMap::new();
+        for transaction in candidates {
+            by_short_id
+                .entry(short_id(&block_hash, &transaction.hash()))
+                .and_modify(|slot| *slot = None)
+                .or_insert(Some(transaction));
+        }
+
+        let transactions = self
+            .short_ids
+            .iter()
+            .map(|id| by_short_id.get(id).cloned().flatten())
+            .collect();
+        PartialBlock {
+            compact: self,
+            transactions,
+        }
+    }
+}
+
+impl Hashable for CompactBlock {
+    fn hash(&self) -> Hash {
+        self.header.hash()
+    }
+}
+
+/// A compact block part way through reconstruction.
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    compact: CompactBlock,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    pub fn hash(&self) -> Hash {
+        self.compact.hash()
+    }
+
+    /// Positions of the transactions still needed, in block order.
+    pub fn missing(&self) -> Vec<u32> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, transaction)| transaction.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    /// Fills the gaps with `transactions`, given in the order of `missing`.
+    /// Each must match the short id announced for its position.
+    pub fn fill(&mut self, transactions: Vec<Transaction>) -> Result<(), String> {
+        let missing = self.missing();
+        if transactions.len() != missing.len() {
+            return Err(format!(
+                "Expected {} transactions, got {}",
+                missing.len(),
+                transactions.len()
+            ));
+        }
+
+        let block_hash = self.hash();
+        for (index, transaction) in missing.into_iter().zip(transactions) {
+            let index = index as usize;
+            if short_id(&block_hash, &transaction.hash()) != self.compact.short_ids[index] {
+                return Err(format!("Transaction {} does not match its short id", index));
+            }
+            self.transactions[index] = Some(transaction);
+        }
+        Ok(())
+    }
+
+    /// The full block, once nothing is missing.
+    pub fn into_block(self) -> Option<Block> {
+        let transactions = self.transactions.into_iter().collect::<Option<Vec<_>>>()?;
+        Some(Block {
+            header: self.compact.header,
+            transactions,
+            evidence: self.compact.evidence,
+            signature: self.compact.signature,
+        })
+    }
+}
@@ -0,0 +1,128 @@
+use crate::crypto::Hash;
+use libp2p::identity::{self, ed25519};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use log::info;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A fixed port, so peers that learned our address can find us again after a
+/// restart.
+pub const DEFAULT_LISTEN_ADDRESS: &str = "/ip4/0.0.0.0/tcp/9000";
+/// Where the node's libp2p identity is kept inside its data directory.
+const IDENTITY_FILE: &str = "node_key";
+
+/// What carries connections between nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Tcp,
+    /// In-process channels addressed as `/memory/<port>`, for running
+    /// several nodes in one test without touching the real network.
+    Memory,
+}
+
+/// How a node with an empty chain catches up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Download and replay every block from genesis.
+    Full,
+    /// Restore the state from a peer's snapshot and replay only the blocks
+    /// after it. Peers are not trusted to say which state is the real one:
+    /// `checkpoint` is the hash of the block after the snapshot, whose
+    /// header commits to its state root, obtained from a source the
+    /// operator trusts. Falls back to a full sync if no peer offers it.
+    Snapshot { checkpoint: Hash },
+}
+
+/// How a node joins the peer-to-peer network.
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    pub transport: TransportKind,
+    pub listen_addresses: Vec<Multiaddr>,
+    /// Peers dialled on startup and used to seed Kademlia. Each address must
+    /// end in `/p2p/<peer id>`.
+    pub bootstrap_peers: Vec<Multiaddr>,
+    /// Finds peers on the local network by multicast.
+    pub enable_mdns: bool,
+    /// Holds the identity key and the peer ban list.
+    pub data_dir: PathBuf,
+    pub sync_mode: SyncMode,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            transport: TransportKind::Tcp,
+            listen_addresses: vec![DEFAULT_LISTEN_ADDRESS
+                .parse()
+                .expect("Default listen address is valid")],
+            bootstrap_peers: Vec::new(),
+            enable_mdns: true,
+            data_dir: PathBuf::from("data"),
+            sync_mode: SyncMode::Full,
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// A node on the in-memory transport listening on `/memory/<port>`. It
+    /// only connects to peers it is explicitly told about.
+    pub fn memory(port: u64, data_dir: PathBuf) -> Self {
+        NetworkConfig {
+            transport: TransportKind::Memory,
+            listen_addresses: vec![memory_address(port)],
+            bootstrap_peers: Vec::new(),
+            enable_mdns: false,
+            data_dir,
+            sync_mode: SyncMode::Full,
+        }
+    }
+
+    /// Loads the node's identity from the data directory, generating and
+    /// saving one on first start so the peer id stays the same across
+    /// restarts.
+    pub fn load_identity(&self) -> Result<identity::Keypair, Box<dyn Error>> {
+        let path = self.data_dir.join(IDENTITY_FILE);
+        match fs::read(&path) {
+            Ok(mut bytes) => {
+                let keypair = ed25519::Keypair::decode(&mut bytes)
+                    .map_err(|e| format!("Invalid identity key {}: {}", path.display(), e))?;
+                Ok(identity::Keypair::Ed25519(keypair))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let keypair = ed25519::Keypair::generate();
+                write_identity(&path, &keypair)?;
+                info!("Generated new identity key at {}", path.display());
+                Ok(identity::Keypair::Ed25519(keypair))
+            }
+            Err(e) => Err(format!("Failed to read identity key {}: {}", path.display(), e).into()),
+        }
+    }
+}
+
+fn write_identity(path: &Path, keypair: &ed25519::Keypair) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, keypair.encode())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+pub fn memory_address(port: u64) -> Multiaddr {
+    Multiaddr::empty().with(Protocol::Memory(port))
+}
+
+/// The peer id at the end of a `/p2p/<peer id>` address.
+pub fn peer_id_of(address: &Multiaddr) -> Option<PeerId> {
+    match address.iter().last()? {
+        Protocol::P2p(multihash) => PeerId::from_multihash(multihash).ok(),
+        _ => None,
+    }
+}
@@ -0,0 +1,142 @@
+use crate::blockchain::block::Block;
+use crate::blockchain::transaction::Transaction;
+use crate::consensus::evidence::Evidence;
+use crate::consensus::pbft::Vote;
+use crate::crypto::Hash;
+use crate::network::compact::CompactBlock;
+use crate::network::metrics::{PeerInfo, TrafficStats};
+use crate::network::p2p::NetworkMessage;
+use libp2p::{Multiaddr, PeerId};
+use std::error::Error;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+/// Work handed to the network task by the rest of the node.
+#[derive(Debug)]
+pub enum NetworkCommand {
+    /// Gossip something that originated on this node.
+    Publish(Box<NetworkMessage>),
+    /// Announce a transaction in our mempool to peers in the next batch.
+    AnnounceTransaction(Hash),
+    /// Fetch a block directly from peers; see `NetworkHandle::request_block`.
+    RequestBlock {
+        hash: Hash,
+        reply: oneshot::Sender<Option<Block>>,
+    },
+    /// Connect to a peer at a known address.
+    Dial(Multiaddr),
+    Peers(oneshot::Sender<Vec<PeerInfo>>),
+    Traffic(oneshot::Sender<TrafficStats>),
+}
+
+/// What the network task reports after it has validated and applied
+/// something received from a peer, and peers coming and going.
+#[derive(Debug, Clone)]
+pub enum NetworkEvent {
+    /// A gossiped block was imported.
+    BlockImported { peer: PeerId, block: Box<Block> },
+    /// A transaction fetched from a peer entered the mempool.
+    TransactionReceived {
+        peer: PeerId,
+        transaction: Box<Transaction>,
+    },
+    /// Gossiped evidence was queued for inclusion.
+    EvidenceReceived {
+        peer: PeerId,
+        evidence: Box<Evidence>,
+    },
+    /// Blocks downloaded by the sync manager were imported up to `height`.
+    Synced { height: u64 },
+    /// The state was restored from a peer's snapshot taken after `height`.
+    SnapshotRestored { height: u64 },
+    /// The first connection to a peer opened.
+    PeerConnected { peer: PeerId, address: Multiaddr },
+    /// The last connection to a peer closed.
+    PeerDisconnected { peer: PeerId },
+}
+
+/// A cheap, cloneable way to talk to the network task. Nothing ever locks
+/// `P2PNetwork`: the task owns it and drains these commands between swarm
+/// events.
+#[derive(Debug, Clone)]
+pub struct NetworkHandle {
+    commands: mpsc::UnboundedSender<NetworkCommand>,
+    events: broadcast::Sender<NetworkEvent>,
+}
+
+impl NetworkHandle {
+    pub(crate) fn new(
+        commands: mpsc::UnboundedSender<NetworkCommand>,
+        events: broadcast::Sender<NetworkEvent>,
+    ) -> Self {
+        NetworkHandle { commands, events }
+    }
+
+    /// Subscribes to network events, like `P2PNetwork::subscribe` but
+    /// usable once the network is running.
+    pub fn subscribe(&self) -> broadcast::Receiver<NetworkEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn broadcast_block(&self, block: Block) -> Result<(), Box<dyn Error>> {
+        self.send(NetworkCommand::Publish(Box::new(NetworkMessage::NewBlock(
+            Box::new(CompactBlock::from_block(&block)),
+        ))))
+    }
+
+    /// Announces a transaction by hash. Peers that lack it fetch it from
+    /// our mempool, so it must already be there.
+    pub fn announce_transaction(&self, hash: Hash) -> Result<(), Box<dyn Error>> {
+        self.send(NetworkCommand::AnnounceTransaction(hash))
+    }
+
+    pub fn broadcast_evidence(&self, evidence: Evidence) -> Result<(), Box<dyn Error>> {
+        self.send(NetworkCommand::Publish(Box::new(NetworkMessage::Evidence(
+            evidence,
+        ))))
+    }
+
+    pub fn broadcast_vote(&self, vote: Vote) -> Result<(), Box<dyn Error>> {
+        self.send(NetworkCommand::Publish(Box::new(NetworkMessage::Vote(
+            Box::new(vote),
+        ))))
+    }
+
+    /// Fetches a block directly from a connected peer, moving on to another
+    /// peer if one times out or does not have it. Yields `None` once enough
+    /// peers have failed.
+    pub async fn request_block(&self, hash: Hash) -> Result<Option<Block>, Box<dyn Error>> {
+        let (reply, receiver) = oneshot::channel();
+        self.send(NetworkCommand::RequestBlock { hash, reply })?;
+        Ok(receiver.await.unwrap_or(None))
+    }
+
+    /// Connects to the peer at `address`, which should end in
+    /// `/p2p/<peer id>` so Kademlia can remember it.
+    pub fn dial(&self, address: Multiaddr) -> Result<(), Box<dyn Error>> {
+        self.send(NetworkCommand::Dial(address))
+    }
+
+    /// The peers currently connected.
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>, Box<dyn Error>> {
+        let (reply, receiver) = oneshot::channel();
+        self.send(NetworkCommand::Peers(reply))?;
+        receiver
+            .await
+            .map_err(|_| "Network task has stopped".into())
+    }
+
+    /// Messages and bytes exchanged per topic so far.
+    pub async fn traffic(&self) -> Result<TrafficStats, Box<dyn Error>> {
+        let (reply, receiver) = oneshot::channel();
+        self.send(NetworkCommand::Traffic(reply))?;
+        receiver
+            .await
+            .map_err(|_| "Network task has stopped".into())
+    }
+
+    fn send(&self, command: NetworkCommand) -> Result<(), Box<dyn Error>> {
+        self.commands
+            .send(command)
+            .map_err(|_| "Network task has stopped".into())
+    }
+}
@@ -0,0 +1,72 @@
+use libp2p::{Multiaddr, PeerId};
+use std::collections::BTreeMap;
+
+/// A connected peer as the network task sees it.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    /// Remote addresses of the open connections to the peer.
+    pub addresses: Vec<Multiaddr>,
+    /// Whether the peer passed the status handshake. Until it does, nothing
+    /// but the handshake is exchanged with it.
+    pub handshaken: bool,
+    pub reputation: i32,
+    /// The head height from the peer's latest handshake.
+    pub head_height: Option<u64>,
+}
+
+/// Messages and bytes exchanged on one topic since the node started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficCounter {
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+}
+
+/// Traffic by topic. Gossip is counted under its topic, and direct requests
+/// and responses under `sync/<kind>`. Incoming messages are counted before
+/// they are rate limited or validated; outgoing gossip only counts what this
+/// node published, not what gossipsub forwarded for others.
+#[derive(Debug, Clone, Default)]
+pub struct TrafficStats {
+    topics: BTreeMap<&'static str, TrafficCounter>,
+}
+
+impl TrafficStats {
+    pub fn record_in(&mut self, topic: &'static str, bytes: usize) {
+        let counter = self.topics.entry(topic).or_default();
+        counter.messages_in += 1;
+        counter.bytes_in += bytes as u64;
+    }
+
+    pub fn record_out(&mut self, topic: &'static str, bytes: usize) {
+        let counter = self.topics.entry(topic).or_default();
+        counter.messages_out += 1;
+        counter.bytes_out += bytes as u64;
+    }
+
+    /// The counter for `topic`, zero if nothing was exchanged on it.
+    pub fn topic(&self, topic: &str) -> TrafficCounter {
+        self.topics.get(topic).copied().unwrap_or_default()
+    }
+
+    /// Every topic with traffic, in name order.
+    pub fn topics(&self) -> impl Iterator<Item = (&'static str, TrafficCounter)> + '_ {
+        self.topics
+            .iter()
+            .map(|(topic, counter)| (*topic, *counter))
+    }
+
+    /// All topics added up.
+    pub fn total(&self) -> TrafficCounter {
+        self.topics
+            .values()
+            .fold(TrafficCounter::default(), |total, counter| TrafficCounter {
+                messages_in: total.messages_in + counter.messages_in,
+                bytes_in: total.bytes_in + counter.bytes_in,
+                messages_out: total.messages_out + counter.messages_out,
+                bytes_out: total.bytes_out + counter.bytes_out,
+            })
+    }
+}
@@ -1,3 +1,19 @@
+pub mod compact;
+pub mod config;
+pub mod handle;
+pub mod metrics;
 pub mod p2p;
+pub mod protocol;
+pub(crate) mod rate_limit;
+pub mod relay;
+pub mod reputation;
+pub mod seen;
+pub mod snapshot_sync;
+pub mod sync;
+pub mod wire;
 
+pub use config::{NetworkConfig, SyncMode, TransportKind};
+pub use handle::{NetworkEvent, NetworkHandle};
+pub use metrics::{PeerInfo, TrafficCounter, TrafficStats};
 pub use p2p::P2PNetwork;
+pub use sync::SyncManager;
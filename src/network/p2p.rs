@@ -1,48 +1,210 @@
 use crate::blockchain::block::Block;
 use crate::blockchain::chain::Blockchain;
 use crate::blockchain::transaction::Transaction;
+use crate::consensus::evidence::Evidence;
+use crate::consensus::pbft::Vote;
 use crate::crypto::{Hash, Hashable};
+use crate::network::compact::{CompactBlock, PartialBlock};
+use crate::network::config::{peer_id_of, NetworkConfig, SyncMode, TransportKind};
+use crate::network::handle::{NetworkCommand, NetworkEvent, NetworkHandle};
+use crate::network::metrics::{PeerInfo, TrafficStats};
+use crate::network::protocol::{
+    ChainStatus, Handshake, SnapshotOffer, SyncCodec, SyncProtocol, SyncRequest, SyncResponse,
+    MAX_GOSSIP_SIZE, PROTOCOL_VERSION, REQUEST_TIMEOUT,
+};
+use crate::network::rate_limit::{MessageKind, RateLimiter};
+use crate::network::relay::{TransactionRelay, MAX_ANNOUNCEMENT_SIZE};
+use crate::network::reputation::{PeerAction, Reputation};
+use crate::network::seen::SeenCache;
+use crate::network::snapshot_sync::SnapshotSync;
+use crate::network::sync::{SyncManager, MAX_BODIES_PER_REQUEST, MAX_HEADERS_PER_REQUEST};
+use crate::network::wire;
 use futures::prelude::*;
-use libp2p::core::either::EitherError;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{Boxed, MemoryTransport};
+use libp2p::core::upgrade::Version;
+use libp2p::mplex::MplexConfig;
+use libp2p::noise::{Keypair, NoiseConfig, X25519Spec};
+use libp2p::request_response::{
+    ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+    RequestResponseMessage, ResponseChannel,
+};
 use libp2p::{
-    floodsub::{Floodsub, FloodsubEvent, Topic},
-    identity,
+    gossipsub::{
+        error::PublishError, Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage,
+        IdentTopic as Topic, MessageAcceptance, MessageAuthenticity, MessageId, ValidationMode,
+    },
+    kad::{store::MemoryStore, Kademlia, KademliaConfig, KademliaEvent},
     mdns::{Mdns, MdnsEvent},
-    swarm::{NetworkBehaviourEventProcess, ProtocolsHandlerUpgrErr, SwarmBuilder, SwarmEvent,Swarm},
+    swarm::{toggle::Toggle, NetworkBehaviourEventProcess, Swarm, SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig,
-    NetworkBehaviour, PeerId, Transport,
+    Multiaddr, NetworkBehaviour, PeerId, Transport,
 };
-use log::{error, info};
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error as StdError;
-use std::io;
+use std::fmt::Debug;
+use std::iter;
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
-use void::Void;
-use libp2p::noise::{Keypair, NoiseConfig, X25519Spec};
-use libp2p::core::upgrade::Version;
-use libp2p::mplex::MplexConfig;
+use std::time::Instant;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
 
 const BLOCK_TOPIC: &str = "blocks";
-const TRANSACTION_TOPIC: &str = "transactions";
-const BLOCK_REQUEST_TOPIC: &str = "block_requests";
+const EVIDENCE_TOPIC: &str = "evidence";
+const VOTE_TOPIC: &str = "votes";
+
+/// How long accepted transactions are collected before being announced.
+const ANNOUNCE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+/// How often we hand out sync requests.
+const SYNC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+/// How often each peer's head is refreshed by repeating the handshake.
+const STATUS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+/// How many different peers are asked for a block before giving up.
+const MAX_BLOCK_REQUEST_ATTEMPTS: usize = 3;
+/// How often Kademlia walks the DHT to find peers beyond the ones we know.
+const DISCOVERY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+/// Keeps our DHT apart from other libp2p networks, such as IPFS.
+const KADEMLIA_PROTOCOL: &[u8] = b"/flux/kad/1";
+/// How many events a slow subscriber may fall behind before it misses some.
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+/// How many events from peers may wait for the network task.
+const INBOUND_QUEUE_CAPACITY: usize = 1024;
+/// Room kept free in the inbound queue. Below it the swarm is not polled, so
+/// peers wait on their connections instead of filling memory.
+const INBOUND_QUEUE_HEADROOM: usize = 64;
+/// Where the ban list is kept inside the node's data directory.
+const BAN_LIST_FILE: &str = "banned_peers.json";
 
 #[derive(NetworkBehaviour)]
 #[behaviour(event_process = true)]
 struct FluxBehaviour {
-    floodsub: Floodsub,
-    mdns: Mdns,
+    gossipsub: Gossipsub,
+    mdns: Toggle<Mdns>,
+    kademlia: Kademlia<MemoryStore>,
+    request_response: RequestResponse<SyncCodec>,
     #[behaviour(ignore)]
-    response_sender: mpsc::UnboundedSender<NetworkMessage>,
+    event_sender: mpsc::Sender<InboundEvent>,
+    /// Applied here, before anything is queued, so a flood is dropped
+    /// without taking up room meant for other peers.
+    #[behaviour(ignore)]
+    rate_limiter: RateLimiter,
+    #[behaviour(ignore)]
+    traffic: TrafficStats,
+}
+
+/// What the behaviour hands to `P2PNetwork`, which owns the chain and so is
+/// the one that can act on it.
+enum InboundEvent {
+    /// A decoded gossip message, held back from forwarding until it has
+    /// been validated.
+    Gossip {
+        propagation_source: PeerId,
+        message_id: MessageId,
+        message: Box<NetworkMessage>,
+    },
+    /// Something a peer did that the behaviour cannot judge on its own
+    /// account, such as gossip that does not decode.
+    PeerAction(PeerId, PeerAction),
+    Discovered(PeerId, Multiaddr),
+    Request {
+        peer: PeerId,
+        request: SyncRequest,
+        channel: ResponseChannel<SyncResponse>,
+    },
+    Response {
+        peer: PeerId,
+        request_id: RequestId,
+        response: SyncResponse,
+    },
+    RequestFailed {
+        peer: PeerId,
+        request_id: RequestId,
+    },
 }
 
-impl NetworkBehaviourEventProcess<FloodsubEvent> for FluxBehaviour {
-    fn inject_event(&mut self, event: FloodsubEvent) {
-        if let FloodsubEvent::Message(message) = event {
-            if let Ok(msg) = serde_json::from_slice::<NetworkMessage>(&message.data) {
-                if let Err(e) = self.response_sender.send(msg) {
-                    error!("Error sending message through channel: {}", e);
+impl FluxBehaviour {
+    /// Queues an event for `P2PNetwork`. The swarm is not polled while the
+    /// queue is nearly full, so this only drops events when one poll yields
+    /// more than `INBOUND_QUEUE_HEADROOM`; dropped gossip is not forwarded
+    /// and a dropped request goes unanswered.
+    fn forward(&mut self, event: InboundEvent) {
+        match self.event_sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => {
+                warn!("Inbound queue full, dropping an event");
+                if let InboundEvent::Gossip {
+                    propagation_source,
+                    message_id,
+                    ..
+                } = event
+                {
+                    let _ = self.gossipsub.report_message_validation_result(
+                        &message_id,
+                        &propagation_source,
+                        MessageAcceptance::Ignore,
+                    );
+                }
+            }
+            Err(TrySendError::Closed(_)) => {
+                error!("Error sending message through channel: network task stopped");
+            }
+        }
+    }
+
+    /// Whether `peer` may send another message of `kind`. A peer over its
+    /// limit is penalized for each message dropped.
+    fn allow(&mut self, peer: PeerId, kind: MessageKind) -> bool {
+        if self.rate_limiter.allow(peer, kind) {
+            return true;
+        }
+        warn!("{} exceeded its {:?} rate limit", peer, kind);
+        self.forward(InboundEvent::PeerAction(peer, PeerAction::RateLimited));
+        false
+    }
+}
+
+impl NetworkBehaviourEventProcess<GossipsubEvent> for FluxBehaviour {
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        if let GossipsubEvent::Message {
+            propagation_source,
+            message_id,
+            message,
+        } = event
+        {
+            let (topic, kind) = if message.topic == Topic::new(BLOCK_TOPIC).hash() {
+                (BLOCK_TOPIC, MessageKind::BlockGossip)
+            } else if message.topic == Topic::new(VOTE_TOPIC).hash() {
+                (VOTE_TOPIC, MessageKind::VoteGossip)
+            } else {
+                (EVIDENCE_TOPIC, MessageKind::EvidenceGossip)
+            };
+            self.traffic.record_in(topic, message.data.len());
+            if !self.allow(propagation_source, kind) {
+                let _ = self.gossipsub.report_message_validation_result(
+                    &message_id,
+                    &propagation_source,
+                    MessageAcceptance::Ignore,
+                );
+                return;
+            }
+            match wire::decode::<NetworkMessage>(&message.data, MAX_GOSSIP_SIZE) {
+                Ok(msg) => self.forward(InboundEvent::Gossip {
+                    propagation_source,
+                    message_id,
+                    message: Box::new(msg),
+                }),
+                Err(_) => {
+                    let _ = self.gossipsub.report_message_validation_result(
+                        &message_id,
+                        &propagation_source,
+                        MessageAcceptance::Reject,
+                    );
+                    self.forward(InboundEvent::PeerAction(
+                        propagation_source,
+                        PeerAction::Malformed,
+                    ));
                 }
             }
         }
@@ -51,41 +213,170 @@ impl NetworkBehaviourEventProcess<FloodsubEvent> for FluxBehaviour {
 
 impl NetworkBehaviourEventProcess<MdnsEvent> for FluxBehaviour {
     fn inject_event(&mut self, event: MdnsEvent) {
-        match event {
-            MdnsEvent::Discovered(list) => {
-                for (peer_id, _multiaddr) in list {
-                    self.floodsub.add_node_to_partial_view(peer_id);
-                }
+        if let MdnsEvent::Discovered(list) = event {
+            for (peer_id, multiaddr) in list {
+                self.kademlia.add_address(&peer_id, multiaddr.clone());
+                self.forward(InboundEvent::Discovered(peer_id, multiaddr));
             }
-            MdnsEvent::Expired(list) => {
-                for (peer_id, _multiaddr) in list {
-                    if !self.mdns.has_node(&peer_id) {
-                        self.floodsub.remove_node_from_partial_view(&peer_id);
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<KademliaEvent> for FluxBehaviour {
+    fn inject_event(&mut self, event: KademliaEvent) {
+        if let KademliaEvent::RoutingUpdated {
+            peer,
+            is_new_peer: true,
+            addresses,
+            ..
+        } = event
+        {
+            self.forward(InboundEvent::Discovered(peer, addresses.first().clone()));
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<SyncRequest, SyncResponse>>
+    for FluxBehaviour
+{
+    fn inject_event(&mut self, event: RequestResponseEvent<SyncRequest, SyncResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    self.traffic
+                        .record_in(request.topic(), encoded_size(&request));
+                    // Dropping the channel leaves the request unanswered.
+                    if self.allow(peer, MessageKind::of_request(&request)) {
+                        self.forward(InboundEvent::Request {
+                            peer,
+                            request,
+                            channel,
+                        });
                     }
                 }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => {
+                    self.traffic
+                        .record_in(response.topic(), encoded_size(&response));
+                    self.forward(InboundEvent::Response {
+                        peer,
+                        request_id,
+                        response,
+                    })
+                }
+            },
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                warn!("Request to {} failed: {}", peer, error);
+                self.forward(InboundEvent::RequestFailed { peer, request_id });
             }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                warn!("Failed to answer request from {}: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum NetworkMessage {
-    NewBlock(Block),
-    NewTransaction(Transaction),
-    BlockRequest(Hash),
-    BlockResponse(Block),
+    /// A new block, with transactions peers likely have given by short id.
+    NewBlock(Box<CompactBlock>),
+    Evidence(Evidence),
+    /// A validator's PBFT vote on a block.
+    Vote(Box<Vote>),
 }
 
+impl NetworkMessage {
+    fn topic(&self) -> &'static str {
+        match self {
+            NetworkMessage::NewBlock(_) => BLOCK_TOPIC,
+            NetworkMessage::Evidence(_) => EVIDENCE_TOPIC,
+            NetworkMessage::Vote(_) => VOTE_TOPIC,
+        }
+    }
+}
+
+/// The hash gossip is deduplicated by: that of the encoded message, not of
+/// the block or evidence it carries. Keying on the block hash would let a
+/// re-gossip of a genuine header with altered short ids or evidence get the
+/// genuine block ignored.
+fn content_hash(data: &[u8]) -> Hash {
+    Hash::from(blake3::hash(data).as_bytes())
+}
+
+fn content_message_id(message: &GossipsubMessage) -> MessageId {
+    MessageId::from(content_hash(&message.data).as_bytes().to_vec())
+}
+
+/// Why an outbound request was sent, so its response reaches the right place.
+enum OutboundRequest {
+    Status,
+    Sync,
+    Block(Hash),
+    /// Transactions missing from a compact block we are reconstructing.
+    BlockTransactions(Hash),
+    Announcement,
+    /// Announced transactions we are pulling.
+    Transactions(Vec<Hash>),
+    Snapshot,
+    SnapshotChunk(u32),
+}
+
+/// A gossiped compact block waiting on transactions from the peer that sent
+/// it. Its validation result is reported once they arrive.
+struct PendingCompactBlock {
+    partial: PartialBlock,
+    peer: PeerId,
+    message_id: MessageId,
+}
+
+/// A block being fetched by hash, one peer at a time.
+struct BlockFetch {
+    tried: HashSet<PeerId>,
+    waiters: Vec<oneshot::Sender<Option<Block>>>,
+}
+
+/// The node's networking. It runs as its own task, started with `run`, and
+/// the rest of the node talks to it only through `NetworkHandle` commands
+/// and `NetworkEvent` subscriptions.
 pub struct P2PNetwork {
     swarm: Swarm<FluxBehaviour>,
-    response_receiver: mpsc::UnboundedReceiver<NetworkMessage>,
+    event_receiver: mpsc::Receiver<InboundEvent>,
+    command_sender: mpsc::UnboundedSender<NetworkCommand>,
+    command_receiver: mpsc::UnboundedReceiver<NetworkCommand>,
+    events: broadcast::Sender<NetworkEvent>,
     blockchain: Arc<RwLock<Blockchain>>,
-    pending_block_requests: HashMap<Hash, mpsc::Sender<Block>>,
+    pending_block_requests: HashMap<Hash, BlockFetch>,
+    pending_compact_blocks: HashMap<Hash, PendingCompactBlock>,
+    outbound_requests: HashMap<RequestId, OutboundRequest>,
+    /// Remote addresses of the open connections to each peer.
+    connections: HashMap<PeerId, Vec<Multiaddr>>,
+    /// Peers that passed the status handshake, with when their status was
+    /// last refreshed. Nothing else is exchanged with a peer until then.
+    handshaken_peers: HashMap<PeerId, Instant>,
+    sync: SyncManager,
+    snapshot_sync: SnapshotSync,
+    relay: TransactionRelay,
+    seen: SeenCache,
+    reputation: Reputation,
+    listen_addresses: Vec<Multiaddr>,
+    bootstrap_peers: Vec<Multiaddr>,
 }
 
 impl P2PNetwork {
-    pub async fn new(blockchain: Arc<RwLock<Blockchain>>) -> Result<Self, Box<dyn StdError>> {
-       let id_keys = identity::Keypair::generate_ed25519();
+    pub async fn new(
+        blockchain: Arc<RwLock<Blockchain>>,
+        config: NetworkConfig,
+    ) -> Result<Self, Box<dyn StdError>> {
+        let id_keys = config.load_identity()?;
         let peer_id = PeerId::from(id_keys.public());
         info!("Local peer id: {:?}", peer_id);
 
@@ -93,223 +384,1128 @@ impl P2PNetwork {
             .into_authentic(&id_keys)
             .expect("Signing libp2p-noise static DH keypair failed.");
 
-        let transport = TokioTcpConfig::new()
-            .upgrade(Version::V1)
-            .authenticate(NoiseConfig::xx(noise_keys).into_authenticated())
-            .multiplex(MplexConfig::new())
-            .boxed();
+        let transport: Boxed<(PeerId, StreamMuxerBox)> = match config.transport {
+            TransportKind::Tcp => TokioTcpConfig::new()
+                .upgrade(Version::V1)
+                .authenticate(NoiseConfig::xx(noise_keys).into_authenticated())
+                .multiplex(MplexConfig::new())
+                .boxed(),
+            TransportKind::Memory => MemoryTransport
+                .upgrade(Version::V1)
+                .authenticate(NoiseConfig::xx(noise_keys).into_authenticated())
+                .multiplex(MplexConfig::new())
+                .boxed(),
+        };
+
+        let (event_sender, event_receiver) = mpsc::channel(INBOUND_QUEUE_CAPACITY);
+
+        // Messages are only forwarded once `handle_network_message` has
+        // checked them and reported the result. Ids are content hashes, so
+        // the same block or evidence published by two nodes is one message.
+        let gossipsub_config = GossipsubConfigBuilder::default()
+            .validate_messages()
+            .validation_mode(ValidationMode::Strict)
+            .message_id_fn(content_message_id)
+            .max_transmit_size(MAX_GOSSIP_SIZE)
+            .build()?;
 
-        let (response_sender, response_receiver) = mpsc::unbounded_channel();
+        let mut request_response_config = RequestResponseConfig::default();
+        request_response_config.set_request_timeout(REQUEST_TIMEOUT);
+
+        let mut kademlia_config = KademliaConfig::default();
+        kademlia_config.set_protocol_name(KADEMLIA_PROTOCOL);
+        let mut kademlia =
+            Kademlia::with_config(peer_id, MemoryStore::new(peer_id), kademlia_config);
+        for address in &config.bootstrap_peers {
+            let peer = peer_id_of(address)
+                .ok_or_else(|| format!("Bootstrap address {} has no /p2p/ peer id", address))?;
+            kademlia.add_address(&peer, address.clone());
+        }
 
         let mut behaviour = FluxBehaviour {
-            floodsub: Floodsub::new(peer_id),
-            mdns: Mdns::new(Default::default()).await?,
-            response_sender,
+            gossipsub: Gossipsub::new(MessageAuthenticity::Signed(id_keys), gossipsub_config)?,
+            mdns: if config.enable_mdns {
+                Some(Mdns::new(Default::default()).await?)
+            } else {
+                None
+            }
+            .into(),
+            kademlia,
+            request_response: RequestResponse::new(
+                SyncCodec,
+                iter::once((SyncProtocol, ProtocolSupport::Full)),
+                request_response_config,
+            ),
+            event_sender,
+            rate_limiter: RateLimiter::new(),
+            traffic: TrafficStats::default(),
         };
 
-        behaviour.floodsub.subscribe(Topic::new(BLOCK_TOPIC));
-        behaviour.floodsub.subscribe(Topic::new(TRANSACTION_TOPIC));
-        behaviour
-            .floodsub
-            .subscribe(Topic::new(BLOCK_REQUEST_TOPIC));
+        for topic in [BLOCK_TOPIC, EVIDENCE_TOPIC, VOTE_TOPIC] {
+            behaviour
+                .gossipsub
+                .subscribe(&Topic::new(topic))
+                .map_err(|e| format!("Failed to subscribe to {}: {:?}", topic, e))?;
+        }
 
-       let swarm = SwarmBuilder::new(transport, behaviour, peer_id)
+        let mut swarm = SwarmBuilder::new(transport, behaviour, peer_id)
             .executor(Box::new(|fut| {
                 tokio::spawn(fut);
             }))
             .build();
 
+        let reputation = Reputation::load(config.data_dir.join(BAN_LIST_FILE));
+        for peer in reputation.banned_peers() {
+            swarm.ban_peer_id(*peer);
+        }
+
+        let head = blockchain.read().await.get_latest_block().await;
+        let sync = SyncManager::new(head.header.height, head.hash());
+        let snapshot_sync = SnapshotSync::new(match config.sync_mode {
+            SyncMode::Snapshot { checkpoint } if head.header.height == 0 => Some(checkpoint),
+            _ => None,
+        });
+
+        let (command_sender, command_receiver) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+
         Ok(P2PNetwork {
             swarm,
-            response_receiver,
+            event_receiver,
+            command_sender,
+            command_receiver,
+            events,
             blockchain,
             pending_block_requests: HashMap::new(),
+            pending_compact_blocks: HashMap::new(),
+            outbound_requests: HashMap::new(),
+            connections: HashMap::new(),
+            handshaken_peers: HashMap::new(),
+            sync,
+            snapshot_sync,
+            relay: TransactionRelay::new(),
+            seen: SeenCache::default(),
+            reputation,
+            listen_addresses: config.listen_addresses,
+            bootstrap_peers: config.bootstrap_peers,
         })
     }
 
-    pub async fn run(&mut self) -> Result<(), Box<dyn StdError>> {
-        self.swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    pub fn local_peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
+
+    /// A handle for sending commands to the network once it is running.
+    pub fn handle(&self) -> NetworkHandle {
+        NetworkHandle::new(self.command_sender.clone(), self.events.clone())
+    }
+
+    /// Subscribes to what the network receives and applies from peers.
+    pub fn subscribe(&self) -> broadcast::Receiver<NetworkEvent> {
+        self.events.subscribe()
+    }
+
+    /// The peers currently connected.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.connections
+            .iter()
+            .map(|(peer, addresses)| PeerInfo {
+                peer_id: *peer,
+                addresses: addresses.clone(),
+                handshaken: self.handshaken_peers.contains_key(peer),
+                reputation: self.reputation.score(peer),
+                head_height: self.sync.peer_status(peer).map(|status| status.height),
+            })
+            .collect()
+    }
+
+    /// Messages and bytes exchanged per topic so far.
+    pub fn traffic(&self) -> &TrafficStats {
+        &self.swarm.behaviour().traffic
+    }
+
+    /// Drives the swarm until it fails. Meant to be spawned as a task that
+    /// owns the network.
+    pub async fn run(mut self) -> Result<(), Box<dyn StdError>> {
+        for address in &self.listen_addresses {
+            self.swarm.listen_on(address.clone())?;
+        }
+        for address in &self.bootstrap_peers {
+            if let Err(e) = self.swarm.dial_addr(address.clone()) {
+                warn!("Failed to dial bootstrap peer {}: {}", address, e);
+            }
+        }
+        let mut sync_timer = tokio::time::interval(SYNC_INTERVAL);
+        let mut discovery_timer = tokio::time::interval(DISCOVERY_INTERVAL);
+        let mut announce_timer = tokio::time::interval(ANNOUNCE_INTERVAL);
 
         loop {
             tokio::select! {
-                event = self.swarm.select_next_some() => {
+                event = self.swarm.select_next_some(), if self.has_inbound_room() => {
                     self.handle_swarm_event(event).await;
                 },
-                message = self.response_receiver.recv() => {
-                    if let Some(msg) = message {
-                        self.handle_network_message(msg).await;
+                event = self.event_receiver.recv() => {
+                    if let Some(event) = event {
+                        self.handle_inbound_event(event).await;
                     } else {
                         break;
                     }
                 }
+                Some(command) = self.command_receiver.recv() => {
+                    self.handle_command(command);
+                }
+                _ = sync_timer.tick() => {
+                    self.seen.prune();
+                    for peer in self.reputation.expire_bans() {
+                        info!("Ban on {} expired", peer);
+                        self.swarm.unban_peer_id(peer);
+                    }
+                    if let Err(e) = self.drive_sync().await {
+                        error!("Sync error: {}", e);
+                    }
+                }
+                _ = announce_timer.tick() => {
+                    self.announce_transactions();
+                }
+                _ = discovery_timer.tick() => {
+                    // Fails only while the routing table is empty, and mDNS
+                    // or an inbound peer may still fill it.
+                    let _ = self.swarm.behaviour_mut().kademlia.bootstrap();
+                }
             }
         }
 
         Ok(())
     }
 
-    async fn floodsub_event(&mut self, event: FloodsubEvent) {
-        match event {
-            FloodsubEvent::Message(message) => {
-                if let Ok(msg) = serde_json::from_slice::<NetworkMessage>(&message.data) {
-                    self.handle_network_message(msg).await;
-                }
-            }
-            _ => {}
-        }
+    fn has_inbound_room(&self) -> bool {
+        self.swarm.behaviour().event_sender.capacity() >= INBOUND_QUEUE_HEADROOM
     }
 
-    async fn mdns_event(&mut self, event: MdnsEvent) {
-        match event {
-            MdnsEvent::Discovered(list) => {
-                info!("Discovered {:?} nodes", list.len());
+    fn handle_command(&mut self, command: NetworkCommand) {
+        match command {
+            NetworkCommand::Publish(message) => {
+                if let Err(e) = self.publish(&message) {
+                    error!("{}", e);
+                }
+            }
+            NetworkCommand::AnnounceTransaction(hash) => {
+                self.seen.insert(hash);
+                self.relay.queue(hash);
+            }
+            NetworkCommand::RequestBlock { hash, reply } => self.request_block(hash, reply),
+            NetworkCommand::Dial(address) => {
+                if let Some(peer) = peer_id_of(&address) {
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer, address.clone());
+                }
+                if let Err(e) = self.swarm.dial_addr(address.clone()) {
+                    warn!("Failed to dial {}: {}", address, e);
+                }
+            }
+            NetworkCommand::Peers(reply) => {
+                let _ = reply.send(self.peers());
+            }
+            NetworkCommand::Traffic(reply) => {
+                let _ = reply.send(self.traffic().clone());
             }
-            _ => {}
         }
     }
 
-    async fn handle_swarm_event(
-        &mut self,
-        event: SwarmEvent<(), EitherError<ProtocolsHandlerUpgrErr<io::Error>, Void>>,
-    ) {
+    async fn handle_swarm_event<E: Debug>(&mut self, event: SwarmEvent<(), E>) {
         match event {
             SwarmEvent::NewListenAddr { address, .. } => {
                 info!("Listening on {:?}", address);
             }
-            // SwarmEvent::Behaviour(behaviour_event) => match behaviour_event {
-            //     FluxBehaviourEvent::Floodsub(event) => {
-            //         self.floodsub_event(event).await;
-            //     }
-            //     FluxBehaviourEvent::Mdns(event) => {
-            //         self.mdns_event(event).await;
-            //     }
-            // },
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+            } => {
+                let address = endpoint.get_remote_address().clone();
+                self.connections
+                    .entry(peer_id)
+                    .or_default()
+                    .push(address.clone());
+                if num_established.get() == 1 {
+                    self.emit(NetworkEvent::PeerConnected {
+                        peer: peer_id,
+                        address,
+                    });
+                    self.send_status(peer_id).await;
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } => {
+                if num_established > 0 {
+                    if let Some(addresses) = self.connections.get_mut(&peer_id) {
+                        let closed = endpoint.get_remote_address();
+                        if let Some(index) = addresses.iter().position(|address| address == closed)
+                        {
+                            addresses.remove(index);
+                        }
+                    }
+                    return;
+                }
+                self.connections.remove(&peer_id);
+                self.emit(NetworkEvent::PeerDisconnected { peer: peer_id });
+                self.handshaken_peers.remove(&peer_id);
+                self.sync.on_peer_disconnected(&peer_id);
+                self.snapshot_sync.on_peer_disconnected(&peer_id);
+                self.swarm
+                    .behaviour_mut()
+                    .rate_limiter
+                    .remove_peer(&peer_id);
+                let retries = self.relay.on_peer_disconnected(&peer_id);
+                self.request_transactions(retries);
+            }
             _ => {}
         }
     }
 
-    pub async fn handle_network_message(&mut self, message: NetworkMessage) {
-        match message {
-            NetworkMessage::NewBlock(block) => {
-                info!("Received new block: {:?}", block.hash());
-                let result = self.add_block_to_blockchain(block.clone()).await;
-                match result {
-                    Ok(_) => {
-                        if let Err(e) = self.broadcast_block(block).await {
-                            error!("Failed to broadcast block: {}", e);
-                        }
+    async fn handle_inbound_event(&mut self, event: InboundEvent) {
+        match event {
+            InboundEvent::Gossip {
+                propagation_source,
+                message_id,
+                message,
+            } => {
+                let acceptance = if self.handshaken_peers.contains_key(&propagation_source) {
+                    self.handle_network_message(propagation_source, message_id.clone(), *message)
+                        .await
+                } else {
+                    Some(MessageAcceptance::Ignore)
+                };
+                if let Some(acceptance) = acceptance {
+                    self.report_validation(&message_id, &propagation_source, acceptance);
+                }
+            }
+            InboundEvent::PeerAction(peer, action) => self.report_peer(peer, action),
+            InboundEvent::Discovered(peer_id, address) => {
+                if !self.swarm.is_connected(&peer_id) && !self.reputation.is_banned(&peer_id) {
+                    info!("Discovered {} at {}", peer_id, address);
+                    if let Err(e) = self.swarm.dial_addr(address) {
+                        warn!("Failed to dial {}: {}", peer_id, e);
                     }
-                    Err(e) => error!("Failed to add block: {}", e),
                 }
             }
-            NetworkMessage::NewTransaction(transaction) => {
-                info!("Received new transaction: {:?}", transaction.hash());
-                let result = self
-                    .add_transaction_to_blockchain(transaction.clone())
-                    .await;
-                match result {
-                    Ok(_) => {
-                        if let Err(e) = self.broadcast_transaction(transaction).await {
-                            error!("Failed to broadcast transaction: {}", e);
+            InboundEvent::Request {
+                peer,
+                request,
+                channel,
+            } => {
+                info!("Received {:?} from {}", request, peer);
+                let allowed = match &request {
+                    SyncRequest::Status(handshake) => self.on_handshake(peer, handshake).await,
+                    _ => self.handshaken_peers.contains_key(&peer),
+                };
+                if !allowed {
+                    return;
+                }
+                let response = match request {
+                    SyncRequest::AnnounceTransactions(hashes) => {
+                        if hashes.len() > MAX_ANNOUNCEMENT_SIZE {
+                            self.report_peer(peer, PeerAction::Invalid);
+                            return;
                         }
+                        self.on_transaction_announcement(peer, hashes);
+                        SyncResponse::Ack
                     }
-                    Err(e) => error!("Failed to add transaction: {}", e),
+                    request => {
+                        if let SyncRequest::Transactions(hashes) = &request {
+                            for hash in hashes {
+                                self.relay.mark_known(peer, *hash);
+                            }
+                        }
+                        serve_request(&*self.blockchain.read().await, request).await
+                    }
+                };
+                let topic = response.topic();
+                let size = encoded_size(&response);
+                let behaviour = self.swarm.behaviour_mut();
+                if behaviour
+                    .request_response
+                    .send_response(channel, response)
+                    .is_ok()
+                {
+                    behaviour.traffic.record_out(topic, size);
+                } else {
+                    warn!("Connection to {} closed before the response was sent", peer);
                 }
             }
-            NetworkMessage::BlockRequest(hash) => {
-                info!("Received block request for hash: {:?}", hash);
-                if let Some(block) = self.get_block_from_blockchain(&hash).await {
-                    if let Err(e) = self.send_block_response(block).await {
-                        error!("Failed to send block response: {}", e);
+            InboundEvent::Response {
+                peer,
+                request_id,
+                response,
+            } => {
+                let purpose = match self.outbound_requests.remove(&request_id) {
+                    Some(purpose) => purpose,
+                    None => return,
+                };
+                if let Err(e) = response.check_size_limits() {
+                    warn!("Oversized response from {}: {}", peer, e);
+                    self.report_peer(peer, PeerAction::Invalid);
+                    self.on_request_failed(peer, purpose);
+                    return;
+                }
+                match purpose {
+                    OutboundRequest::Status => {
+                        if let SyncResponse::Status(handshake) = response {
+                            self.on_handshake(peer, &handshake).await;
+                        }
+                    }
+                    OutboundRequest::Sync => {
+                        let result = {
+                            let blockchain = self.blockchain.read().await;
+                            let hashes = blockchain.block_hashes_by_height().await;
+                            let consensus = blockchain.consensus().await;
+                            self.sync.on_response(
+                                &peer,
+                                response,
+                                |header| consensus.is_scheduled_producer(header),
+                                |height| hashes.get(&height).copied(),
+                            )
+                        };
+                        if let Err(e) = result {
+                            error!("Invalid sync response from {}: {}", peer, e);
+                            self.report_peer(peer, PeerAction::Invalid);
+                        }
+                        if let Err(e) = self.import_synced_blocks().await {
+                            error!("Failed to import synced blocks: {}", e);
+                        }
+                    }
+                    OutboundRequest::Block(hash) => match response {
+                        // The hash only covers the header, so the body and
+                        // signature are checked before the block is handed on.
+                        SyncResponse::Block(Some(block))
+                            if block.hash() == hash
+                                && block.verify_signature()
+                                && block.has_valid_body() =>
+                        {
+                            self.complete_block_request(&hash, Some(*block));
+                        }
+                        SyncResponse::Block(None) => self.retry_block_request(hash),
+                        _ => {
+                            self.report_peer(peer, PeerAction::Invalid);
+                            self.retry_block_request(hash);
+                        }
+                    },
+                    OutboundRequest::BlockTransactions(hash) => {
+                        self.complete_compact_block(hash, response).await;
+                    }
+                    OutboundRequest::Announcement => {}
+                    OutboundRequest::Transactions(hashes) => {
+                        self.receive_transactions(peer, hashes, response).await;
+                    }
+                    OutboundRequest::Snapshot => {
+                        let result = match response {
+                            SyncResponse::Snapshot(offer) => {
+                                self.snapshot_sync.on_offer(peer, offer.map(|offer| *offer))
+                            }
+                            _ => Err("Expected a snapshot offer".to_string()),
+                        };
+                        if let Err(e) = result {
+                            warn!("Invalid snapshot offer from {}: {}", peer, e);
+                            self.report_peer(peer, PeerAction::Invalid);
+                        }
+                    }
+                    OutboundRequest::SnapshotChunk(index) => {
+                        let result = match response {
+                            SyncResponse::SnapshotChunk(chunk) => {
+                                self.snapshot_sync.on_chunk(&peer, index, chunk)
+                            }
+                            _ => {
+                                self.snapshot_sync.on_request_failed(&peer, index);
+                                Err("Expected a snapshot chunk".to_string())
+                            }
+                        };
+                        if let Err(e) = result {
+                            warn!("Invalid snapshot chunk from {}: {}", peer, e);
+                            self.report_peer(peer, PeerAction::Invalid);
+                        }
+                        self.finish_snapshot().await;
                     }
                 }
             }
-            NetworkMessage::BlockResponse(block) => {
-                info!("Received block response: {:?}", block.hash());
-                if let Some(sender) = self.pending_block_requests.remove(&block.hash()) {
-                    if let Err(e) = sender.send(block).await {
-                        error!("Failed to send block to requester: {}", e);
+            InboundEvent::RequestFailed { peer, request_id } => {
+                if let Some(purpose) = self.outbound_requests.remove(&request_id) {
+                    if !matches!(purpose, OutboundRequest::Status) {
+                        self.report_peer(peer, PeerAction::Unresponsive);
                     }
+                    self.on_request_failed(peer, purpose);
                 }
             }
         }
     }
 
-    async fn add_block_to_blockchain(&self, block: Block) -> Result<(), Box<dyn StdError>> {
-        let blockchain = self.blockchain.write().await;
-        blockchain.add_block(block).await?;
-        Ok(())
+    /// Moves on after a request got no usable answer: retries it elsewhere
+    /// or gives up on what it was for.
+    fn on_request_failed(&mut self, peer: PeerId, purpose: OutboundRequest) {
+        match purpose {
+            OutboundRequest::Status => {
+                let _ = self.swarm.disconnect_peer_id(peer);
+            }
+            OutboundRequest::Sync => {
+                self.sync.on_request_failed(&peer);
+            }
+            OutboundRequest::Block(hash) => {
+                self.retry_block_request(hash);
+            }
+            OutboundRequest::BlockTransactions(hash) => {
+                // Sync fetches the block later if it turns out to extend the
+                // chain.
+                if let Some(pending) = self.pending_compact_blocks.remove(&hash) {
+                    self.report_validation(
+                        &pending.message_id,
+                        &pending.peer,
+                        MessageAcceptance::Ignore,
+                    );
+                }
+            }
+            OutboundRequest::Announcement => {}
+            OutboundRequest::Transactions(hashes) => {
+                let retries = self.relay.on_fetch_failed(&peer, &hashes);
+                self.request_transactions(retries);
+            }
+            // Discovery ends at its deadline whether or not every peer
+            // answered.
+            OutboundRequest::Snapshot => {}
+            OutboundRequest::SnapshotChunk(index) => {
+                self.snapshot_sync.on_request_failed(&peer, index);
+            }
+        }
     }
 
-    async fn add_transaction_to_blockchain(
-        &self,
-        transaction: Transaction,
-    ) -> Result<(), Box<dyn StdError>> {
-        let blockchain = self.blockchain.write().await;
-        blockchain.add_transaction(transaction).await?;
-        Ok(())
+    /// Validates and applies a gossip message. The result decides whether
+    /// gossipsub forwards it: only messages that passed are propagated, and
+    /// ones that are provably invalid count against the peer that sent them.
+    ///
+    /// Propagation belongs to the network layer alone. Gossip is forwarded
+    /// by gossipsub after this returns `Accept` and is never re-published;
+    /// `Blockchain` only publishes what originates on this node.
+    ///
+    /// `peer` is the peer that forwarded the message. Peers only forward
+    /// what they validated, so it answers for the message in its reputation.
+    ///
+    /// Returns `None` when a compact block needs transactions we lack; its
+    /// result is reported when they arrive.
+    async fn handle_network_message(
+        &mut self,
+        peer: PeerId,
+        message_id: MessageId,
+        message: NetworkMessage,
+    ) -> Option<MessageAcceptance> {
+        if !self.seen.insert(Hash::from(message_id.0.as_slice())) {
+            return Some(MessageAcceptance::Ignore);
+        }
+
+        Some(match message {
+            NetworkMessage::NewBlock(compact) => {
+                let hash = compact.hash();
+                info!("Received new block {:?} from {}", hash, peer);
+                if !compact.verify_signature() {
+                    self.report_peer(peer, PeerAction::Invalid);
+                    return Some(MessageAcceptance::Reject);
+                }
+
+                let mempool = self
+                    .blockchain
+                    .read()
+                    .await
+                    .get_mempool_transactions()
+                    .await;
+                let partial = compact.reconstruct(mempool);
+                let missing = partial.missing();
+                if missing.is_empty() {
+                    let block = partial.into_block()?;
+                    return Some(self.import_gossiped_block(peer, block).await);
+                }
+
+                info!(
+                    "Fetching {} missing transactions of {:?} from {}",
+                    missing.len(),
+                    hash,
+                    peer
+                );
+                self.send_request(
+                    peer,
+                    SyncRequest::BlockTransactions {
+                        block_hash: hash,
+                        indexes: missing,
+                    },
+                    OutboundRequest::BlockTransactions(hash),
+                );
+                self.pending_compact_blocks.insert(
+                    hash,
+                    PendingCompactBlock {
+                        partial,
+                        peer,
+                        message_id,
+                    },
+                );
+                return None;
+            }
+            NetworkMessage::Evidence(evidence) => {
+                info!("Received evidence against {:?}", evidence.offender());
+                let added = self
+                    .blockchain
+                    .read()
+                    .await
+                    .add_evidence(evidence.clone())
+                    .await;
+                match added {
+                    Ok(_) => {
+                        self.emit(NetworkEvent::EvidenceReceived {
+                            peer,
+                            evidence: Box::new(evidence),
+                        });
+                        MessageAcceptance::Accept
+                    }
+                    Err(e) => {
+                        error!("Failed to add evidence: {}", e);
+                        MessageAcceptance::Ignore
+                    }
+                }
+            }
+            NetworkMessage::Vote(vote) => {
+                let added = self.blockchain.read().await.add_vote(*vote).await;
+                match added {
+                    Ok(true) => {
+                        info!("Votes from {} completed a certificate", peer);
+                        MessageAcceptance::Accept
+                    }
+                    Ok(false) => MessageAcceptance::Accept,
+                    // The voter set may just have changed, so a vote we
+                    // cannot verify is not held against the peer.
+                    Err(e) => {
+                        warn!("Ignoring vote from {}: {}", peer, e);
+                        MessageAcceptance::Ignore
+                    }
+                }
+            }
+        })
     }
 
-    async fn get_block_from_blockchain(&self, hash: &Hash) -> Option<Block> {
-        let blockchain = self.blockchain.read().await;
-        blockchain.get_block_by_hash(hash).await
+    /// Checks a gossiped block's body and size and imports it. The compact
+    /// announcement fits in a gossip message whatever the size of the block
+    /// it stands for, so the size is only known once it is rebuilt.
+    async fn import_gossiped_block(&mut self, peer: PeerId, block: Block) -> MessageAcceptance {
+        if !block.has_valid_body() || !block.is_within_size_limit() {
+            self.report_peer(peer, PeerAction::Invalid);
+            return MessageAcceptance::Reject;
+        }
+        let added = self.blockchain.read().await.add_block(block.clone()).await;
+        match added {
+            Ok(_) => {
+                self.sync.set_local_head(block.header.height, block.hash());
+                self.report_peer(peer, PeerAction::Useful);
+                self.emit(NetworkEvent::BlockImported {
+                    peer,
+                    block: Box::new(block),
+                });
+                MessageAcceptance::Accept
+            }
+            Err(e) => {
+                error!("Failed to add block: {}", e);
+                MessageAcceptance::Ignore
+            }
+        }
     }
 
-    async fn broadcast_block(&mut self, block: Block) -> Result<(), Box<dyn std::error::Error>> {
-        let bytes = serde_json::to_vec(&NetworkMessage::NewBlock(block))?;
-        self.swarm
-            .behaviour_mut()
-            .floodsub
-            .publish(Topic::new(BLOCK_TOPIC), bytes);
-        Ok(())
+    /// Finishes a compact block with the transactions its sender returned
+    /// and reports the validation result held back until now.
+    async fn complete_compact_block(&mut self, hash: Hash, response: SyncResponse) {
+        let mut pending = match self.pending_compact_blocks.remove(&hash) {
+            Some(pending) => pending,
+            None => return,
+        };
+
+        let filled = match response {
+            SyncResponse::Transactions(transactions) => pending.partial.fill(transactions),
+            _ => Err("Unexpected response".to_string()),
+        };
+        let block = match filled {
+            Ok(()) => pending.partial.into_block(),
+            Err(e) => {
+                warn!(
+                    "Could not complete block {:?} from {}: {}",
+                    hash, pending.peer, e
+                );
+                None
+            }
+        };
+        let acceptance = match block {
+            Some(block) => self.import_gossiped_block(pending.peer, block).await,
+            None => {
+                self.report_peer(pending.peer, PeerAction::Invalid);
+                MessageAcceptance::Reject
+            }
+        };
+        self.report_validation(&pending.message_id, &pending.peer, acceptance);
     }
 
-    pub async fn broadcast_transaction(
+    fn report_validation(
         &mut self,
-        transaction: Transaction,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let bytes = serde_json::to_vec(&NetworkMessage::NewTransaction(transaction))?;
-        self.swarm
+        message_id: &MessageId,
+        peer: &PeerId,
+        acceptance: MessageAcceptance,
+    ) {
+        if let Err(e) = self
+            .swarm
             .behaviour_mut()
-            .floodsub
-            .publish(Topic::new(TRANSACTION_TOPIC), bytes);
-        Ok(())
+            .gossipsub
+            .report_message_validation_result(message_id, peer, acceptance)
+        {
+            warn!("Failed to report validation result: {:?}", e);
+        }
     }
 
-    async fn send_block_response(
+    /// Remembers what `peer` announced and pulls the transactions we have
+    /// not seen from it.
+    fn on_transaction_announcement(&mut self, peer: PeerId, hashes: Vec<Hash>) {
+        let seen = &self.seen;
+        let wanted = self
+            .relay
+            .on_announcement(peer, hashes, |hash| !seen.contains(hash));
+        if !wanted.is_empty() {
+            self.request_transactions(vec![(peer, wanted)]);
+        }
+    }
+
+    fn request_transactions(&mut self, requests: Vec<(PeerId, Vec<Hash>)>) {
+        for (peer, hashes) in requests {
+            self.send_request(
+                peer,
+                SyncRequest::Transactions(hashes.clone()),
+                OutboundRequest::Transactions(hashes),
+            );
+        }
+    }
+
+    /// Handles the answer to a transaction fetch. Transactions we did not
+    /// ask this peer for count against it; ones it left out are asked of
+    /// another peer that announced them.
+    async fn receive_transactions(
         &mut self,
-        block: Block,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let bytes = serde_json::to_vec(&NetworkMessage::BlockResponse(block))?;
-        self.swarm
-            .behaviour_mut()
-            .floodsub
-            .publish(Topic::new(BLOCK_REQUEST_TOPIC), bytes);
+        peer: PeerId,
+        requested: Vec<Hash>,
+        response: SyncResponse,
+    ) {
+        let transactions = match response {
+            SyncResponse::Transactions(transactions) => transactions,
+            _ => {
+                self.report_peer(peer, PeerAction::Invalid);
+                Vec::new()
+            }
+        };
+
+        for transaction in transactions {
+            let hash = transaction.hash();
+            if !self.relay.is_fetching_from(&peer, &hash) {
+                self.report_peer(peer, PeerAction::Invalid);
+                continue;
+            }
+            self.relay.on_fetched(peer, hash);
+            self.import_transaction(peer, transaction).await;
+        }
+
+        let undelivered: Vec<Hash> = requested
+            .into_iter()
+            .filter(|hash| self.relay.is_fetching_from(&peer, hash))
+            .collect();
+        let retries = self.relay.on_fetch_failed(&peer, &undelivered);
+        self.request_transactions(retries);
+    }
+
+    /// Adds a transaction fetched from `peer` to the mempool and queues it
+    /// to be announced to the peers that lack it.
+    ///
+    /// Only a transaction that is invalid whatever our state, with a bad
+    /// signature or over the size limit, counts against the peer. One that
+    /// does not apply to our state, say a nonce we are not at yet, may apply
+    /// to the peer's, so it is dropped without penalty and left unseen to be
+    /// fetched again once it might.
+    async fn import_transaction(&mut self, peer: PeerId, transaction: Transaction) {
+        let hash = transaction.hash();
+        if self.seen.contains(&hash) {
+            return;
+        }
+        info!("Received new transaction: {:?}", hash);
+        if !transaction.verify() || !transaction.is_within_size_limit() {
+            self.report_peer(peer, PeerAction::Invalid);
+            return;
+        }
+        let added = self
+            .blockchain
+            .read()
+            .await
+            .add_transaction(transaction.clone())
+            .await;
+        match added {
+            Ok(_) => {
+                self.seen.insert(hash);
+                self.report_peer(peer, PeerAction::Useful);
+                self.relay.queue(hash);
+                self.emit(NetworkEvent::TransactionReceived {
+                    peer,
+                    transaction: Box::new(transaction),
+                });
+            }
+            Err(e) => error!("Failed to add transaction: {}", e),
+        }
+    }
+
+    /// Sends each handshaken peer the queued transaction hashes it does not
+    /// already have.
+    fn announce_transactions(&mut self) {
+        let peers: Vec<PeerId> = self.handshaken_peers.keys().copied().collect();
+        for (peer, hashes) in self.relay.flush(peers) {
+            self.send_request(
+                peer,
+                SyncRequest::AnnounceTransactions(hashes),
+                OutboundRequest::Announcement,
+            );
+        }
+    }
+
+    /// Sends an event to subscribers. Having none is not an error.
+    fn emit(&self, event: NetworkEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Adjusts a peer's reputation, banning and disconnecting it once the
+    /// score falls to the threshold.
+    fn report_peer(&mut self, peer: PeerId, action: PeerAction) {
+        if !self.reputation.report(peer, action) {
+            return;
+        }
+
+        warn!(
+            "Banning {} with reputation {}",
+            peer,
+            self.reputation.score(&peer)
+        );
+        self.handshaken_peers.remove(&peer);
+        self.sync.on_peer_disconnected(&peer);
+        self.snapshot_sync.on_peer_disconnected(&peer);
+        self.swarm.behaviour_mut().kademlia.remove_peer(&peer);
+        self.swarm.ban_peer_id(peer);
+    }
+
+    /// Sends our handshake to `peer`, which answers with its own.
+    async fn send_status(&mut self, peer: PeerId) {
+        let handshake = local_handshake(&*self.blockchain.read().await).await;
+        self.send_request(
+            peer,
+            SyncRequest::Status(handshake),
+            OutboundRequest::Status,
+        );
+    }
+
+    /// Checks a peer's handshake against ours. A peer on another chain or
+    /// protocol version is disconnected; otherwise its head is passed to the
+    /// sync manager.
+    async fn on_handshake(&mut self, peer: PeerId, handshake: &Handshake) -> bool {
+        let ours = local_handshake(&*self.blockchain.read().await).await;
+        if let Err(e) = handshake.check(&ours) {
+            warn!("Disconnecting {}: {}", peer, e);
+            self.handshaken_peers.remove(&peer);
+            self.sync.on_peer_disconnected(&peer);
+            let _ = self.swarm.disconnect_peer_id(peer);
+            return false;
+        }
+
+        self.handshaken_peers.insert(peer, Instant::now());
+        self.sync.on_peer_status(peer, handshake.head.clone());
+        true
+    }
+
+    /// Refreshes stale peer heads and sends whatever the sync manager wants
+    /// fetched next. Block sync waits while a snapshot is being fetched.
+    async fn drive_sync(&mut self) -> Result<(), Box<dyn StdError>> {
+        let stale: Vec<PeerId> = self
+            .handshaken_peers
+            .iter()
+            .filter(|(_, refreshed)| refreshed.elapsed() >= STATUS_INTERVAL)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in stale {
+            // Not asked again until this answer arrives or the request fails.
+            self.handshaken_peers.insert(peer, Instant::now());
+            self.send_status(peer).await;
+        }
+
+        if self.snapshot_sync.is_active() {
+            let peers: Vec<PeerId> = self.handshaken_peers.keys().copied().collect();
+            for (peer, request) in self.snapshot_sync.next_requests(&peers) {
+                let purpose = match &request {
+                    SyncRequest::SnapshotChunk { index, .. } => {
+                        OutboundRequest::SnapshotChunk(*index)
+                    }
+                    _ => OutboundRequest::Snapshot,
+                };
+                self.send_request(peer, request, purpose);
+            }
+            if self.snapshot_sync.is_active() {
+                return Ok(());
+            }
+            info!("No peer offered the checkpointed snapshot in time, syncing from genesis");
+        }
+
+        for (peer, request) in self.sync.next_requests() {
+            self.send_request(peer, request, OutboundRequest::Sync);
+        }
         Ok(())
     }
 
-    pub async fn request_block(&mut self, hash: Hash) -> Result<Option<Block>, Box<dyn StdError>> {
-        let (sender, mut receiver) = mpsc::channel(1);
-        self.pending_block_requests.insert(hash.clone(), sender);
+    /// Imports downloaded blocks in height order, stopping at the first one
+    /// the chain rejects. A branch leaving the chain below its head replaces
+    /// the blocks above the fork.
+    async fn import_synced_blocks(&mut self) -> Result<(), String> {
+        let ready = self.sync.drain_ready();
+        if ready.is_empty() {
+            return Ok(());
+        }
 
-        let message = NetworkMessage::BlockRequest(hash);
-        let bytes = serde_json::to_vec(&message)?;
-        self.swarm
-            .behaviour_mut()
-            .floodsub
-            .publish(Topic::new(BLOCK_REQUEST_TOPIC), bytes);
+        let result = {
+            let blockchain = self.blockchain.read().await;
+            let head = blockchain.get_latest_block().await.hash();
+            if ready[0].header.previous_hash != head {
+                info!("Switching to a fork from height {}", ready[0].header.height);
+                blockchain
+                    .reorganize(ready)
+                    .await
+                    .map_err(|e| e.to_string())
+            } else {
+                let mut result = Ok(());
+                for block in ready {
+                    if let Err(e) = blockchain.add_block(block).await {
+                        result = Err(e.to_string());
+                        break;
+                    }
+                }
+                result
+            }
+        };
+
+        let head = self.blockchain.read().await.get_latest_block().await;
+        self.sync.set_local_head(head.header.height, head.hash());
+        self.emit(NetworkEvent::Synced {
+            height: head.header.height,
+        });
+        result
+    }
 
-        // Wait for the response with a timeout
-        match tokio::time::timeout(std::time::Duration::from_secs(30), receiver.recv()).await {
-            Ok(Some(block)) => Ok(Some(block)),
-            Ok(None) => Ok(None),
-            Err(_) => Err("Block request timed out".into()),
+    /// Restores the state from the snapshot once every chunk has arrived.
+    /// If that fails the chain stays at genesis and block sync starts from
+    /// there.
+    async fn finish_snapshot(&mut self) {
+        let (offer, chunks) = match self.snapshot_sync.take_complete() {
+            Some(complete) => complete,
+            None => return,
+        };
+        let height = offer.manifest.height;
+        let head = offer.block.hash();
+        let result = match offer
+            .manifest
+            .restore(chunks, offer.next_header.header.state_root)
+        {
+            Ok(state) => self
+                .blockchain
+                .read()
+                .await
+                .restore_snapshot(state, offer.block)
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                info!("Restored state at height {} from a snapshot", height);
+                self.sync.set_local_head(height, head);
+                self.emit(NetworkEvent::SnapshotRestored { height });
+            }
+            Err(e) => error!("Failed to restore snapshot at height {}: {}", height, e),
         }
     }
+
+    /// Sends a request to `peer`, remembering why for when it is answered.
+    fn send_request(&mut self, peer: PeerId, request: SyncRequest, purpose: OutboundRequest) {
+        let topic = request.topic();
+        let size = encoded_size(&request);
+        let behaviour = self.swarm.behaviour_mut();
+        let request_id = behaviour.request_response.send_request(&peer, request);
+        behaviour.traffic.record_out(topic, size);
+        self.outbound_requests.insert(request_id, purpose);
+    }
+
+    /// Publishes our own message. Having no peers yet is not an error: the
+    /// sync protocol catches them up once they connect. Neither is publishing
+    /// something already gossiped, since peers have it.
+    fn publish(&mut self, message: &NetworkMessage) -> Result<(), Box<dyn StdError>> {
+        let topic = message.topic();
+        let bytes = wire::encode(message)?;
+        self.seen.insert(content_hash(&bytes));
+        let size = bytes.len();
+        let behaviour = self.swarm.behaviour_mut();
+        match behaviour.gossipsub.publish(Topic::new(topic), bytes) {
+            Ok(_) => {
+                behaviour.traffic.record_out(topic, size);
+                Ok(())
+            }
+            Err(PublishError::InsufficientPeers) | Err(PublishError::Duplicate) => Ok(()),
+            Err(e) => Err(format!("Failed to publish on {}: {:?}", topic, e).into()),
+        }
+    }
+
+    /// Starts fetching a block, or joins a fetch already under way. `reply`
+    /// gets `None` once `MAX_BLOCK_REQUEST_ATTEMPTS` peers have failed.
+    fn request_block(&mut self, hash: Hash, reply: oneshot::Sender<Option<Block>>) {
+        match self.pending_block_requests.get_mut(&hash) {
+            Some(fetch) => fetch.waiters.push(reply),
+            None => {
+                self.pending_block_requests.insert(
+                    hash,
+                    BlockFetch {
+                        tried: HashSet::new(),
+                        waiters: vec![reply],
+                    },
+                );
+                self.retry_block_request(hash);
+            }
+        }
+    }
+
+    fn retry_block_request(&mut self, hash: Hash) {
+        let fetch = match self.pending_block_requests.get_mut(&hash) {
+            Some(fetch) => fetch,
+            None => return,
+        };
+        let peer = self
+            .handshaken_peers
+            .keys()
+            .find(|peer| !fetch.tried.contains(peer))
+            .copied();
+
+        match peer {
+            Some(peer) if fetch.tried.len() < MAX_BLOCK_REQUEST_ATTEMPTS => {
+                fetch.tried.insert(peer);
+                self.send_request(
+                    peer,
+                    SyncRequest::BlockByHash(hash),
+                    OutboundRequest::Block(hash),
+                );
+            }
+            _ => self.complete_block_request(&hash, None),
+        }
+    }
+
+    fn complete_block_request(&mut self, hash: &Hash, block: Option<Block>) {
+        if let Some(fetch) = self.pending_block_requests.remove(hash) {
+            for waiter in fetch.waiters {
+                let _ = waiter.send(block.clone());
+            }
+        }
+    }
+}
+
+/// The size of a direct message on the wire, for `TrafficStats`.
+fn encoded_size<T: Serialize>(message: &T) -> usize {
+    wire::encoded_size(message).unwrap_or_default()
+}
+
+/// What this node announces about itself. Takes the chain rather than the
+/// network so it can be built under a lock that is already held.
+async fn local_handshake(blockchain: &Blockchain) -> Handshake {
+    let head = blockchain.get_latest_block().await;
+    Handshake {
+        protocol_version: PROTOCOL_VERSION,
+        chain_id: blockchain.chain_id().to_string(),
+        genesis_hash: blockchain.genesis_hash(),
+        head: ChainStatus {
+            height: head.header.height,
+            head_hash: head.hash(),
+        },
+    }
 }
 
-#[derive(Debug)]
-enum FluxBehaviourEvent {
-    Floodsub(FloodsubEvent),
-    Mdns(MdnsEvent),
+/// Answers a peer's request from our chain.
+async fn serve_request(blockchain: &Blockchain, request: SyncRequest) -> SyncResponse {
+    match request {
+        SyncRequest::Status(_) => SyncResponse::Status(local_handshake(blockchain).await),
+        SyncRequest::BlockByHash(hash) => {
+            SyncResponse::Block(blockchain.get_block_by_hash(&hash).await.map(Box::new))
+        }
+        SyncRequest::BlocksByRange { start, count } => SyncResponse::Blocks(
+            blockchain
+                .get_blocks_by_range(start, count.min(MAX_BODIES_PER_REQUEST as u64))
+                .await,
+        ),
+        SyncRequest::Headers { start, count } => SyncResponse::Headers(
+            blockchain
+                .get_headers(start, count.min(MAX_HEADERS_PER_REQUEST))
+                .await,
+        ),
+        SyncRequest::BlockTransactions {
+            block_hash,
+            indexes,
+        } => {
+            let block = match blockchain.get_block_by_hash(&block_hash).await {
+                Some(block) => block,
+                None => return SyncResponse::Transactions(Vec::new()),
+            };
+            SyncResponse::Transactions(
+                indexes
+                    .iter()
+                    .filter_map(|index| block.transactions.get(*index as usize).cloned())
+                    .collect(),
+            )
+        }
+        SyncRequest::Transactions(hashes) => SyncResponse::Transactions(
+            blockchain
+                .get_mempool_transactions_by_hash(
+                    &hashes[..hashes.len().min(MAX_ANNOUNCEMENT_SIZE)],
+                )
+                .await,
+        ),
+        // Handled by the network, which tracks what each peer has seen.
+        SyncRequest::AnnounceTransactions(_) => SyncResponse::Ack,
+        SyncRequest::Snapshot(next_hash) => {
+            let offer = match blockchain.get_snapshot_before(&next_hash).await {
+                Some((snapshot, next_header)) => blockchain
+                    .get_block_by_hash(&snapshot.manifest.block_hash)
+                    .await
+                    .map(|block| {
+                        Box::new(SnapshotOffer {
+                            manifest: snapshot.manifest.clone(),
+                            block,
+                            next_header,
+                        })
+                    }),
+                None => None,
+            };
+            SyncResponse::Snapshot(offer)
+        }
+        SyncRequest::SnapshotChunk { height, index } => SyncResponse::SnapshotChunk(
+            blockchain
+                .get_snapshot(height)
+                .await
+                .and_then(|snapshot| snapshot.chunk(index).map(<[u8]>::to_vec)),
+        ),
+        SyncRequest::Bodies(hashes) => {
+            let mut blocks = Vec::new();
+            for hash in hashes.iter().take(MAX_BODIES_PER_REQUEST) {
+                if let Some(block) = blockchain.get_block_by_hash(hash).await {
+                    blocks.push(block);
+                }
+            }
+            SyncResponse::Blocks(blocks)
+        }
+    }
 }
@@ -0,0 +1,297 @@
+use crate::blockchain::block::Block;
+use crate::blockchain::transaction::Transaction;
+use crate::consensus::evidence::SignedHeader;
+use crate::crypto::{Hash, Hashable};
+use crate::network::wire;
+use crate::state::snapshot::MAX_SNAPSHOT_CHUNKS;
+use crate::state::SnapshotManifest;
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed};
+use libp2p::request_response::{ProtocolName, RequestResponseCodec};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io;
+use std::time::Duration;
+
+/// Bumped on any incompatible change to the messages below or to gossip.
+/// Every encoded message starts with it, so a peer on another version is
+/// refused before its bytes are interpreted.
+pub const PROTOCOL_VERSION: u8 = 5;
+/// How long a peer has to answer a request before it counts as failed.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Requests are small; anything larger is not from a well-behaved peer.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+const MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+/// The largest encoded block a node produces or accepts.
+pub const MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+pub const MAX_TRANSACTION_SIZE: usize = 4 * 1024;
+/// A block is the largest thing gossiped.
+pub const MAX_GOSSIP_SIZE: usize = MAX_BLOCK_SIZE;
+
+/// A node's view of its own chain, exchanged so peers know who to sync from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainStatus {
+    pub height: u64,
+    pub head_hash: Hash,
+}
+
+/// Sent by both sides when a connection opens, and again periodically to
+/// refresh the peer's head.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Handshake {
+    pub protocol_version: u8,
+    pub chain_id: String,
+    pub genesis_hash: Hash,
+    pub head: ChainStatus,
+}
+
+impl Handshake {
+    /// Whether a peer announcing `self` can talk to a node announcing `ours`.
+    pub fn check(&self, ours: &Handshake) -> Result<(), String> {
+        if self.protocol_version != ours.protocol_version {
+            return Err(format!(
+                "Protocol version {} is not {}",
+                self.protocol_version, ours.protocol_version
+            ));
+        }
+        if self.chain_id != ours.chain_id {
+            return Err(format!("Chain {} is not {}", self.chain_id, ours.chain_id));
+        }
+        if self.genesis_hash != ours.genesis_hash {
+            return Err("Genesis block differs".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Direct requests between two peers: the status handshake, block and
+/// header fetches and transaction announcements.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SyncRequest {
+    Status(Handshake),
+    /// A single block, for example the parent of a block we cannot attach.
+    BlockByHash(Hash),
+    /// Up to `count` consecutive blocks starting at height `start`.
+    BlocksByRange {
+        start: u64,
+        count: u64,
+    },
+    /// Up to `count` consecutive headers starting at height `start`.
+    Headers {
+        start: u64,
+        count: u64,
+    },
+    /// Full blocks for the given header hashes.
+    Bodies(Vec<Hash>),
+    /// The transactions at `indexes` in a block, to finish a compact block.
+    BlockTransactions {
+        block_hash: Hash,
+        indexes: Vec<u32>,
+    },
+    /// Hashes of transactions that entered the sender's mempool. The
+    /// receiver fetches the ones it lacks with `Transactions`.
+    AnnounceTransactions(Vec<Hash>),
+    /// Mempool transactions by hash. Ones no longer there are left out.
+    Transactions(Vec<Hash>),
+    /// The state snapshot whose root the header with this hash commits to:
+    /// the one taken after that header's parent.
+    Snapshot(Hash),
+    /// One chunk of the snapshot taken at `height`.
+    SnapshotChunk {
+        height: u64,
+        index: u32,
+    },
+}
+
+impl SyncRequest {
+    /// What the request is counted under in `TrafficStats`.
+    pub fn topic(&self) -> &'static str {
+        match self {
+            SyncRequest::Status(_) => "sync/status",
+            SyncRequest::BlockByHash(_) => "sync/block_by_hash",
+            SyncRequest::BlocksByRange { .. } => "sync/blocks_by_range",
+            SyncRequest::Headers { .. } => "sync/headers",
+            SyncRequest::Bodies(_) => "sync/bodies",
+            SyncRequest::BlockTransactions { .. } => "sync/block_transactions",
+            SyncRequest::AnnounceTransactions(_) => "sync/announce_transactions",
+            SyncRequest::Transactions(_) => "sync/transactions",
+            SyncRequest::Snapshot(_) => "sync/snapshot",
+            SyncRequest::SnapshotChunk { .. } => "sync/snapshot_chunk",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SyncResponse {
+    Status(Handshake),
+    Block(Option<Box<Block>>),
+    Blocks(Vec<Block>),
+    Headers(Vec<SignedHeader>),
+    Transactions(Vec<Transaction>),
+    /// Acknowledges an announcement.
+    Ack,
+    Snapshot(Option<Box<SnapshotOffer>>),
+    /// `None` once the snapshot has been dropped.
+    SnapshotChunk(Option<Vec<u8>>),
+}
+
+impl SyncResponse {
+    /// What the response is counted under in `TrafficStats`.
+    pub fn topic(&self) -> &'static str {
+        match self {
+            SyncResponse::Status(_) => "sync/status",
+            SyncResponse::Block(_) => "sync/block",
+            SyncResponse::Blocks(_) => "sync/blocks",
+            SyncResponse::Headers(_) => "sync/headers",
+            SyncResponse::Transactions(_) => "sync/transactions",
+            SyncResponse::Ack => "sync/ack",
+            SyncResponse::Snapshot(_) => "sync/snapshot",
+            SyncResponse::SnapshotChunk(_) => "sync/snapshot_chunk",
+        }
+    }
+
+    /// Checks each block and transaction against its own size limit. The
+    /// response as a whole is only bounded by `MAX_RESPONSE_SIZE`, which a
+    /// single oversized block or transaction could hide in.
+    pub fn check_size_limits(&self) -> Result<(), String> {
+        let blocks: &[Block] = match self {
+            SyncResponse::Block(Some(block)) => std::slice::from_ref(&**block),
+            SyncResponse::Blocks(blocks) => blocks,
+            SyncResponse::Snapshot(Some(offer)) => std::slice::from_ref(&offer.block),
+            SyncResponse::Transactions(transactions) => {
+                return match transactions
+                    .iter()
+                    .position(|transaction| !transaction.is_within_size_limit())
+                {
+                    Some(index) => Err(format!("Transaction {} exceeds the size limit", index)),
+                    None => Ok(()),
+                };
+            }
+            _ => return Ok(()),
+        };
+        match blocks.iter().find(|block| !block.is_within_size_limit()) {
+            Some(block) => Err(format!("Block {} exceeds the size limit", block.hash())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A snapshot's manifest with the blocks needed to trust it: the block it
+/// was taken after, and the header of the next block, whose state root the
+/// restored state must match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotOffer {
+    pub manifest: SnapshotManifest,
+    pub block: Block,
+    pub next_header: SignedHeader,
+}
+
+impl SnapshotOffer {
+    /// Checks the offer hangs together: both blocks are signed by their
+    /// producers and follow each other. The state root the second commits
+    /// to is checked once every chunk has arrived, and whether their
+    /// producers were entitled to produce them once the chain resumes.
+    pub fn verify(&self) -> Result<(), String> {
+        let manifest = &self.manifest;
+        if manifest.chunk_hashes.is_empty() || manifest.chunk_hashes.len() > MAX_SNAPSHOT_CHUNKS {
+            return Err(format!(
+                "Snapshot has {} chunks",
+                manifest.chunk_hashes.len()
+            ));
+        }
+        if self.block.hash() != manifest.block_hash || self.block.header.height != manifest.height {
+            return Err("Snapshot block does not match the manifest".to_string());
+        }
+        if !self.block.verify_signature() || !self.block.has_valid_body() {
+            return Err("Invalid snapshot block".to_string());
+        }
+        let next = &self.next_header;
+        if next.header.previous_hash != manifest.block_hash
+            || next.header.height != manifest.height + 1
+            || !next.header.verify_signature(&next.signature)
+        {
+            return Err("Invalid header after the snapshot block".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncProtocol;
+
+impl ProtocolName for SyncProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/flux/sync/1"
+    }
+}
+
+/// Length-prefixed `wire` framing for `SyncRequest` and `SyncResponse`.
+#[derive(Debug, Clone, Default)]
+pub struct SyncCodec;
+
+#[async_trait]
+impl RequestResponseCodec for SyncCodec {
+    type Protocol = SyncProtocol;
+    type Request = SyncRequest;
+    type Response = SyncResponse;
+
+    async fn read_request<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io, MAX_REQUEST_SIZE).await
+    }
+
+    async fn read_response<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io, MAX_RESPONSE_SIZE).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &SyncProtocol,
+        io: &mut T,
+        request: SyncRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &SyncProtocol,
+        io: &mut T,
+        response: SyncResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &response).await
+    }
+}
+
+async fn read_message<T, M>(io: &mut T, max_size: usize) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: DeserializeOwned,
+{
+    let bytes = read_length_prefixed(io, max_size).await?;
+    if bytes.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    wire::decode(&bytes, max_size).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+async fn write_message<T, M>(io: &mut T, message: &M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let bytes = wire::encode(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_length_prefixed(io, bytes).await?;
+    io.close().await
+}
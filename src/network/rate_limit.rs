@@ -0,0 +1,121 @@
+use crate::network::protocol::SyncRequest;
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// What a peer can send us unasked. Each kind has its own allowance, so a
+/// peer flooding one cannot use up what it is allowed of another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    BlockGossip,
+    EvidenceGossip,
+    /// Every validator votes twice per block.
+    VoteGossip,
+    Status,
+    /// Block, header and body requests.
+    BlockRequest,
+    TransactionAnnouncement,
+    TransactionRequest,
+    SnapshotRequest,
+}
+
+impl MessageKind {
+    pub fn of_request(request: &SyncRequest) -> Self {
+        match request {
+            SyncRequest::Status(_) => MessageKind::Status,
+            SyncRequest::BlockByHash(_)
+            | SyncRequest::BlocksByRange { .. }
+            | SyncRequest::Headers { .. }
+            | SyncRequest::Bodies(_)
+            | SyncRequest::BlockTransactions { .. } => MessageKind::BlockRequest,
+            SyncRequest::AnnounceTransactions(_) => MessageKind::TransactionAnnouncement,
+            SyncRequest::Transactions(_) => MessageKind::TransactionRequest,
+            SyncRequest::Snapshot(_) | SyncRequest::SnapshotChunk { .. } => {
+                MessageKind::SnapshotRequest
+            }
+        }
+    }
+
+    /// How many messages may arrive at once, and how many per second after
+    /// that. Well above what an honest peer sends: it makes one request at
+    /// a time while syncing and announces transactions in batches.
+    fn limit(self) -> (f64, f64) {
+        match self {
+            MessageKind::BlockGossip => (20.0, 5.0),
+            MessageKind::EvidenceGossip => (20.0, 2.0),
+            MessageKind::VoteGossip => (200.0, 50.0),
+            MessageKind::Status => (5.0, 1.0),
+            MessageKind::BlockRequest => (50.0, 20.0),
+            MessageKind::TransactionAnnouncement => (20.0, 10.0),
+            MessageKind::TransactionRequest => (20.0, 10.0),
+            MessageKind::SnapshotRequest => (10.0, 5.0),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per peer and message kind. A message is let through while
+/// its bucket has a token, and buckets refill at a steady rate.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: HashMap<(PeerId, MessageKind), Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes a token for a message of `kind` from `peer`, returning whether
+    /// the message is within its limit.
+    pub fn allow(&mut self, peer: PeerId, kind: MessageKind) -> bool {
+        let (burst, per_second) = kind.limit();
+        let now = Instant::now();
+        let bucket = self.buckets.entry((peer, kind)).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.buckets.retain(|(candidate, _), _| candidate != peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_apply_per_peer_and_message_kind() {
+        let mut limiter = RateLimiter::new();
+        let flooder = PeerId::random();
+        let other = PeerId::random();
+        let kind = MessageKind::of_request(&SyncRequest::Snapshot(Default::default()));
+
+        let allowed = (0..100)
+            .take_while(|_| limiter.allow(flooder, kind))
+            .count();
+        assert!(allowed > 0 && allowed < 100);
+        assert!(!limiter.allow(flooder, kind));
+
+        // Neither another peer nor another kind of message is held back.
+        assert!(limiter.allow(other, kind));
+        assert!(limiter.allow(flooder, MessageKind::BlockGossip));
+
+        limiter.remove_peer(&flooder);
+        assert!(limiter.allow(flooder, kind));
+    }
+}
@@ -0,0 +1,275 @@
+use crate::crypto::Hash;
+use libp2p::PeerId;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Most transaction hashes sent in, or accepted from, one announcement.
+pub const MAX_ANNOUNCEMENT_SIZE: usize = 256;
+/// How many hashes are remembered per peer. The oldest are forgotten first;
+/// by then the transactions have long been included or dropped.
+pub const MAX_KNOWN_TRANSACTIONS: usize = 16 * 1024;
+
+/// Transaction hashes a peer is known to have, because it announced them,
+/// sent them, asked for them or had them announced to it.
+#[derive(Debug, Default)]
+struct KnownTransactions {
+    hashes: HashSet<Hash>,
+    order: VecDeque<Hash>,
+}
+
+impl KnownTransactions {
+    fn insert(&mut self, hash: Hash) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > MAX_KNOWN_TRANSACTIONS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// A transaction being pulled from a peer that announced it, with the other
+/// peers that announced it meanwhile to fall back on.
+struct TransactionFetch {
+    peer: PeerId,
+    alternatives: Vec<PeerId>,
+}
+
+/// Spreads transactions by announcing their hashes in batches and pulling
+/// the ones we lack from a peer that announced them. Each peer is announced
+/// a transaction at most once, and never one it is known to have.
+///
+/// Like `SyncManager`, it does no I/O: the network feeds it announcements
+/// and fetch results and sends what it returns.
+#[derive(Default)]
+pub struct TransactionRelay {
+    known: HashMap<PeerId, KnownTransactions>,
+    /// Accepted since the last flush, waiting to be announced.
+    queue: Vec<Hash>,
+    fetching: HashMap<Hash, TransactionFetch>,
+}
+
+impl TransactionRelay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a transaction that entered our mempool for the next batch.
+    pub fn queue(&mut self, hash: Hash) {
+        self.queue.push(hash);
+    }
+
+    /// Records that `peer` has the transaction.
+    pub fn mark_known(&mut self, peer: PeerId, hash: Hash) {
+        self.known.entry(peer).or_default().insert(hash);
+    }
+
+    /// Forgets a peer. Fetches it was serving move to another announcer.
+    pub fn on_peer_disconnected(&mut self, peer: &PeerId) -> Vec<(PeerId, Vec<Hash>)> {
+        self.known.remove(peer);
+        for fetch in self.fetching.values_mut() {
+            fetch.alternatives.retain(|alternative| alternative != peer);
+        }
+        let hashes: Vec<Hash> = self
+            .fetching
+            .iter()
+            .filter(|(_, fetch)| fetch.peer == *peer)
+            .map(|(hash, _)| *hash)
+            .collect();
+        self.on_fetch_failed(peer, &hashes)
+    }
+
+    /// Takes the queued hashes and splits them into announcements for
+    /// `peers`, leaving out what each peer already has.
+    pub fn flush(&mut self, peers: impl IntoIterator<Item = PeerId>) -> Vec<(PeerId, Vec<Hash>)> {
+        if self.queue.is_empty() {
+            return Vec::new();
+        }
+        let queue = std::mem::take(&mut self.queue);
+
+        let mut announcements = Vec::new();
+        for peer in peers {
+            let known = self.known.entry(peer).or_default();
+            let hashes: Vec<Hash> = queue
+                .iter()
+                .filter(|hash| known.insert(**hash))
+                .copied()
+                .collect();
+            for batch in hashes.chunks(MAX_ANNOUNCEMENT_SIZE) {
+                announcements.push((peer, batch.to_vec()));
+            }
+        }
+        announcements
+    }
+
+    /// Handles hashes announced by `peer` and returns the ones to fetch from
+    /// it: those `is_new` accepts and that are not already being fetched
+    /// from someone else.
+    pub fn on_announcement(
+        &mut self,
+        peer: PeerId,
+        hashes: Vec<Hash>,
+        is_new: impl Fn(&Hash) -> bool,
+    ) -> Vec<Hash> {
+        let mut wanted = Vec::new();
+        for hash in hashes {
+            self.mark_known(peer, hash);
+            if !is_new(&hash) {
+                continue;
+            }
+            match self.fetching.get_mut(&hash) {
+                Some(fetch) => {
+                    if fetch.peer != peer && !fetch.alternatives.contains(&peer) {
+                        fetch.alternatives.push(peer);
+                    }
+                }
+                None => {
+                    self.fetching.insert(
+                        hash,
+                        TransactionFetch {
+                            peer,
+                            alternatives: Vec::new(),
+                        },
+                    );
+                    wanted.push(hash);
+                }
+            }
+        }
+        wanted
+    }
+
+    /// Whether `hash` was asked of `peer` and not yet delivered.
+    pub fn is_fetching_from(&self, peer: &PeerId, hash: &Hash) -> bool {
+        self.fetching
+            .get(hash)
+            .is_some_and(|fetch| fetch.peer == *peer)
+    }
+
+    /// A fetched transaction arrived.
+    pub fn on_fetched(&mut self, peer: PeerId, hash: Hash) {
+        self.fetching.remove(&hash);
+        self.mark_known(peer, hash);
+    }
+
+    /// `peer` did not deliver `hashes`. Each is asked of the next peer that
+    /// announced it, and given up on when there is none; the returned
+    /// requests are grouped by peer.
+    pub fn on_fetch_failed(&mut self, peer: &PeerId, hashes: &[Hash]) -> Vec<(PeerId, Vec<Hash>)> {
+        let mut retries: HashMap<PeerId, Vec<Hash>> = HashMap::new();
+        for hash in hashes {
+            let fetch = match self.fetching.get_mut(hash) {
+                Some(fetch) if fetch.peer == *peer => fetch,
+                _ => continue,
+            };
+            fetch.alternatives.retain(|alternative| alternative != peer);
+            if fetch.alternatives.is_empty() {
+                self.fetching.remove(hash);
+                continue;
+            }
+            fetch.peer = fetch.alternatives.remove(0);
+            retries.entry(fetch.peer).or_default().push(*hash);
+        }
+        retries.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u64) -> Hash {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&n.to_le_bytes());
+        Hash::from(bytes)
+    }
+
+    #[test]
+    fn a_transaction_is_announced_to_each_peer_once() {
+        let mut relay = TransactionRelay::new();
+        let (first, second) = (PeerId::random(), PeerId::random());
+        relay.queue(hash(1));
+        relay.queue(hash(2));
+
+        let announcements = relay.flush([first, second]);
+        assert_eq!(announcements.len(), 2);
+        assert!(announcements
+            .iter()
+            .all(|(_, hashes)| hashes == &vec![hash(1), hash(2)]));
+
+        // Queued again, say after a reorg, it goes to no one who had it.
+        relay.queue(hash(1));
+        assert!(relay.flush([first, second]).is_empty());
+    }
+
+    #[test]
+    fn a_transaction_is_not_announced_back_to_its_announcer() {
+        let mut relay = TransactionRelay::new();
+        let (announcer, other) = (PeerId::random(), PeerId::random());
+        assert_eq!(
+            relay.on_announcement(announcer, vec![hash(1)], |_| true),
+            vec![hash(1)]
+        );
+        relay.on_fetched(announcer, hash(1));
+        relay.queue(hash(1));
+
+        assert_eq!(
+            relay.flush([announcer, other]),
+            vec![(other, vec![hash(1)])]
+        );
+    }
+
+    #[test]
+    fn a_failed_fetch_falls_back_to_another_announcer() {
+        let mut relay = TransactionRelay::new();
+        let (first, second) = (PeerId::random(), PeerId::random());
+        assert_eq!(
+            relay.on_announcement(first, vec![hash(1)], |_| true),
+            vec![hash(1)]
+        );
+        // Already being fetched, so not asked of the second announcer yet.
+        assert!(relay
+            .on_announcement(second, vec![hash(1)], |_| true)
+            .is_empty());
+
+        assert_eq!(
+            relay.on_fetch_failed(&first, &[hash(1)]),
+            vec![(second, vec![hash(1)])]
+        );
+        assert!(relay.is_fetching_from(&second, &hash(1)));
+
+        // With no announcer left it is given up on.
+        assert!(relay.on_fetch_failed(&second, &[hash(1)]).is_empty());
+        assert!(!relay.is_fetching_from(&second, &hash(1)));
+    }
+
+    #[test]
+    fn a_disconnected_peers_fetches_move_to_another_announcer() {
+        let mut relay = TransactionRelay::new();
+        let (first, second) = (PeerId::random(), PeerId::random());
+        relay.on_announcement(first, vec![hash(1)], |_| true);
+        relay.on_announcement(second, vec![hash(1)], |_| true);
+
+        assert_eq!(
+            relay.on_peer_disconnected(&first),
+            vec![(second, vec![hash(1)])]
+        );
+    }
+
+    #[test]
+    fn known_transactions_forget_the_oldest_past_the_limit() {
+        let mut known = KnownTransactions::default();
+        for n in 0..=MAX_KNOWN_TRANSACTIONS as u64 {
+            assert!(known.insert(hash(n)));
+        }
+
+        assert_eq!(known.hashes.len(), MAX_KNOWN_TRANSACTIONS);
+        assert_eq!(known.order.len(), MAX_KNOWN_TRANSACTIONS);
+        assert!(!known.hashes.contains(&hash(0)));
+        assert!(!known.insert(hash(MAX_KNOWN_TRANSACTIONS as u64)));
+        // The forgotten hash counts as new again.
+        assert!(known.insert(hash(0)));
+    }
+}
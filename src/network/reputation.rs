@@ -0,0 +1,247 @@
+use crate::consensus::unix_now;
+use libp2p::PeerId;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Peers at or below this score are disconnected and banned.
+pub const BAN_THRESHOLD: i32 = -100;
+/// Good behaviour stops counting past this, so a long-lived peer cannot bank
+/// enough credit to misbehave for a while before it is banned.
+pub const MAX_REPUTATION: i32 = 100;
+pub const BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Something a peer did that changes how much we trust it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAction {
+    /// Sent gossip that could not be decoded.
+    Malformed,
+    /// Sent a block, transaction or sync response that failed validation.
+    Invalid,
+    /// Let a request fail or time out.
+    Unresponsive,
+    /// Sent more of one kind of message than its rate limit allows.
+    RateLimited,
+    /// Delivered a valid block or transaction we did not have yet.
+    Useful,
+}
+
+impl PeerAction {
+    fn score(self) -> i32 {
+        match self {
+            PeerAction::Malformed => -50,
+            PeerAction::Invalid => -50,
+            PeerAction::Unresponsive => -5,
+            PeerAction::RateLimited => -10,
+            PeerAction::Useful => 1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct BanEntry {
+    peer: String,
+    /// Unix time in seconds the ban is lifted.
+    until: u64,
+}
+
+/// Per-peer scores and the list of banned peers. Bans are written to disk as
+/// they change so they survive a restart.
+#[derive(Debug, Default)]
+pub struct Reputation {
+    scores: HashMap<PeerId, i32>,
+    bans: HashMap<PeerId, u64>,
+    path: Option<PathBuf>,
+}
+
+impl Reputation {
+    /// Loads the ban list stored at `path`, dropping bans that have expired.
+    /// A missing or unreadable file starts an empty list.
+    pub fn load(path: PathBuf) -> Self {
+        let bans = match fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice::<Vec<BanEntry>>(&bytes) {
+                Ok(entries) => {
+                    let now = unix_now();
+                    entries
+                        .into_iter()
+                        .filter(|entry| entry.until > now)
+                        .filter_map(|entry| Some((entry.peer.parse().ok()?, entry.until)))
+                        .collect()
+                }
+                Err(e) => {
+                    warn!("Ignoring unreadable ban list {}: {}", path.display(), e);
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                warn!("Failed to read ban list {}: {}", path.display(), e);
+                HashMap::new()
+            }
+        };
+
+        Reputation {
+            scores: HashMap::new(),
+            bans,
+            path: Some(path),
+        }
+    }
+
+    pub fn score(&self, peer: &PeerId) -> i32 {
+        self.scores.get(peer).copied().unwrap_or(0)
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.bans.contains_key(peer)
+    }
+
+    pub fn banned_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.bans.keys()
+    }
+
+    /// Applies `action` to the peer's score and returns whether this pushed
+    /// it to the ban threshold.
+    pub fn report(&mut self, peer: PeerId, action: PeerAction) -> bool {
+        if self.is_banned(&peer) {
+            return false;
+        }
+
+        let score = self.scores.entry(peer).or_insert(0);
+        *score = (*score + action.score()).min(MAX_REPUTATION);
+        if *score > BAN_THRESHOLD {
+            return false;
+        }
+
+        self.bans.insert(peer, unix_now() + BAN_DURATION.as_secs());
+        self.save();
+        true
+    }
+
+    /// Lifts bans that have run out and returns the peers they applied to.
+    /// Their scores start over.
+    pub fn expire_bans(&mut self) -> Vec<PeerId> {
+        let now = unix_now();
+        let expired: Vec<PeerId> = self
+            .bans
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(peer, _)| *peer)
+            .collect();
+        if expired.is_empty() {
+            return expired;
+        }
+
+        for peer in &expired {
+            self.bans.remove(peer);
+            self.scores.remove(peer);
+        }
+        self.save();
+        expired
+    }
+
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let entries: Vec<BanEntry> = self
+            .bans
+            .iter()
+            .map(|(peer, until)| BanEntry {
+                peer: peer.to_base58(),
+                until: *until,
+            })
+            .collect();
+
+        let result = serde_json::to_vec(&entries)
+            .map_err(io::Error::from)
+            .and_then(|bytes| {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::write(path, bytes)
+            });
+        if let Err(e) = result {
+            warn!("Failed to save ban list {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban_list_path() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("flux-reputation-{}", PeerId::random()))
+            .join("bans.json")
+    }
+
+    #[test]
+    fn a_peer_is_banned_when_its_score_reaches_the_threshold() {
+        let mut reputation = Reputation::default();
+        let peer = PeerId::random();
+
+        assert!(!reputation.report(peer, PeerAction::Invalid));
+        assert!(!reputation.is_banned(&peer));
+        assert!(reputation.report(peer, PeerAction::Invalid));
+        assert!(reputation.is_banned(&peer));
+        assert_eq!(reputation.score(&peer), BAN_THRESHOLD);
+        // Reports against a banned peer change nothing.
+        assert!(!reputation.report(peer, PeerAction::Invalid));
+        assert_eq!(reputation.score(&peer), BAN_THRESHOLD);
+    }
+
+    #[test]
+    fn good_behaviour_is_capped() {
+        let mut reputation = Reputation::default();
+        let peer = PeerId::random();
+        for _ in 0..2 * MAX_REPUTATION {
+            reputation.report(peer, PeerAction::Useful);
+        }
+
+        assert_eq!(reputation.score(&peer), MAX_REPUTATION);
+        for _ in 0..4 {
+            reputation.report(peer, PeerAction::Malformed);
+        }
+        assert!(reputation.is_banned(&peer));
+    }
+
+    #[test]
+    fn bans_survive_a_restart() {
+        let path = ban_list_path();
+        let peer = PeerId::random();
+        let mut reputation = Reputation::load(path.clone());
+        reputation.report(peer, PeerAction::Invalid);
+        reputation.report(peer, PeerAction::Invalid);
+
+        let reloaded = Reputation::load(path.clone());
+        assert!(reloaded.is_banned(&peer));
+        assert_eq!(reloaded.score(&peer), 0);
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn an_expired_ban_is_lifted_and_its_score_cleared() {
+        let path = ban_list_path();
+        let peer = PeerId::random();
+        let mut reputation = Reputation::load(path.clone());
+        reputation.report(peer, PeerAction::Invalid);
+        reputation.report(peer, PeerAction::Invalid);
+        assert!(reputation.expire_bans().is_empty());
+
+        reputation.bans.insert(peer, unix_now());
+        assert_eq!(reputation.expire_bans(), vec![peer]);
+        assert!(!reputation.is_banned(&peer));
+        assert_eq!(reputation.score(&peer), 0);
+        // The lifted ban is gone from disk too.
+        assert!(!Reputation::load(path.clone()).is_banned(&peer));
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
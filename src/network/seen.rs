@@ -0,0 +1,60 @@
+use crate::crypto::Hash;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a message hash is remembered. Gossip older than this has either
+/// reached every peer or been superseded.
+pub const SEEN_TTL: Duration = Duration::from_secs(120);
+
+/// Hashes of blocks, transactions and evidence this node has already handled,
+/// so a message arriving again from another peer is neither re-validated nor
+/// forwarded a second time.
+#[derive(Debug)]
+pub struct SeenCache {
+    ttl: Duration,
+    entries: HashMap<Hash, Instant>,
+}
+
+impl SeenCache {
+    pub fn new(ttl: Duration) -> Self {
+        SeenCache {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Records `hash` and returns whether it was new.
+    pub fn insert(&mut self, hash: Hash) -> bool {
+        let now = Instant::now();
+        match self.entries.insert(hash, now) {
+            Some(seen_at) => now.duration_since(seen_at) > self.ttl,
+            None => true,
+        }
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.entries
+            .get(hash)
+            .is_some_and(|seen_at| seen_at.elapsed() <= self.ttl)
+    }
+
+    /// Forgets hashes older than the TTL.
+    pub fn prune(&mut self) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, seen_at| seen_at.elapsed() <= ttl);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Default for SeenCache {
+    fn default() -> Self {
+        Self::new(SEEN_TTL)
+    }
+}
@@ -0,0 +1,383 @@
+use crate::crypto::{Hash, Hashable};
+use crate::network::protocol::{SnapshotOffer, SyncRequest};
+use libp2p::PeerId;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// How long to wait for any peer to offer a snapshot before falling back to
+/// replaying the chain from genesis.
+pub const SNAPSHOT_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Chunks requested from one peer at a time.
+const MAX_CHUNKS_IN_FLIGHT: usize = 2;
+
+struct Download {
+    offer: SnapshotOffer,
+    /// Peers that offered the checkpointed snapshot.
+    peers: Vec<PeerId>,
+    chunks: Vec<Option<Vec<u8>>>,
+    in_flight: HashMap<u32, PeerId>,
+}
+
+impl Download {
+    fn is_complete(&self) -> bool {
+        self.chunks.iter().all(Option::is_some)
+    }
+}
+
+enum Phase {
+    /// Asking peers for the checkpointed snapshot.
+    Discovering {
+        asked: HashSet<PeerId>,
+    },
+    Downloading(Box<Download>),
+    Done,
+}
+
+/// Fast sync for a node starting from scratch: instead of replaying every
+/// block, it downloads the state as of an epoch boundary in chunks, each
+/// checked against a manifest whose root a signed header commits to.
+/// Header-first sync takes over from the snapshot's block once it is
+/// restored, or from genesis if no peer offers the snapshot in time.
+///
+/// A node at genesis cannot tell whether the header's producer was entitled
+/// to sign it, and however many peers agree on a state they may all be one
+/// attacker. So only the snapshot committed to by a trusted checkpoint, the
+/// hash of that header, is downloaded; without one the sync is disabled.
+///
+/// Like `SyncManager`, it does no I/O.
+pub struct SnapshotSync {
+    phase: Phase,
+    /// The hash of the header committing to the snapshot's state root.
+    checkpoint: Hash,
+    /// Set once there are peers to ask. Only a download in progress keeps
+    /// the sync going past it.
+    deadline: Option<Instant>,
+}
+
+impl SnapshotSync {
+    /// A sync for the snapshot `checkpoint` commits to, which starts out
+    /// finished without one.
+    pub fn new(checkpoint: Option<Hash>) -> Self {
+        SnapshotSync {
+            phase: match checkpoint {
+                Some(_) => Phase::Discovering {
+                    asked: HashSet::new(),
+                },
+                None => Phase::Done,
+            },
+            checkpoint: checkpoint.unwrap_or_default(),
+            deadline: None,
+        }
+    }
+
+    /// Whether the snapshot is still being fetched. Block sync waits until
+    /// it is not.
+    pub fn is_active(&self) -> bool {
+        !matches!(self.phase, Phase::Done)
+    }
+
+    /// Stops trying, leaving the chain to sync from genesis.
+    pub fn abandon(&mut self) {
+        self.phase = Phase::Done;
+    }
+
+    /// Requests to send now: the offer query to peers not asked yet, or
+    /// missing chunks to peers with room for more.
+    pub fn next_requests(&mut self, peers: &[PeerId]) -> Vec<(PeerId, SyncRequest)> {
+        match &mut self.phase {
+            Phase::Discovering { asked } => {
+                if peers.is_empty() {
+                    return Vec::new();
+                }
+                let deadline = *self
+                    .deadline
+                    .get_or_insert_with(|| Instant::now() + SNAPSHOT_DISCOVERY_TIMEOUT);
+                if Instant::now() >= deadline {
+                    self.phase = Phase::Done;
+                    return Vec::new();
+                }
+                peers
+                    .iter()
+                    .filter(|peer| asked.insert(**peer))
+                    .map(|peer| (*peer, SyncRequest::Snapshot(self.checkpoint)))
+                    .collect()
+            }
+            Phase::Downloading(download) => {
+                let height = download.offer.manifest.height;
+                let mut missing = (0..download.chunks.len() as u32)
+                    .filter(|index| {
+                        download.chunks[*index as usize].is_none()
+                            && !download.in_flight.contains_key(index)
+                    })
+                    .collect::<Vec<_>>()
+                    .into_iter();
+                let mut requests = Vec::new();
+                for peer in download.peers.clone() {
+                    let busy = download
+                        .in_flight
+                        .values()
+                        .filter(|assigned| **assigned == peer)
+                        .count();
+                    for _ in busy..MAX_CHUNKS_IN_FLIGHT {
+                        let index = match missing.next() {
+                            Some(index) => index,
+                            None => break,
+                        };
+                        download.in_flight.insert(index, peer);
+                        requests.push((peer, SyncRequest::SnapshotChunk { height, index }));
+                    }
+                }
+                requests
+            }
+            Phase::Done => Vec::new(),
+        }
+    }
+
+    /// Handles a peer's answer to the offer query. The first offer of the
+    /// checkpointed snapshot starts the download; peers offering it later
+    /// help with it. An error means the offer was invalid or for another
+    /// snapshot than the one asked for.
+    pub fn on_offer(&mut self, peer: PeerId, offer: Option<SnapshotOffer>) -> Result<(), String> {
+        let offer = match offer {
+            Some(offer) => offer,
+            None => return Ok(()),
+        };
+        offer.verify()?;
+        if offer.next_header.header.hash() != self.checkpoint {
+            return Err("Snapshot does not match the checkpoint".to_string());
+        }
+
+        match &mut self.phase {
+            Phase::Discovering { .. } => {
+                let chunk_count = offer.manifest.chunk_hashes.len();
+                self.phase = Phase::Downloading(Box::new(Download {
+                    offer,
+                    peers: vec![peer],
+                    chunks: vec![None; chunk_count],
+                    in_flight: HashMap::new(),
+                }));
+            }
+            // The header fixes the state root, but not how the state was cut
+            // into chunks, so only offers with the same manifest can help.
+            Phase::Downloading(download) => {
+                if download.offer.manifest == offer.manifest && !download.peers.contains(&peer) {
+                    download.peers.push(peer);
+                }
+            }
+            Phase::Done => {}
+        }
+        Ok(())
+    }
+
+    /// Handles a chunk. `None` means the peer no longer has the snapshot; a
+    /// chunk that does not match the manifest is an error.
+    pub fn on_chunk(
+        &mut self,
+        peer: &PeerId,
+        index: u32,
+        chunk: Option<Vec<u8>>,
+    ) -> Result<(), String> {
+        let download = match &mut self.phase {
+            Phase::Downloading(download) => download,
+            _ => return Ok(()),
+        };
+        if download.in_flight.get(&index) != Some(peer) {
+            return Err("Unsolicited snapshot chunk".to_string());
+        }
+        download.in_flight.remove(&index);
+
+        match chunk {
+            Some(chunk) if download.offer.manifest.verify_chunk(index, &chunk) => {
+                download.chunks[index as usize] = Some(chunk);
+                Ok(())
+            }
+            Some(_) => {
+                self.drop_peer(peer);
+                Err(format!(
+                    "Snapshot chunk {} does not match the manifest",
+                    index
+                ))
+            }
+            None => {
+                self.drop_peer(peer);
+                Ok(())
+            }
+        }
+    }
+
+    /// A chunk request timed out or failed; the peer is not asked again.
+    pub fn on_request_failed(&mut self, peer: &PeerId, index: u32) {
+        if let Phase::Downloading(download) = &mut self.phase {
+            if download.in_flight.get(&index) == Some(peer) {
+                download.in_flight.remove(&index);
+            }
+        }
+        self.drop_peer(peer);
+    }
+
+    pub fn on_peer_disconnected(&mut self, peer: &PeerId) {
+        self.drop_peer(peer);
+    }
+
+    /// The offer and every chunk in order, once all have arrived. Ends the
+    /// sync either way, since a snapshot that fails to restore is not worth
+    /// retrying from the same peers.
+    pub fn take_complete(&mut self) -> Option<(SnapshotOffer, Vec<Vec<u8>>)> {
+        match &self.phase {
+            Phase::Downloading(download) if download.is_complete() => {}
+            _ => return None,
+        }
+        match std::mem::replace(&mut self.phase, Phase::Done) {
+            Phase::Downloading(download) => Some((
+                download.offer,
+                download.chunks.into_iter().flatten().collect(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Stops downloading from `peer`. With nobody left to download from,
+    /// the next round asks every peer for offers again.
+    fn drop_peer(&mut self, peer: &PeerId) {
+        let download = match &mut self.phase {
+            Phase::Downloading(download) => download,
+            _ => return,
+        };
+        download.peers.retain(|candidate| candidate != peer);
+        download.in_flight.retain(|_, assigned| assigned != peer);
+        if download.peers.is_empty() {
+            self.phase = Phase::Discovering {
+                asked: HashSet::new(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::Block;
+    use crate::consensus::evidence::SignedHeader;
+    use crate::crypto::{Hash, KeyPair};
+    use crate::state::snapshot::SnapshotManifest;
+
+    /// A well-formed offer for a snapshot at height 9, whose next header is
+    /// signed by `producer`.
+    fn offer(producer: &KeyPair) -> SnapshotOffer {
+        let block_producer = KeyPair::generate();
+        let mut block = Block::new(
+            Hash::default(),
+            Vec::new(),
+            9,
+            0,
+            27,
+            block_producer.public_key(),
+            0,
+        );
+        block.sign(&block_producer);
+        let mut next = Block::new(
+            block.hash(),
+            Vec::new(),
+            10,
+            0,
+            30,
+            producer.public_key(),
+            0,
+        );
+        next.sign(producer);
+        SnapshotOffer {
+            manifest: SnapshotManifest {
+                height: 9,
+                block_hash: block.hash(),
+                chunk_hashes: vec![Hash::default(); 3],
+            },
+            block,
+            next_header: SignedHeader {
+                header: next.header,
+                signature: next.signature,
+            },
+        }
+    }
+
+    fn is_downloading(sync: &SnapshotSync) -> bool {
+        matches!(sync.phase, Phase::Downloading(_))
+    }
+
+    /// A sync trusting the header `offer` commits to its state with.
+    fn sync_for(offer: &SnapshotOffer) -> SnapshotSync {
+        SnapshotSync::new(Some(offer.next_header.header.hash()))
+    }
+
+    #[test]
+    fn without_a_checkpoint_there_is_no_snapshot_sync() {
+        let mut sync = SnapshotSync::new(None);
+        assert!(!sync.is_active());
+        assert!(sync.next_requests(&[PeerId::random()]).is_empty());
+    }
+
+    #[test]
+    fn peers_are_asked_for_the_checkpointed_snapshot_and_one_offer_suffices() {
+        let honest = offer(&KeyPair::generate());
+        let mut sync = sync_for(&honest);
+        let peers = [PeerId::random(), PeerId::random()];
+        let requests = sync.next_requests(&peers);
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|(_, request)| matches!(
+            request,
+            SyncRequest::Snapshot(hash) if *hash == honest.next_header.header.hash()
+        )));
+
+        sync.on_offer(peers[0], Some(honest.clone())).unwrap();
+        assert!(is_downloading(&sync));
+        sync.on_offer(peers[1], Some(honest)).unwrap();
+        let requested: HashSet<PeerId> = sync
+            .next_requests(&peers)
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect();
+        assert_eq!(requested.len(), 2);
+    }
+
+    #[test]
+    fn a_snapshot_other_than_the_checkpointed_one_is_refused() {
+        let honest = offer(&KeyPair::generate());
+        let mut forged = honest.clone();
+        let forger = KeyPair::generate();
+        let mut next = Block::new(
+            honest.manifest.block_hash,
+            Vec::new(),
+            10,
+            0,
+            30,
+            forger.public_key(),
+            0,
+        )
+        .with_state_root(Hash::from([7; 32]));
+        next.sign(&forger);
+        forged.next_header = SignedHeader {
+            header: next.header,
+            signature: next.signature,
+        };
+
+        // However many peers offer it.
+        let mut sync = sync_for(&honest);
+        for _ in 0..10 {
+            assert!(sync
+                .on_offer(PeerId::random(), Some(forged.clone()))
+                .is_err());
+        }
+        assert!(!is_downloading(&sync));
+    }
+
+    #[test]
+    fn the_download_restarts_once_every_peer_serving_it_has_left() {
+        let honest = offer(&KeyPair::generate());
+        let mut sync = sync_for(&honest);
+        let leaving = PeerId::random();
+        sync.on_offer(leaving, Some(honest)).unwrap();
+        sync.on_peer_disconnected(&leaving);
+
+        assert!(!is_downloading(&sync));
+        assert_eq!(sync.next_requests(&[leaving]).len(), 1);
+    }
+}
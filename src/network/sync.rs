@@ -0,0 +1,810 @@
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::consensus::dpos::{epoch_of, EPOCH_LENGTH};
+use crate::consensus::evidence::SignedHeader;
+use crate::crypto::{Hash, Hashable};
+use crate::network::protocol::{ChainStatus, SyncRequest, SyncResponse, REQUEST_TIMEOUT};
+use libp2p::PeerId;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Instant;
+
+/// Most headers a peer is asked for, or will serve, in one request.
+pub const MAX_HEADERS_PER_REQUEST: u64 = 128;
+/// Most block bodies a peer is asked for, or will serve, in one request.
+pub const MAX_BODIES_PER_REQUEST: usize = 16;
+
+struct PeerSync {
+    status: ChainStatus,
+    in_flight: Option<SyncRequest>,
+    /// Set after a failed request so the retry goes to a different peer.
+    backoff_until: Option<Instant>,
+    /// Its chain left ours too far back to follow, or left the one being
+    /// downloaded. Not asked for headers until it announces a new head.
+    diverged: bool,
+}
+
+impl PeerSync {
+    fn is_idle(&self) -> bool {
+        self.in_flight.is_none() && self.backoff_until.is_none()
+    }
+}
+
+/// Where a longer chain being downloaded leaves the local one.
+struct Fork {
+    height: u64,
+    hash: Hash,
+    /// How far the branch is downloaded before it replaces the local chain.
+    target: u64,
+}
+
+/// Earlier headers asked of a peer whose chain did not link to the local
+/// head, to find where the two chains meet.
+struct AncestorSearch {
+    peer: PeerId,
+    start: u64,
+    end: u64,
+}
+
+/// Header-first catch-up. Headers are downloaded from one peer at a time and
+/// checked to form a signed chain on top of the local head, produced by the
+/// scheduled validators; bodies for those headers are then spread over every
+/// peer that has them and handed back in height order for import. Producers
+/// can only be checked in the epoch of the next block to import, whose
+/// schedule is known, so headers are fetched one epoch at a time.
+///
+/// A peer whose headers do not link to the local head is on a fork, not
+/// misbehaving. Its earlier headers are fetched back to where the chains
+/// meet, within the epoch of the local head so both share a schedule, and
+/// its branch from there is downloaded in full and handed back for the
+/// chain to reorganize onto.
+///
+/// The manager does no I/O: the network feeds it peer statuses and responses
+/// and sends the requests it returns.
+pub struct SyncManager {
+    peers: HashMap<PeerId, PeerSync>,
+    local_height: u64,
+    local_head: Hash,
+    /// Validated headers above the local head, or above the fork point
+    /// while following a fork, by height.
+    headers: BTreeMap<u64, SignedHeader>,
+    /// Downloaded bodies waiting for their turn to be imported.
+    bodies: HashMap<Hash, Block>,
+    fork: Option<Fork>,
+    ancestor_search: Option<AncestorSearch>,
+}
+
+impl SyncManager {
+    pub fn new(local_height: u64, local_head: Hash) -> Self {
+        SyncManager {
+            peers: HashMap::new(),
+            local_height,
+            local_head,
+            headers: BTreeMap::new(),
+            bodies: HashMap::new(),
+            fork: None,
+            ancestor_search: None,
+        }
+    }
+
+    pub fn on_peer_status(&mut self, peer: PeerId, status: ChainStatus) {
+        match self.peers.get_mut(&peer) {
+            Some(state) => {
+                if state.status.head_hash != status.head_hash {
+                    state.diverged = false;
+                }
+                state.status = status;
+            }
+            None => {
+                self.peers.insert(
+                    peer,
+                    PeerSync {
+                        status,
+                        in_flight: None,
+                        backoff_until: None,
+                        diverged: false,
+                    },
+                );
+            }
+        }
+    }
+
+    /// The head `peer` last announced.
+    pub fn peer_status(&self, peer: &PeerId) -> Option<&ChainStatus> {
+        self.peers.get(peer).map(|state| &state.status)
+    }
+
+    /// Forgets a peer; anything it was fetching is requested again elsewhere.
+    pub fn on_peer_disconnected(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+        if self
+            .ancestor_search
+            .as_ref()
+            .is_some_and(|search| search.peer == *peer)
+        {
+            self.ancestor_search = None;
+        }
+    }
+
+    /// A request to `peer` timed out or its connection failed. What it was
+    /// fetching goes to other peers first.
+    pub fn on_request_failed(&mut self, peer: &PeerId) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.in_flight = None;
+            state.backoff_until = Some(Instant::now() + REQUEST_TIMEOUT);
+        }
+    }
+
+    /// Moves the local head after blocks were imported, by sync or otherwise.
+    /// Downloaded headers that no longer extend the head are discarded, and
+    /// so is a fork being followed.
+    pub fn set_local_head(&mut self, height: u64, head_hash: Hash) {
+        self.local_height = height;
+        self.local_head = head_hash;
+        self.fork = None;
+        self.ancestor_search = None;
+        self.headers = self.headers.split_off(&(height + 1));
+
+        let extends_head = self
+            .headers
+            .values()
+            .next()
+            .is_none_or(|first| first.header.previous_hash == self.local_head);
+        if !extends_head {
+            self.headers.clear();
+        }
+        let headers = &self.headers;
+        self.bodies.retain(|hash, block| {
+            headers
+                .get(&block.header.height)
+                .is_some_and(|signed| signed.header.hash() == *hash)
+        });
+    }
+
+    /// Highest head height announced by any peer.
+    pub fn target_height(&self) -> u64 {
+        self.peers
+            .values()
+            .map(|peer| peer.status.height)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        self.target_height() > self.local_height
+    }
+
+    /// Requests to send now: at most one header request, plus body requests
+    /// for every idle peer that has blocks still missing.
+    pub fn next_requests(&mut self) -> Vec<(PeerId, SyncRequest)> {
+        let now = Instant::now();
+        for peer in self.peers.values_mut() {
+            if peer.backoff_until.is_some_and(|until| until <= now) {
+                peer.backoff_until = None;
+            }
+        }
+
+        let mut requests = Vec::new();
+
+        let (base_height, _) = self.base();
+        let header_tip = self
+            .headers
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or(base_height);
+        let fetching_headers = self
+            .peers
+            .values()
+            .any(|peer| matches!(peer.in_flight, Some(SyncRequest::Headers { .. })));
+        let checkable_tip = self.checkable_tip();
+        if let Some(search) = &self.ancestor_search {
+            if !fetching_headers && self.peers.get(&search.peer).is_some_and(PeerSync::is_idle) {
+                let count = search.end + 1 - search.start;
+                requests.push((
+                    search.peer,
+                    SyncRequest::Headers {
+                        start: search.start,
+                        count,
+                    },
+                ));
+            }
+        } else if !fetching_headers && header_tip < checkable_tip {
+            if let Some((peer, height)) = self.idle_peer_above(header_tip) {
+                let start = header_tip + 1;
+                let count = (height.min(checkable_tip) - header_tip).min(MAX_HEADERS_PER_REQUEST);
+                requests.push((peer, SyncRequest::Headers { start, count }));
+            }
+        }
+
+        let mut requested: HashSet<Hash> = self
+            .peers
+            .values()
+            .filter_map(|peer| match &peer.in_flight {
+                Some(SyncRequest::Bodies(hashes)) => Some(hashes.iter().copied()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        let idle: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.is_idle())
+            .map(|(peer_id, _)| *peer_id)
+            .filter(|peer_id| requests.iter().all(|(assigned, _)| assigned != peer_id))
+            .collect();
+        for peer_id in idle {
+            let peer_height = self.peers[&peer_id].status.height;
+            let hashes: Vec<Hash> = self
+                .headers
+                .range(..=peer_height)
+                .map(|(_, signed)| signed.header.hash())
+                .filter(|hash| !self.bodies.contains_key(hash) && !requested.contains(hash))
+                .take(MAX_BODIES_PER_REQUEST)
+                .collect();
+            if hashes.is_empty() {
+                continue;
+            }
+            requested.extend(hashes.iter().copied());
+            requests.push((peer_id, SyncRequest::Bodies(hashes)));
+        }
+
+        for (peer_id, request) in &requests {
+            if let Some(peer) = self.peers.get_mut(peer_id) {
+                peer.in_flight = Some(request.clone());
+            }
+        }
+        requests
+    }
+
+    /// Checks a peer's answer against what it was asked for and keeps the
+    /// parts that are valid. `is_scheduled_producer` tells whether a header
+    /// comes from the validator consensus scheduled for it, and
+    /// `local_hash_at` gives the hash of the local block at a height. An
+    /// error means the peer sent something it should not have; being on
+    /// another chain is not one.
+    pub fn on_response(
+        &mut self,
+        peer: &PeerId,
+        response: SyncResponse,
+        is_scheduled_producer: impl Fn(&BlockHeader) -> bool,
+        local_hash_at: impl Fn(u64) -> Option<Hash>,
+    ) -> Result<(), String> {
+        let request = self
+            .peers
+            .get_mut(peer)
+            .and_then(|state| state.in_flight.take())
+            .ok_or("Unsolicited sync response")?;
+
+        match (request, response) {
+            (SyncRequest::Headers { start, count }, SyncResponse::Headers(headers)) => {
+                let searching = self
+                    .ancestor_search
+                    .as_ref()
+                    .is_some_and(|search| search.peer == *peer && search.start == start);
+                if searching {
+                    self.on_ancestor_headers(
+                        peer,
+                        count,
+                        headers,
+                        is_scheduled_producer,
+                        local_hash_at,
+                    )
+                } else {
+                    self.on_headers(peer, start, count, headers, is_scheduled_producer)
+                }
+            }
+            (SyncRequest::Bodies(hashes), SyncResponse::Blocks(blocks)) => {
+                self.on_bodies(&hashes, blocks)
+            }
+            _ => Err("Sync response does not match the request".to_string()),
+        }
+    }
+
+    /// Removes and returns the blocks that can be imported next, in order.
+    /// A fork's branch is only returned once downloaded in full, and starts
+    /// below the local head. The caller should report the new head with
+    /// `set_local_head`.
+    pub fn drain_ready(&mut self) -> Vec<Block> {
+        let (base_height, _) = self.base();
+        let mut tip = base_height;
+        while let Some(signed) = self.headers.get(&(tip + 1)) {
+            if !self.bodies.contains_key(&signed.header.hash()) {
+                break;
+            }
+            tip += 1;
+        }
+        if self.fork.as_ref().is_some_and(|fork| tip < fork.target) {
+            return Vec::new();
+        }
+
+        (base_height + 1..=tip)
+            .filter_map(|height| self.bodies.remove(&self.headers[&height].header.hash()))
+            .collect()
+    }
+
+    /// The block downloaded headers build on: the local head, or where a
+    /// fork being followed leaves the local chain.
+    fn base(&self) -> (u64, Hash) {
+        match &self.fork {
+            Some(fork) => (fork.height, fork.hash),
+            None => (self.local_height, self.local_head),
+        }
+    }
+
+    fn checkable_tip(&self) -> u64 {
+        checkable_tip_above(self.base().0)
+    }
+
+    /// The lowest block a fork may leave the local chain at: the last block
+    /// before the local head's epoch, so both chains share its schedule.
+    fn fork_floor(&self) -> u64 {
+        (epoch_of(self.local_height) * EPOCH_LENGTH).saturating_sub(1)
+    }
+
+    fn on_headers(
+        &mut self,
+        peer: &PeerId,
+        start: u64,
+        count: u64,
+        headers: Vec<SignedHeader>,
+        is_scheduled_producer: impl Fn(&BlockHeader) -> bool,
+    ) -> Result<(), String> {
+        if headers.is_empty() || headers.len() as u64 > count {
+            return Err("Wrong number of headers".to_string());
+        }
+        check_headers(&headers, start, &is_scheduled_producer)?;
+
+        let (base_height, base_hash) = self.base();
+        let previous = match start.checked_sub(1) {
+            Some(height) if height == base_height => base_hash,
+            Some(height) => self
+                .headers
+                .get(&height)
+                .map(|signed| signed.header.hash())
+                .ok_or("Headers do not extend the downloaded chain")?,
+            None => return Err("Headers cannot start at genesis".to_string()),
+        };
+        if headers[0].header.previous_hash != previous {
+            // The peer is on another chain. Unless a fork is already being
+            // followed, look for where it meets ours; otherwise leave it be.
+            if self.fork.is_none() && start == self.local_height + 1 {
+                let end = self.local_height;
+                let start = self
+                    .fork_floor()
+                    .max(end.saturating_sub(MAX_HEADERS_PER_REQUEST))
+                    + 1;
+                if start <= end {
+                    self.ancestor_search = Some(AncestorSearch {
+                        peer: *peer,
+                        start,
+                        end,
+                    });
+                    return Ok(());
+                }
+            }
+            self.mark_diverged(peer);
+            return Ok(());
+        }
+
+        for signed in headers {
+            self.headers.insert(signed.header.height, signed);
+        }
+        Ok(())
+    }
+
+    /// Handles earlier headers of a peer on another chain. Where the highest
+    /// of them matches a local block, its branch above that is kept and
+    /// followed; if none do, the search moves further back, and gives up on
+    /// the peer at the fork floor.
+    fn on_ancestor_headers(
+        &mut self,
+        peer: &PeerId,
+        count: u64,
+        headers: Vec<SignedHeader>,
+        is_scheduled_producer: impl Fn(&BlockHeader) -> bool,
+        local_hash_at: impl Fn(u64) -> Option<Hash>,
+    ) -> Result<(), String> {
+        let search = self
+            .ancestor_search
+            .take()
+            .ok_or("No ancestor search in progress")?;
+        if headers.is_empty() || headers.len() as u64 > count {
+            return Err("Wrong number of headers".to_string());
+        }
+        check_headers(&headers, search.start, &is_scheduled_producer)?;
+
+        let matched = headers
+            .iter()
+            .rposition(|signed| local_hash_at(signed.header.height) == Some(signed.header.hash()));
+        let (ancestor, hash, branch) = match matched {
+            Some(index) => {
+                let signed = &headers[index];
+                (
+                    signed.header.height,
+                    signed.header.hash(),
+                    &headers[index + 1..],
+                )
+            }
+            None if local_hash_at(search.start - 1) == Some(headers[0].header.previous_hash) => (
+                search.start - 1,
+                headers[0].header.previous_hash,
+                &headers[..],
+            ),
+            None if search.start > self.fork_floor() + 1 => {
+                let end = search.start - 1;
+                let start = self
+                    .fork_floor()
+                    .max(end.saturating_sub(MAX_HEADERS_PER_REQUEST))
+                    + 1;
+                self.ancestor_search = Some(AncestorSearch {
+                    peer: search.peer,
+                    start,
+                    end,
+                });
+                return Ok(());
+            }
+            None => {
+                self.mark_diverged(peer);
+                return Ok(());
+            }
+        };
+        if ancestor >= self.local_height {
+            return Ok(());
+        }
+
+        // The peer announced a longer chain; its branch is followed as far
+        // as its producers can be checked.
+        let peer_height = self.peers.get(peer).map_or(0, |state| state.status.height);
+        let target = peer_height
+            .min(checkable_tip_above(ancestor))
+            .max(self.local_height);
+        self.headers = branch
+            .iter()
+            .map(|signed| (signed.header.height, signed.clone()))
+            .collect();
+        self.bodies.clear();
+        self.fork = Some(Fork {
+            height: ancestor,
+            hash,
+            target,
+        });
+        Ok(())
+    }
+
+    fn mark_diverged(&mut self, peer: &PeerId) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.diverged = true;
+        }
+    }
+
+    fn on_bodies(&mut self, requested: &[Hash], blocks: Vec<Block>) -> Result<(), String> {
+        for block in blocks {
+            let hash = block.hash();
+            if !requested.contains(&hash) {
+                return Err("Block was not requested".to_string());
+            }
+            let signed = self
+                .headers
+                .get(&block.header.height)
+                .filter(|signed| signed.header.hash() == hash)
+                .ok_or("Block does not match a downloaded header")?;
+            if !block.has_valid_body() || block.signature != signed.signature {
+                return Err("Block body does not match its header".to_string());
+            }
+            self.bodies.insert(hash, block);
+        }
+        Ok(())
+    }
+
+    fn idle_peer_above(&self, height: u64) -> Option<(PeerId, u64)> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.is_idle() && !peer.diverged && peer.status.height > height)
+            .max_by_key(|(_, peer)| peer.status.height)
+            .map(|(peer_id, peer)| (*peer_id, peer.status.height))
+    }
+}
+
+/// The last height of the epoch of the block after `height`, the highest
+/// header above it whose producer consensus can check.
+fn checkable_tip_above(height: u64) -> u64 {
+    (epoch_of(height + 1) + 1) * EPOCH_LENGTH - 1
+}
+
+/// Checks that `headers` form a chain from `start`, each signed by its
+/// scheduled producer. Where the first links to is up to the caller.
+fn check_headers(
+    headers: &[SignedHeader],
+    start: u64,
+    is_scheduled_producer: impl Fn(&BlockHeader) -> bool,
+) -> Result<(), String> {
+    let mut previous = None;
+    for (signed, height) in headers.iter().zip(start..) {
+        if signed.header.height != height {
+            return Err("Headers are not consecutive".to_string());
+        }
+        if previous.is_some_and(|previous| signed.header.previous_hash != previous) {
+            return Err("Header does not link to its parent".to_string());
+        }
+        if !is_scheduled_producer(&signed.header) {
+            return Err("Header is not from its scheduled producer".to_string());
+        }
+        if !signed.header.verify_signature(&signed.signature) {
+            return Err("Invalid header signature".to_string());
+        }
+        previous = Some(signed.header.hash());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{KeyPair, PublicKey};
+
+    /// `count` empty blocks by `producer` on top of `previous` at `height`.
+    fn blocks(producer: &KeyPair, previous: Hash, height: u64, count: u64) -> Vec<Block> {
+        let mut previous = previous;
+        (height..height + count)
+            .map(|height| {
+                let mut block = Block::new(
+                    previous,
+                    Vec::new(),
+                    height,
+                    epoch_of(height),
+                    height * 3,
+                    producer.public_key(),
+                    0,
+                );
+                block.sign(producer);
+                previous = block.hash();
+                block
+            })
+            .collect()
+    }
+
+    fn signed(blocks: &[Block]) -> Vec<SignedHeader> {
+        blocks
+            .iter()
+            .map(|block| SignedHeader {
+                header: block.header.clone(),
+                signature: block.signature.clone(),
+            })
+            .collect()
+    }
+
+    /// `count` headers by `producer` on top of `previous` at `height`.
+    fn headers(producer: &KeyPair, previous: Hash, height: u64, count: u64) -> Vec<SignedHeader> {
+        signed(&blocks(producer, previous, height, count))
+    }
+
+    fn scheduled(producer: &PublicKey) -> impl Fn(&BlockHeader) -> bool + '_ {
+        move |header| &header.validator == producer
+    }
+
+    /// A local chain made of `blocks` on top of genesis.
+    fn local_chain(blocks: &[Block]) -> impl Fn(u64) -> Option<Hash> + '_ {
+        move |height| match height {
+            0 => Some(Hash::default()),
+            height => blocks.get(height as usize - 1).map(Block::hash),
+        }
+    }
+
+    /// A manager at `local_height` with one peer at `peer_height`, and the
+    /// header request it sends that peer.
+    fn syncing(local_height: u64, peer_height: u64) -> (SyncManager, PeerId, SyncRequest) {
+        let mut sync = SyncManager::new(local_height, Hash::default());
+        let peer = PeerId::random();
+        sync.on_peer_status(
+            peer,
+            ChainStatus {
+                height: peer_height,
+                head_hash: Hash::default(),
+            },
+        );
+        let mut requests = sync.next_requests();
+        assert_eq!(requests.len(), 1);
+        let (to, request) = requests.remove(0);
+        assert_eq!(to, peer);
+        (sync, peer, request)
+    }
+
+    #[test]
+    fn valid_headers_are_kept_and_their_bodies_requested() {
+        let producer = KeyPair::generate();
+        let (mut sync, peer, request) = syncing(0, 5);
+        assert!(matches!(
+            request,
+            SyncRequest::Headers { start: 1, count: 5 }
+        ));
+
+        let response = SyncResponse::Headers(headers(&producer, Hash::default(), 1, 5));
+        sync.on_response(
+            &peer,
+            response,
+            scheduled(&producer.public_key()),
+            local_chain(&[]),
+        )
+        .unwrap();
+
+        let requests = sync.next_requests();
+        assert!(matches!(&requests[..], [(_, SyncRequest::Bodies(hashes))] if hashes.len() == 5));
+    }
+
+    #[test]
+    fn headers_that_do_not_link_are_rejected() {
+        let producer = KeyPair::generate();
+        let (mut sync, peer, _) = syncing(0, 5);
+
+        let mut chain = headers(&producer, Hash::default(), 1, 2);
+        chain.extend(headers(&producer, Hash::default(), 3, 3));
+        let response = SyncResponse::Headers(chain);
+        assert!(sync
+            .on_response(
+                &peer,
+                response,
+                scheduled(&producer.public_key()),
+                local_chain(&[])
+            )
+            .is_err());
+        assert!(sync.drain_ready().is_empty());
+    }
+
+    #[test]
+    fn headers_with_a_bad_signature_are_rejected() {
+        let producer = KeyPair::generate();
+        let (mut sync, peer, _) = syncing(0, 5);
+
+        let mut chain = headers(&producer, Hash::default(), 1, 5);
+        chain[2].signature = KeyPair::generate().sign(chain[2].header.hash().as_bytes());
+        let response = SyncResponse::Headers(chain);
+        assert!(sync
+            .on_response(
+                &peer,
+                response,
+                scheduled(&producer.public_key()),
+                local_chain(&[])
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn headers_from_an_unscheduled_producer_are_rejected() {
+        let producer = KeyPair::generate();
+        let impostor = KeyPair::generate();
+        let (mut sync, peer, _) = syncing(0, 5);
+
+        // Correctly linked and signed, but by a validator not due to produce.
+        let response = SyncResponse::Headers(headers(&impostor, Hash::default(), 1, 5));
+        assert!(sync
+            .on_response(
+                &peer,
+                response,
+                scheduled(&producer.public_key()),
+                local_chain(&[])
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn headers_are_fetched_one_epoch_at_a_time() {
+        let producer = KeyPair::generate();
+        let local_height = EPOCH_LENGTH - 10;
+        let (mut sync, peer, request) = syncing(local_height, 10 * EPOCH_LENGTH);
+        assert!(matches!(
+            request,
+            SyncRequest::Headers { start, count: 9 } if start == local_height + 1
+        ));
+
+        let response =
+            SyncResponse::Headers(headers(&producer, Hash::default(), local_height + 1, 9));
+        sync.on_response(
+            &peer,
+            response,
+            scheduled(&producer.public_key()),
+            local_chain(&[]),
+        )
+        .unwrap();
+
+        // Only bodies are asked for until the head reaches the epoch's end.
+        let requests = sync.next_requests();
+        assert!(requests
+            .iter()
+            .all(|(_, request)| matches!(request, SyncRequest::Bodies(_))));
+    }
+
+    #[test]
+    fn a_peer_on_a_fork_is_followed_back_to_where_the_chains_meet() {
+        let producer = KeyPair::generate();
+        let rival = KeyPair::generate();
+        let anyone = |_: &BlockHeader| true;
+        // We have 1..=5; the peer shares 1..=3 and has its own 4..=8.
+        let ours = blocks(&producer, Hash::default(), 1, 5);
+        let mut theirs = ours[..3].to_vec();
+        theirs.extend(blocks(&rival, ours[2].hash(), 4, 5));
+
+        let mut sync = SyncManager::new(5, ours[4].hash());
+        let peer = PeerId::random();
+        sync.on_peer_status(
+            peer,
+            ChainStatus {
+                height: 8,
+                head_hash: theirs[7].hash(),
+            },
+        );
+        assert!(matches!(
+            &sync.next_requests()[..],
+            [(_, SyncRequest::Headers { start: 6, count: 3 })]
+        ));
+
+        // Headers that do not link to our head are not held against the peer.
+        let response = SyncResponse::Headers(signed(&theirs[5..]));
+        sync.on_response(&peer, response, anyone, local_chain(&ours))
+            .unwrap();
+        assert!(matches!(
+            &sync.next_requests()[..],
+            [(_, SyncRequest::Headers { start: 1, count: 5 })]
+        ));
+        let response = SyncResponse::Headers(signed(&theirs[..5]));
+        sync.on_response(&peer, response, anyone, local_chain(&ours))
+            .unwrap();
+
+        // The branch above block 3 is downloaded from there.
+        assert!(matches!(
+            &sync.next_requests()[..],
+            [(_, SyncRequest::Headers { start: 6, count: 3 })]
+        ));
+        let response = SyncResponse::Headers(signed(&theirs[5..]));
+        sync.on_response(&peer, response, anyone, local_chain(&ours))
+            .unwrap();
+        let requests = sync.next_requests();
+        assert!(matches!(&requests[..], [(_, SyncRequest::Bodies(hashes))] if hashes.len() == 5));
+
+        // Only handed back once the whole branch is there.
+        let response = SyncResponse::Blocks(theirs[3..7].to_vec());
+        sync.on_response(&peer, response, anyone, local_chain(&ours))
+            .unwrap();
+        assert!(sync.drain_ready().is_empty());
+        sync.next_requests();
+        let response = SyncResponse::Blocks(theirs[7..].to_vec());
+        sync.on_response(&peer, response, anyone, local_chain(&ours))
+            .unwrap();
+        let branch = sync.drain_ready();
+        assert_eq!(branch.len(), 5);
+        assert_eq!(branch[0].header.previous_hash, ours[2].hash());
+        assert_eq!(branch[4].hash(), theirs[7].hash());
+    }
+
+    #[test]
+    fn a_fork_older_than_the_current_epoch_is_not_followed() {
+        let producer = KeyPair::generate();
+        let anyone = |_: &BlockHeader| true;
+        let local_height = EPOCH_LENGTH + 5;
+        let local_head = Hash::from([1; 32]);
+        let local_hash_at = |_| Some(local_head);
+
+        let mut sync = SyncManager::new(local_height, local_head);
+        let peer = PeerId::random();
+        sync.on_peer_status(
+            peer,
+            ChainStatus {
+                height: local_height + 5,
+                head_hash: Hash::default(),
+            },
+        );
+        sync.next_requests();
+        let response =
+            SyncResponse::Headers(headers(&producer, Hash::default(), local_height + 1, 5));
+        sync.on_response(&peer, response, anyone, local_hash_at)
+            .unwrap();
+
+        // The search stops at the start of the epoch.
+        assert!(matches!(
+            &sync.next_requests()[..],
+            [(_, SyncRequest::Headers { start, count: 6 })] if *start == EPOCH_LENGTH
+        ));
+        let response = SyncResponse::Headers(headers(&producer, Hash::default(), EPOCH_LENGTH, 6));
+        sync.on_response(&peer, response, anyone, local_hash_at)
+            .unwrap();
+        assert!(sync.next_requests().is_empty());
+    }
+}
@@ -0,0 +1,104 @@
+use crate::network::protocol::PROTOCOL_VERSION;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Varint integers and length-prefixed sequences, so a 64-byte signature
+/// takes 65 bytes. The same value always encodes to the same bytes, and
+/// length prefixes are checked against the input size before anything is
+/// allocated for them.
+fn options(max_size: usize) -> impl Options {
+    bincode::DefaultOptions::new().with_limit(max_size as u64)
+}
+
+/// Encodes `value` for the wire, prefixed with the protocol version.
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![PROTOCOL_VERSION];
+    bincode::DefaultOptions::new()
+        .serialize_into(&mut bytes, value)
+        .map_err(|e| format!("Failed to encode message: {}", e))?;
+    Ok(bytes)
+}
+
+/// The length of `encode`'s output, without encoding.
+pub fn encoded_size<T: Serialize>(value: &T) -> Result<usize, String> {
+    bincode::DefaultOptions::new()
+        .serialized_size(value)
+        .map(|size| size as usize + 1)
+        .map_err(|e| format!("Failed to size message: {}", e))
+}
+
+/// Decodes a value written by `encode`. Input longer than `max_size`, from
+/// another protocol version, or with trailing bytes is refused.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8], max_size: usize) -> Result<T, String> {
+    if bytes.len() > max_size {
+        return Err(format!(
+            "Message of {} bytes exceeds the {} byte limit",
+            bytes.len(),
+            max_size
+        ));
+    }
+    match bytes.split_first() {
+        Some((&PROTOCOL_VERSION, body)) => options(max_size)
+            .deserialize(body)
+            .map_err(|e| format!("Malformed message: {}", e)),
+        Some((version, _)) => Err(format!("Unsupported protocol version {}", version)),
+        None => Err("Empty message".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_SIZE: usize = 1024;
+
+    fn value() -> (u64, Vec<u8>, String) {
+        (300, vec![1, 2, 3], "flux".to_string())
+    }
+
+    #[test]
+    fn a_value_round_trips_to_the_same_bytes() {
+        let bytes = encode(&value()).unwrap();
+
+        assert_eq!(bytes, encode(&value()).unwrap());
+        assert_eq!(bytes.len(), encoded_size(&value()).unwrap());
+        assert_eq!(bytes[0], PROTOCOL_VERSION);
+        let decoded: (u64, Vec<u8>, String) = decode(&bytes, MAX_SIZE).unwrap();
+        assert_eq!(decoded, value());
+    }
+
+    #[test]
+    fn another_protocol_version_is_refused() {
+        let mut bytes = encode(&value()).unwrap();
+        bytes[0] = PROTOCOL_VERSION.wrapping_add(1);
+
+        assert!(decode::<(u64, Vec<u8>, String)>(&bytes, MAX_SIZE).is_err());
+        assert!(decode::<(u64, Vec<u8>, String)>(&[], MAX_SIZE).is_err());
+    }
+
+    #[test]
+    fn trailing_bytes_are_refused() {
+        let mut bytes = encode(&value()).unwrap();
+        bytes.push(0);
+
+        assert!(decode::<(u64, Vec<u8>, String)>(&bytes, MAX_SIZE).is_err());
+    }
+
+    #[test]
+    fn oversized_input_is_refused() {
+        let bytes = encode(&vec![0u8; MAX_SIZE]).unwrap();
+
+        assert!(decode::<Vec<u8>>(&bytes, MAX_SIZE).is_err());
+    }
+
+    #[test]
+    fn a_length_prefix_past_the_limit_is_refused_before_allocating() {
+        // A `Vec<u8>` whose varint length, marker 253 and eight bytes,
+        // claims u64::MAX elements in a ten byte message.
+        let mut bytes = vec![PROTOCOL_VERSION, 253];
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+
+        assert!(decode::<Vec<u8>>(&bytes, MAX_SIZE).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use crate::crypto::{merkle_root, Hash, PublicKey};
+use crate::state::snapshot::encoded_hash;
+use crate::state::world_state::Account;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Accounts are spread over this many buckets by the first byte of their key.
+const BUCKET_COUNT: usize = 256;
+
+/// Every account, bucketed so the state root only rehashes the buckets a
+/// block touched rather than every account.
+#[derive(Debug, Clone)]
+pub(crate) struct AccountTable {
+    buckets: Vec<HashMap<PublicKey, Account>>,
+    bucket_hashes: Vec<Hash>,
+    /// Buckets changed since their hash was last computed.
+    dirty: BTreeSet<usize>,
+}
+
+fn bucket_of(public_key: &PublicKey) -> usize {
+    public_key.as_bytes()[0] as usize % BUCKET_COUNT
+}
+
+impl AccountTable {
+    pub fn new() -> Self {
+        AccountTable {
+            buckets: vec![HashMap::new(); BUCKET_COUNT],
+            bucket_hashes: vec![Hash::default(); BUCKET_COUNT],
+            dirty: (0..BUCKET_COUNT).collect(),
+        }
+    }
+
+    pub fn get(&self, public_key: &PublicKey) -> Option<&Account> {
+        self.buckets[bucket_of(public_key)].get(public_key)
+    }
+
+    /// The account of `public_key`, created empty if it has none yet.
+    pub fn get_or_create(&mut self, public_key: &PublicKey) -> &mut Account {
+        let bucket = bucket_of(public_key);
+        self.dirty.insert(bucket);
+        self.buckets[bucket]
+            .entry(public_key.clone())
+            .or_insert(Account {
+                balance: 0,
+                nonce: 0,
+            })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PublicKey, &Account)> {
+        self.buckets.iter().flatten()
+    }
+
+    /// Merkle root over the hashes of every bucket's canonical encoding,
+    /// rehashing only the buckets changed since the last call.
+    pub fn root(&mut self) -> Result<Hash, String> {
+        for bucket in std::mem::take(&mut self.dirty) {
+            let sorted: BTreeMap<_, _> = self.buckets[bucket].iter().collect();
+            self.bucket_hashes[bucket] = encoded_hash(&sorted)?;
+        }
+        Ok(merkle_root(self.bucket_hashes.iter().copied()))
+    }
+}
+
+impl FromIterator<(PublicKey, Account)> for AccountTable {
+    fn from_iter<I: IntoIterator<Item = (PublicKey, Account)>>(accounts: I) -> Self {
+        let mut table = AccountTable::new();
+        for (public_key, account) in accounts {
+            table.buckets[bucket_of(&public_key)].insert(public_key, account);
+        }
+        table
+    }
+}
@@ -0,0 +1,100 @@
+use crate::consensus::dpos::{EPOCH_LENGTH, VALIDATOR_COUNT};
+use crate::crypto::PublicKey;
+use crate::state::snapshot::sorted_map;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// Number of a validator's most recent scheduled slots that are tracked.
+pub const LIVENESS_WINDOW: usize = 50;
+/// A validator missing more than this many slots in its window is jailed.
+pub const MAX_MISSED_SLOTS: usize = 25;
+/// Number of blocks a jailed validator must wait before it may unjail.
+pub const JAIL_PERIOD: u64 = EPOCH_LENGTH;
+/// Only the skipped slots in the last round before a block count as missed.
+/// A longer gap means the whole network stalled, and charging every
+/// validator for it would jail them all.
+pub const MISSED_SLOT_LOOKBACK: u64 = VALIDATOR_COUNT as u64;
+/// Jailing stops once this few validators are left in the stake table, the
+/// fewest PBFT can tolerate a faulty one with.
+pub const MIN_ACTIVE_VALIDATORS: usize = 4;
+
+/// Sliding window of produced/missed outcomes over each validator's own
+/// scheduled slots.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LivenessTracker {
+    #[serde(serialize_with = "sorted_map")]
+    windows: HashMap<PublicKey, VecDeque<bool>>,
+}
+
+impl LivenessTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the outcome of one of `validator`'s slots and returns how many
+    /// slots it missed within the window.
+    pub fn record(&mut self, validator: &PublicKey, produced: bool) -> usize {
+        let window = self.windows.entry(validator.clone()).or_default();
+        if window.len() == LIVENESS_WINDOW {
+            window.pop_front();
+        }
+        window.push_back(produced);
+        self.missed(validator)
+    }
+
+    pub fn missed(&self, validator: &PublicKey) -> usize {
+        self.windows.get(validator).map_or(0, |window| {
+            window.iter().filter(|produced| !**produced).count()
+        })
+    }
+
+    pub fn reset(&mut self, validator: &PublicKey) {
+        self.windows.remove(validator);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KeyPair;
+
+    #[test]
+    fn only_misses_within_the_window_count() {
+        let validator = KeyPair::generate().public_key();
+        let mut tracker = LivenessTracker::new();
+
+        for _ in 0..10 {
+            tracker.record(&validator, false);
+        }
+        assert_eq!(tracker.record(&validator, true), 10);
+
+        // The early misses slide out as the validator keeps producing.
+        for _ in 0..LIVENESS_WINDOW - 11 {
+            tracker.record(&validator, true);
+        }
+        assert_eq!(tracker.missed(&validator), 10);
+        assert_eq!(tracker.record(&validator, true), 9);
+        for _ in 0..9 {
+            tracker.record(&validator, true);
+        }
+        assert_eq!(tracker.missed(&validator), 0);
+    }
+
+    #[test]
+    fn validators_are_tracked_separately_and_reset_on_their_own() {
+        let absent = KeyPair::generate().public_key();
+        let present = KeyPair::generate().public_key();
+        let mut tracker = LivenessTracker::new();
+
+        for _ in 0..3 {
+            tracker.record(&absent, false);
+            tracker.record(&present, true);
+        }
+        assert_eq!(tracker.missed(&absent), 3);
+        assert_eq!(tracker.missed(&present), 0);
+
+        tracker.reset(&absent);
+        assert_eq!(tracker.missed(&absent), 0);
+        assert_eq!(tracker.record(&absent, false), 1);
+    }
+}
@@ -1,3 +1,14 @@
+pub(crate) mod accounts;
+pub mod liveness;
+pub mod randao;
+pub mod rewards;
+pub mod snapshot;
+pub mod staking;
 pub mod world_state;
 
+pub use liveness::LivenessTracker;
+pub use randao::RandaoState;
+pub use rewards::RewardSchedule;
+pub use snapshot::{Snapshot, SnapshotManifest};
+pub use staking::StakingState;
 pub use world_state::WorldState;
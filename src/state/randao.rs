@@ -0,0 +1,155 @@
+use crate::blockchain::block::Block;
+use crate::consensus::randomness::{randao_commitment, randao_mix};
+use crate::crypto::{Hash, PublicKey};
+use crate::state::snapshot::sorted_map;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RandaoCommitment {
+    /// Index of the reveal this commitment binds the validator to.
+    pub index: u64,
+    pub commitment: Hash,
+}
+
+/// Commit-reveal randomness beacon. Every block reveals the secret its
+/// producer committed to in its previous block and commits to the next one,
+/// so a producer cannot pick its reveal after seeing the current mix.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RandaoState {
+    #[serde(serialize_with = "sorted_map")]
+    commitments: HashMap<PublicKey, RandaoCommitment>,
+    mix: Hash,
+}
+
+impl RandaoState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn mix(&self) -> Hash {
+        self.mix
+    }
+
+    /// Index of the reveal `producer` must include in its next block, or
+    /// `None` if it has never committed and so reveals nothing.
+    pub fn next_reveal_index(&self, producer: &PublicKey) -> Option<u64> {
+        self.commitments
+            .get(producer)
+            .map(|commitment| commitment.index)
+    }
+
+    /// The beacon after applying `reveal` from `producer`.
+    pub fn next_mix(&self, producer: &PublicKey, reveal: &Hash) -> Hash {
+        if self.commitments.contains_key(producer) {
+            randao_mix(&self.mix, reveal)
+        } else {
+            self.mix
+        }
+    }
+
+    /// Checks the block's reveal against its producer's last commitment and
+    /// that the header carries the resulting mix, then records the new
+    /// commitment.
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), String> {
+        let header = &block.header;
+        let index = match self.commitments.get(&header.validator) {
+            Some(previous) => {
+                if randao_commitment(&header.randao_reveal) != previous.commitment {
+                    return Err("RANDAO reveal does not match commitment".to_string());
+                }
+                previous.index + 1
+            }
+            None => {
+                if header.randao_reveal != Hash::default() {
+                    return Err("Unexpected RANDAO reveal without commitment".to_string());
+                }
+                0
+            }
+        };
+
+        let mix = self.next_mix(&header.validator, &header.randao_reveal);
+        if header.randomness != mix {
+            return Err("Invalid randomness".to_string());
+        }
+
+        self.mix = mix;
+        self.commitments.insert(
+            header.validator.clone(),
+            RandaoCommitment {
+                index,
+                commitment: header.randao_commitment,
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::randomness::randao_reveal;
+    use crate::crypto::KeyPair;
+
+    /// A block from `keypair` that reveals its `index`-th secret, or nothing
+    /// if it has not committed yet, and commits to the next one.
+    fn block(randao: &RandaoState, keypair: &KeyPair, reveal: Hash, next_index: u64) -> Block {
+        let producer = keypair.public_key();
+        let commitment = randao_commitment(&randao_reveal(keypair, next_index));
+        Block::new(Hash::default(), Vec::new(), 1, 0, 0, producer.clone(), 0).with_randao(
+            reveal,
+            commitment,
+            randao.next_mix(&producer, &reveal),
+        )
+    }
+
+    #[test]
+    fn reveals_must_open_the_previous_commitment() {
+        let keypair = KeyPair::generate();
+        let producer = keypair.public_key();
+        let mut randao = RandaoState::new();
+
+        randao
+            .apply_block(&block(&randao, &keypair, Hash::default(), 0))
+            .unwrap();
+        assert_eq!(randao.next_reveal_index(&producer), Some(0));
+        // Nothing was revealed, so the mix is unchanged.
+        assert_eq!(randao.mix(), Hash::default());
+
+        let wrong = block(&randao, &keypair, randao_reveal(&keypair, 1), 1);
+        assert!(randao.apply_block(&wrong).is_err());
+
+        let reveal = randao_reveal(&keypair, 0);
+        randao
+            .apply_block(&block(&randao, &keypair, reveal, 1))
+            .unwrap();
+        assert_eq!(randao.mix(), randao_mix(&Hash::default(), &reveal));
+        assert_eq!(randao.next_reveal_index(&producer), Some(1));
+    }
+
+    #[test]
+    fn a_reveal_without_a_commitment_is_rejected() {
+        let keypair = KeyPair::generate();
+        let mut randao = RandaoState::new();
+        let reveal = randao_reveal(&keypair, 0);
+        assert!(randao
+            .apply_block(&block(&randao, &keypair, reveal, 1))
+            .is_err());
+        assert_eq!(randao.next_reveal_index(&keypair.public_key()), None);
+    }
+
+    #[test]
+    fn the_header_must_carry_the_resulting_mix() {
+        let keypair = KeyPair::generate();
+        let mut randao = RandaoState::new();
+        randao
+            .apply_block(&block(&randao, &keypair, Hash::default(), 0))
+            .unwrap();
+
+        let mut forged = block(&randao, &keypair, randao_reveal(&keypair, 0), 1);
+        forged.header.randomness = Hash::from([1; 32]);
+        assert!(randao.apply_block(&forged).is_err());
+        assert_eq!(randao.mix(), Hash::default());
+        assert_eq!(randao.next_reveal_index(&keypair.public_key()), Some(0));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::consensus::dpos::BLOCK_TIME;
+use serde::{Deserialize, Serialize};
+
+const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+const BASIS_POINTS: u128 = 10_000;
+
+/// Issuance rules fixed at genesis. Every block mints a flat `block_reward`
+/// plus its share of the current year's inflation, so a year of full slots
+/// grows the supply by roughly the annual inflation rate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RewardSchedule {
+    pub block_reward: u64,
+    /// Annual inflation in the first year, in basis points of total supply.
+    pub initial_inflation_bps: u64,
+    /// How much the annual rate drops each year, in basis points.
+    pub inflation_decay_bps: u64,
+    /// The annual rate never drops below this, in basis points.
+    pub min_inflation_bps: u64,
+}
+
+impl Default for RewardSchedule {
+    fn default() -> Self {
+        RewardSchedule {
+            block_reward: 0,
+            initial_inflation_bps: 700,
+            inflation_decay_bps: 100,
+            min_inflation_bps: 200,
+        }
+    }
+}
+
+impl RewardSchedule {
+    pub fn inflation_bps(&self, year: u64) -> u64 {
+        self.initial_inflation_bps
+            .saturating_sub(self.inflation_decay_bps.saturating_mul(year))
+            .max(self.min_inflation_bps.min(self.initial_inflation_bps))
+    }
+
+    /// Amount minted by a block produced `elapsed` seconds after genesis.
+    pub fn reward_at(&self, elapsed: u64, total_supply: u64) -> u64 {
+        let blocks_per_year = (SECONDS_PER_YEAR / BLOCK_TIME.as_secs()) as u128;
+        let year = elapsed / SECONDS_PER_YEAR;
+        let inflation = total_supply as u128 * self.inflation_bps(year) as u128
+            / BASIS_POINTS
+            / blocks_per_year;
+        self.block_reward.saturating_add(inflation as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCKS_PER_YEAR: u64 = SECONDS_PER_YEAR / BLOCK_TIME.as_secs();
+
+    #[test]
+    fn inflation_decays_yearly_down_to_the_floor() {
+        let schedule = RewardSchedule::default();
+        assert_eq!(schedule.inflation_bps(0), 700);
+        assert_eq!(schedule.inflation_bps(1), 600);
+        assert_eq!(schedule.inflation_bps(5), 200);
+        assert_eq!(schedule.inflation_bps(6), 200);
+        assert_eq!(schedule.inflation_bps(u64::MAX), 200);
+    }
+
+    #[test]
+    fn the_floor_never_raises_inflation_above_its_start() {
+        let schedule = RewardSchedule {
+            initial_inflation_bps: 100,
+            ..RewardSchedule::default()
+        };
+        assert_eq!(schedule.inflation_bps(0), 100);
+        assert_eq!(schedule.inflation_bps(3), 100);
+    }
+
+    #[test]
+    fn a_year_of_blocks_mints_the_annual_inflation() {
+        let schedule = RewardSchedule::default();
+        let supply = 1_000_000 * BLOCKS_PER_YEAR;
+        let reward = schedule.reward_at(0, supply);
+        assert_eq!(reward * BLOCKS_PER_YEAR, supply * 700 / 10_000);
+        assert_eq!(schedule.reward_at(SECONDS_PER_YEAR - 1, supply), reward);
+        assert_eq!(
+            schedule.reward_at(SECONDS_PER_YEAR, supply) * BLOCKS_PER_YEAR,
+            supply * 600 / 10_000
+        );
+    }
+
+    #[test]
+    fn the_flat_reward_is_added_to_inflation() {
+        let schedule = RewardSchedule {
+            block_reward: 5,
+            ..RewardSchedule::default()
+        };
+        assert_eq!(schedule.reward_at(0, 0), 5);
+        let supply = 1_000_000 * BLOCKS_PER_YEAR;
+        assert_eq!(
+            schedule.reward_at(0, supply),
+            5 + RewardSchedule::default().reward_at(0, supply)
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use crate::crypto::{Hash, PublicKey};
+use crate::state::liveness::LivenessTracker;
+use crate::state::randao::RandaoState;
+use crate::state::rewards::RewardSchedule;
+use crate::state::staking::StakingState;
+use crate::state::world_state::{Account, WorldState};
+use bincode::Options;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// Size of every snapshot chunk but the last.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+/// Largest snapshot a node will download, so a peer cannot make us store an
+/// endless stream of chunks.
+pub const MAX_SNAPSHOT_CHUNKS: usize = 4096;
+
+/// Serializes a map in key order, so equal states encode to equal bytes.
+pub(crate) fn sorted_map<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    S: Serializer,
+{
+    serializer.collect_map(map.iter().collect::<BTreeMap<_, _>>())
+}
+
+pub(crate) fn sorted_nested_map<K, L, V, S>(
+    map: &HashMap<K, HashMap<L, V>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    L: Ord + Serialize,
+    V: Serialize,
+    S: Serializer,
+{
+    serializer.collect_map(
+        map.iter()
+            .map(|(key, inner)| (key, inner.iter().collect::<BTreeMap<_, _>>()))
+            .collect::<BTreeMap<_, _>>(),
+    )
+}
+
+pub(crate) fn sorted_set<K, S>(set: &HashSet<K>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    S: Serializer,
+{
+    serializer.collect_seq(set.iter().collect::<BTreeSet<_>>())
+}
+
+/// Everything in `WorldState`, in the canonical form the state root is
+/// computed over.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StateSnapshot {
+    #[serde(serialize_with = "sorted_map")]
+    pub accounts: HashMap<PublicKey, Account>,
+    pub staking: StakingState,
+    pub liveness: LivenessTracker,
+    pub randao: RandaoState,
+    pub rewards: RewardSchedule,
+    pub genesis_time: u64,
+    pub total_supply: u64,
+    pub height: u64,
+    pub last_block_hash: Hash,
+}
+
+impl StateSnapshot {
+    /// Plain bincode rather than `wire`: the encoding is committed to in
+    /// block headers, so it must not change with the network protocol.
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        bincode::DefaultOptions::new()
+            .serialize(self)
+            .map_err(|e| format!("Failed to encode state: {}", e))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, String> {
+        bincode::DefaultOptions::new()
+            .with_limit((MAX_SNAPSHOT_CHUNKS * CHUNK_SIZE) as u64)
+            .deserialize(bytes)
+            .map_err(|e| format!("Malformed state snapshot: {}", e))
+    }
+}
+
+/// Hash of a value's canonical encoding, which the state root is built from.
+/// Plain bincode for the same reason as `StateSnapshot::encode`.
+pub(crate) fn encoded_hash<T: Serialize + ?Sized>(value: &T) -> Result<Hash, String> {
+    let bytes = bincode::DefaultOptions::new()
+        .serialize(value)
+        .map_err(|e| format!("Failed to encode state: {}", e))?;
+    Ok(Hash::from(blake3::hash(&bytes).as_bytes()))
+}
+
+fn chunk_hash(chunk: &[u8]) -> Hash {
+    Hash::from(blake3::hash(chunk).as_bytes())
+}
+
+/// Lists a snapshot's chunks, so each can be checked as it arrives. The
+/// restored state as a whole is checked against the state root committed to
+/// by the block after `height`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    /// Height of the last block applied to the state.
+    pub height: u64,
+    pub block_hash: Hash,
+    pub chunk_hashes: Vec<Hash>,
+}
+
+impl SnapshotManifest {
+    pub fn verify_chunk(&self, index: u32, chunk: &[u8]) -> bool {
+        self.chunk_hashes
+            .get(index as usize)
+            .is_some_and(|hash| *hash == chunk_hash(chunk))
+    }
+
+    /// Rebuilds the world state from every chunk in order and checks it has
+    /// the `state_root` a block committed to.
+    pub fn restore(&self, chunks: Vec<Vec<u8>>, state_root: Hash) -> Result<WorldState, String> {
+        if chunks.len() != self.chunk_hashes.len() {
+            return Err(format!(
+                "Expected {} chunks, got {}",
+                self.chunk_hashes.len(),
+                chunks.len()
+            ));
+        }
+        for (index, chunk) in chunks.iter().enumerate() {
+            if !self.verify_chunk(index as u32, chunk) {
+                return Err(format!("Chunk {} does not match the manifest", index));
+            }
+        }
+
+        let state = WorldState::from_snapshot(StateSnapshot::decode(&chunks.concat())?)?;
+        if state.height() != self.height || state.get_last_block_hash() != self.block_hash {
+            return Err("Snapshot is not of the block in its manifest".to_string());
+        }
+        if state.state_root() != state_root {
+            return Err("Snapshot does not match the committed state root".to_string());
+        }
+        Ok(state)
+    }
+}
+
+/// The world state after a block, kept so peers can start from it instead of
+/// replaying the chain.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub manifest: SnapshotManifest,
+    chunks: Vec<Vec<u8>>,
+}
+
+impl Snapshot {
+    pub fn new(state: &WorldState) -> Result<Self, String> {
+        let bytes = state.to_snapshot().encode()?;
+        let chunks: Vec<Vec<u8>> = bytes.chunks(CHUNK_SIZE).map(<[u8]>::to_vec).collect();
+        Ok(Snapshot {
+            manifest: SnapshotManifest {
+                height: state.height(),
+                block_hash: state.get_last_block_hash(),
+                chunk_hashes: chunks.iter().map(|chunk| chunk_hash(chunk)).collect(),
+            },
+            chunks,
+        })
+    }
+
+    /// Decodes the state the snapshot was taken of. Only for snapshots this
+    /// node took itself; ones from peers go through `SnapshotManifest::restore`.
+    pub fn state(&self) -> Result<WorldState, String> {
+        WorldState::from_snapshot(StateSnapshot::decode(&self.chunks.concat())?)
+    }
+
+    pub fn chunk(&self, index: u32) -> Option<&[u8]> {
+        self.chunks.get(index as usize).map(Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::GenesisConfig;
+    use crate::crypto::KeyPair;
+
+    fn state_with_accounts(count: usize) -> WorldState {
+        let balances = (0..count)
+            .map(|balance| (KeyPair::generate().public_key(), balance as u64))
+            .collect();
+        WorldState::from_genesis(&GenesisConfig::new("test", 0, balances, Vec::new())).unwrap()
+    }
+
+    fn chunks(snapshot: &Snapshot) -> Vec<Vec<u8>> {
+        (0..snapshot.manifest.chunk_hashes.len() as u32)
+            .map(|index| snapshot.chunk(index).unwrap().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn a_restored_snapshot_has_the_original_state_root() {
+        let state = state_with_accounts(8_000);
+        let snapshot = Snapshot::new(&state).unwrap();
+        assert!(snapshot.manifest.chunk_hashes.len() > 1);
+
+        let restored = snapshot
+            .manifest
+            .restore(chunks(&snapshot), state.state_root())
+            .unwrap();
+        assert_eq!(restored.state_root(), state.state_root());
+        assert_eq!(restored.total_supply(), state.total_supply());
+    }
+
+    #[test]
+    fn a_snapshot_of_another_state_is_rejected() {
+        let state = state_with_accounts(10);
+        let snapshot = Snapshot::new(&state).unwrap();
+        let other_root = state_with_accounts(10).state_root();
+        assert!(snapshot
+            .manifest
+            .restore(chunks(&snapshot), other_root)
+            .is_err());
+    }
+
+    #[test]
+    fn tampered_chunks_are_rejected() {
+        let state = state_with_accounts(10);
+        let snapshot = Snapshot::new(&state).unwrap();
+        let mut chunks = chunks(&snapshot);
+        chunks[0][0] ^= 1;
+        assert!(!snapshot.manifest.verify_chunk(0, &chunks[0]));
+        assert!(snapshot
+            .manifest
+            .restore(chunks, state.state_root())
+            .is_err());
+    }
+}
@@ -0,0 +1,500 @@
+use crate::consensus::dpos::EPOCH_LENGTH;
+use crate::crypto::{verify_proof_of_possession, BlsPublicKey, BlsSignature, PublicKey};
+use crate::state::snapshot::{sorted_map, sorted_nested_map, sorted_set};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Number of blocks undelegated tokens stay locked before they can be claimed.
+pub const UNBONDING_PERIOD: u64 = 2 * EPOCH_LENGTH;
+/// Share of bonded and unbonding stake burned for double signing, in basis points.
+pub const DOUBLE_SIGN_SLASH_BPS: u64 = 500;
+/// The highest commission a validator can take, all of its block rewards.
+pub const MAX_COMMISSION_BPS: u64 = 10_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnbondingEntry {
+    pub delegator: PublicKey,
+    pub validator: PublicKey,
+    pub amount: u64,
+    pub release_height: u64,
+}
+
+/// Bonded stake per validator. A validator's self-stake is stored as a
+/// delegation from itself, so rewards and penalties can treat every bonded
+/// token the same way.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct StakingState {
+    #[serde(serialize_with = "sorted_nested_map")]
+    delegations: HashMap<PublicKey, HashMap<PublicKey, u64>>,
+    unbonding: Vec<UnbondingEntry>,
+    #[serde(serialize_with = "sorted_set")]
+    tombstoned: HashSet<PublicKey>,
+    #[serde(serialize_with = "sorted_map")]
+    jailed_until: HashMap<PublicKey, u64>,
+    #[serde(serialize_with = "sorted_map")]
+    bls_keys: HashMap<PublicKey, BlsPublicKey>,
+    /// Share of each block reward a validator keeps before the rest is
+    /// split by stake, in basis points.
+    #[serde(serialize_with = "sorted_map")]
+    commission_bps: HashMap<PublicKey, u64>,
+}
+
+impl StakingState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_validator(&self, validator: &PublicKey) -> bool {
+        self.delegations.contains_key(validator)
+    }
+
+    /// Bonds `self_stake` for a new validator voting with `bls_key`. The
+    /// proof of possession rules out rogue-key attacks on aggregate votes.
+    pub fn register_validator(
+        &mut self,
+        validator: PublicKey,
+        bls_key: BlsPublicKey,
+        proof_of_possession: &BlsSignature,
+        self_stake: u64,
+        commission_bps: u64,
+    ) -> Result<(), String> {
+        if self.is_validator(&validator) {
+            return Err("Validator already registered".to_string());
+        }
+        if self_stake == 0 {
+            return Err("Validator self-stake must be positive".to_string());
+        }
+        if commission_bps > MAX_COMMISSION_BPS {
+            return Err("Commission above 100%".to_string());
+        }
+        if !verify_proof_of_possession(&bls_key, proof_of_possession) {
+            return Err("Invalid BLS proof of possession".to_string());
+        }
+        self.bls_keys.insert(validator.clone(), bls_key);
+        self.commission_bps
+            .insert(validator.clone(), commission_bps);
+        let mut delegations = HashMap::new();
+        delegations.insert(validator.clone(), self_stake);
+        self.delegations.insert(validator, delegations);
+        Ok(())
+    }
+
+    pub fn delegate(
+        &mut self,
+        delegator: PublicKey,
+        validator: &PublicKey,
+        amount: u64,
+    ) -> Result<(), String> {
+        if self.is_tombstoned(validator) {
+            return Err("Validator was slashed and can no longer be delegated to".to_string());
+        }
+        let delegations = self
+            .delegations
+            .get_mut(validator)
+            .ok_or_else(|| "Unknown validator".to_string())?;
+        *delegations.entry(delegator).or_insert(0) += amount;
+        Ok(())
+    }
+
+    /// Moves `amount` of a delegation into the unbonding queue. The tokens
+    /// stop counting towards the validator's stake immediately.
+    pub fn undelegate(
+        &mut self,
+        delegator: &PublicKey,
+        validator: &PublicKey,
+        amount: u64,
+        height: u64,
+    ) -> Result<(), String> {
+        let bonded = self.delegation(delegator, validator);
+        if amount == 0 || bonded < amount {
+            return Err("Insufficient delegation".to_string());
+        }
+
+        let delegations = self.delegations.get_mut(validator).unwrap();
+        if bonded == amount {
+            delegations.remove(delegator);
+        } else {
+            delegations.insert(delegator.clone(), bonded - amount);
+        }
+
+        self.unbonding.push(UnbondingEntry {
+            delegator: delegator.clone(),
+            validator: validator.clone(),
+            amount,
+            release_height: height + UNBONDING_PERIOD,
+        });
+        Ok(())
+    }
+
+    /// Removes and returns the total of `delegator`'s matured unbonding entries.
+    pub fn claim_unbonded(&mut self, delegator: &PublicKey, height: u64) -> u64 {
+        let mut claimed = 0;
+        self.unbonding.retain(|entry| {
+            if &entry.delegator == delegator && entry.release_height <= height {
+                claimed += entry.amount;
+                false
+            } else {
+                true
+            }
+        });
+        claimed
+    }
+
+    pub fn delegation(&self, delegator: &PublicKey, validator: &PublicKey) -> u64 {
+        self.delegations
+            .get(validator)
+            .and_then(|delegations| delegations.get(delegator))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn unbonding(&self) -> &[UnbondingEntry] {
+        &self.unbonding
+    }
+
+    pub fn bls_key(&self, validator: &PublicKey) -> Option<&BlsPublicKey> {
+        self.bls_keys.get(validator)
+    }
+
+    pub fn commission_bps(&self, validator: &PublicKey) -> u64 {
+        self.commission_bps.get(validator).copied().unwrap_or(0)
+    }
+
+    pub fn stake_of(&self, validator: &PublicKey) -> u64 {
+        self.delegations
+            .get(validator)
+            .map_or(0, |delegations| delegations.values().sum())
+    }
+
+    pub fn is_tombstoned(&self, validator: &PublicKey) -> bool {
+        self.tombstoned.contains(validator)
+    }
+
+    pub fn is_jailed(&self, validator: &PublicKey) -> bool {
+        self.jailed_until.contains_key(validator)
+    }
+
+    /// Keeps `validator` out of the active set until it unjails, which is
+    /// allowed from `until_height` on.
+    pub fn jail(&mut self, validator: &PublicKey, until_height: u64) {
+        self.jailed_until.insert(validator.clone(), until_height);
+    }
+
+    pub fn unjail(&mut self, validator: &PublicKey, height: u64) -> Result<(), String> {
+        match self.jailed_until.get(validator) {
+            None => Err("Validator is not jailed".to_string()),
+            Some(until_height) if height < *until_height => {
+                Err("Jail period has not ended".to_string())
+            }
+            Some(_) => {
+                self.jailed_until.remove(validator);
+                Ok(())
+            }
+        }
+    }
+
+    /// Burns `slash_bps` of every delegation to `validator`, including
+    /// tokens that started unbonding from it at or after `infraction_height`
+    /// and so were at stake when it misbehaved, and permanently removes it
+    /// from the validator set. Returns the amount burned.
+    pub fn slash(&mut self, validator: &PublicKey, slash_bps: u64, infraction_height: u64) -> u64 {
+        let cut = |amount: u64| (amount as u128 * slash_bps as u128 / 10_000) as u64;
+        let mut burned = 0;
+
+        if let Some(delegations) = self.delegations.get_mut(validator) {
+            for stake in delegations.values_mut() {
+                let penalty = cut(*stake);
+                *stake -= penalty;
+                burned += penalty;
+            }
+        }
+        for entry in self.unbonding.iter_mut().filter(|entry| {
+            &entry.validator == validator
+                && entry.release_height >= infraction_height + UNBONDING_PERIOD
+        }) {
+            let penalty = cut(entry.amount);
+            entry.amount -= penalty;
+            burned += penalty;
+        }
+
+        self.tombstoned.insert(validator.clone());
+        burned
+    }
+
+    /// Splits a block reward: the validator takes its commission, and the
+    /// rest is shared between its delegators pro-rata to their bonded stake.
+    /// Rounding dust goes to the validator itself, as does the whole reward
+    /// if it has no stake.
+    pub fn reward_shares(&self, validator: &PublicKey, reward: u64) -> Vec<(PublicKey, u64)> {
+        let total = self.stake_of(validator);
+        if total == 0 {
+            return vec![(validator.clone(), reward)];
+        }
+
+        let commission = (reward as u128 * self.commission_bps(validator) as u128 / 10_000) as u64;
+        let shared = reward - commission;
+        let mut shares = Vec::new();
+        let mut distributed = 0;
+        for (delegator, stake) in &self.delegations[validator] {
+            if delegator == validator {
+                continue;
+            }
+            let share = (shared as u128 * *stake as u128 / total as u128) as u64;
+            distributed += share;
+            shares.push((delegator.clone(), share));
+        }
+        shares.push((validator.clone(), reward - distributed));
+        shares
+    }
+
+    /// Total bonded stake of every validator with a non-zero stake; the table
+    /// DPoS picks its active set from.
+    pub fn stakes(&self) -> HashMap<PublicKey, u64> {
+        self.delegations
+            .keys()
+            .filter(|validator| !self.is_tombstoned(validator) && !self.is_jailed(validator))
+            .map(|validator| (validator.clone(), self.stake_of(validator)))
+            .filter(|(_, stake)| *stake > 0)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{BlsKeyPair, KeyPair};
+
+    fn register(staking: &mut StakingState, self_stake: u64, commission_bps: u64) -> PublicKey {
+        let validator = KeyPair::generate().public_key();
+        let bls = BlsKeyPair::generate();
+        staking
+            .register_validator(
+                validator.clone(),
+                bls.public_key(),
+                &bls.proof_of_possession(),
+                self_stake,
+                commission_bps,
+            )
+            .unwrap();
+        validator
+    }
+
+    fn delegator() -> PublicKey {
+        KeyPair::generate().public_key()
+    }
+
+    fn share_of(shares: &[(PublicKey, u64)], recipient: &PublicKey) -> u64 {
+        shares
+            .iter()
+            .filter(|(candidate, _)| candidate == recipient)
+            .map(|(_, share)| share)
+            .sum()
+    }
+
+    #[test]
+    fn unbonded_tokens_are_claimable_after_the_unbonding_period() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 100, 0);
+        let alice = delegator();
+        staking.delegate(alice.clone(), &validator, 50).unwrap();
+
+        staking.undelegate(&alice, &validator, 30, 10).unwrap();
+        assert_eq!(staking.delegation(&alice, &validator), 20);
+        assert_eq!(staking.stake_of(&validator), 120);
+
+        assert_eq!(staking.claim_unbonded(&alice, 10 + UNBONDING_PERIOD - 1), 0);
+        assert_eq!(staking.claim_unbonded(&alice, 10 + UNBONDING_PERIOD), 30);
+        assert!(staking.unbonding().is_empty());
+        assert_eq!(staking.claim_unbonded(&alice, 10 + UNBONDING_PERIOD), 0);
+    }
+
+    #[test]
+    fn only_matured_entries_are_claimed() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 100, 0);
+        let alice = delegator();
+        staking.delegate(alice.clone(), &validator, 50).unwrap();
+        staking.undelegate(&alice, &validator, 10, 0).unwrap();
+        staking.undelegate(&alice, &validator, 20, 5).unwrap();
+
+        assert_eq!(staking.claim_unbonded(&alice, UNBONDING_PERIOD), 10);
+        assert_eq!(staking.claim_unbonded(&alice, UNBONDING_PERIOD + 5), 20);
+    }
+
+    #[test]
+    fn undelegating_more_than_delegated_fails() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 100, 0);
+        let alice = delegator();
+        staking.delegate(alice.clone(), &validator, 50).unwrap();
+
+        assert!(staking.undelegate(&alice, &validator, 51, 0).is_err());
+        assert!(staking.undelegate(&alice, &validator, 0, 0).is_err());
+        assert!(staking.undelegate(&delegator(), &validator, 1, 0).is_err());
+        assert_eq!(staking.delegation(&alice, &validator), 50);
+        assert!(staking.unbonding().is_empty());
+
+        staking.undelegate(&alice, &validator, 50, 0).unwrap();
+        assert_eq!(staking.delegation(&alice, &validator), 0);
+    }
+
+    #[test]
+    fn delegating_to_an_unknown_validator_fails() {
+        let mut staking = StakingState::new();
+        assert!(staking.delegate(delegator(), &delegator(), 10).is_err());
+    }
+
+    #[test]
+    fn a_jailed_validator_keeps_its_delegations_but_leaves_the_stake_table() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 100, 0);
+        staking.jail(&validator, 50);
+
+        staking.delegate(delegator(), &validator, 10).unwrap();
+        assert_eq!(staking.stake_of(&validator), 110);
+        assert!(!staking.stakes().contains_key(&validator));
+
+        assert!(staking.unjail(&validator, 49).is_err());
+        staking.unjail(&validator, 50).unwrap();
+        assert_eq!(staking.stakes()[&validator], 110);
+        assert!(staking.unjail(&validator, 50).is_err());
+    }
+
+    #[test]
+    fn a_tombstoned_validator_cannot_be_delegated_to() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 100, 0);
+        staking.slash(&validator, DOUBLE_SIGN_SLASH_BPS, 0);
+
+        assert!(staking.delegate(delegator(), &validator, 10).is_err());
+        assert!(!staking.stakes().contains_key(&validator));
+        // Its delegators can still leave.
+        staking.undelegate(&validator, &validator, 95, 0).unwrap();
+    }
+
+    #[test]
+    fn slashing_burns_bonded_and_unbonding_stake() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 1_000, 0);
+        let alice = delegator();
+        staking.delegate(alice.clone(), &validator, 2_000).unwrap();
+        staking.undelegate(&alice, &validator, 1_000, 10).unwrap();
+
+        let burned = staking.slash(&validator, 500, 10);
+        assert_eq!(burned, 150);
+        assert_eq!(staking.delegation(&validator, &validator), 950);
+        assert_eq!(staking.delegation(&alice, &validator), 950);
+        assert_eq!(staking.unbonding()[0].amount, 950);
+        assert!(staking.is_tombstoned(&validator));
+    }
+
+    #[test]
+    fn stake_unbonding_before_the_infraction_is_not_slashed() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 1_000, 0);
+        let alice = delegator();
+        staking.delegate(alice.clone(), &validator, 2_000).unwrap();
+        staking.undelegate(&alice, &validator, 1_000, 9).unwrap();
+        staking.undelegate(&alice, &validator, 1_000, 11).unwrap();
+
+        assert_eq!(staking.slash(&validator, 500, 10), 50 + 50);
+        assert_eq!(staking.unbonding()[0].amount, 1_000);
+        assert_eq!(staking.unbonding()[1].amount, 950);
+    }
+
+    #[test]
+    fn reward_shares_add_up_to_the_reward() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 7, 1_234);
+        for stake in [3, 11, 13, 1] {
+            staking.delegate(delegator(), &validator, stake).unwrap();
+        }
+        for reward in [0, 1, 7, 999, 1_000_003, u64::MAX / 2] {
+            let shares = staking.reward_shares(&validator, reward);
+            assert_eq!(shares.iter().map(|(_, share)| share).sum::<u64>(), reward);
+        }
+    }
+
+    #[test]
+    fn rewards_are_shared_pro_rata_without_commission() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 100, 0);
+        let alice = delegator();
+        let bob = delegator();
+        staking.delegate(alice.clone(), &validator, 200).unwrap();
+        staking.delegate(bob.clone(), &validator, 100).unwrap();
+
+        let shares = staking.reward_shares(&validator, 800);
+        assert_eq!(share_of(&shares, &alice), 400);
+        assert_eq!(share_of(&shares, &bob), 200);
+        assert_eq!(share_of(&shares, &validator), 200);
+    }
+
+    #[test]
+    fn commission_is_taken_before_sharing() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 100, 1_000);
+        let alice = delegator();
+        staking.delegate(alice.clone(), &validator, 100).unwrap();
+
+        // 10% commission, then half of the remaining 900 each.
+        let shares = staking.reward_shares(&validator, 1_000);
+        assert_eq!(share_of(&shares, &alice), 450);
+        assert_eq!(share_of(&shares, &validator), 550);
+    }
+
+    #[test]
+    fn full_commission_leaves_nothing_for_delegators() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 100, MAX_COMMISSION_BPS);
+        let alice = delegator();
+        staking.delegate(alice.clone(), &validator, 900).unwrap();
+
+        let shares = staking.reward_shares(&validator, 1_000);
+        assert_eq!(share_of(&shares, &alice), 0);
+        assert_eq!(share_of(&shares, &validator), 1_000);
+    }
+
+    #[test]
+    fn commission_above_the_whole_reward_is_rejected() {
+        let mut staking = StakingState::new();
+        let bls = BlsKeyPair::generate();
+        let result = staking.register_validator(
+            delegator(),
+            bls.public_key(),
+            &bls.proof_of_possession(),
+            100,
+            MAX_COMMISSION_BPS + 1,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rounding_dust_goes_to_the_validator() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 1, 0);
+        let delegators: Vec<_> = (0..2).map(|_| delegator()).collect();
+        for delegator in &delegators {
+            staking.delegate(delegator.clone(), &validator, 1).unwrap();
+        }
+
+        // A third each is 3.33, so each delegator gets 3 and the validator 4.
+        let shares = staking.reward_shares(&validator, 10);
+        for delegator in &delegators {
+            assert_eq!(share_of(&shares, delegator), 3);
+        }
+        assert_eq!(share_of(&shares, &validator), 4);
+    }
+
+    #[test]
+    fn a_validator_without_stake_keeps_the_whole_reward() {
+        let mut staking = StakingState::new();
+        let validator = register(&mut staking, 10, 0);
+        staking.undelegate(&validator, &validator, 10, 0).unwrap();
+
+        assert_eq!(
+            staking.reward_shares(&validator, 10),
+            vec![(validator.clone(), 10)]
+        );
+    }
+}
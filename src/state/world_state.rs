@@ -1,72 +1,734 @@
 use crate::blockchain::block::Block;
-use crate::blockchain::transaction::Transaction;
-use crate::crypto::{Hash, Hashable, PublicKey};
+use crate::blockchain::genesis::GenesisConfig;
+use crate::blockchain::transaction::{Transaction, TransactionKind};
+use crate::consensus::evidence::Evidence;
+use crate::crypto::merkle_root;
+use crate::crypto::{BlsPublicKey, Hash, Hashable, PublicKey};
+use crate::state::accounts::AccountTable;
+use crate::state::liveness::{
+    LivenessTracker, JAIL_PERIOD, MAX_MISSED_SLOTS, MIN_ACTIVE_VALIDATORS,
+};
+use crate::state::randao::RandaoState;
+use crate::state::rewards::RewardSchedule;
+use crate::state::snapshot::{encoded_hash, StateSnapshot};
+use crate::state::staking::{StakingState, DOUBLE_SIGN_SLASH_BPS, UNBONDING_PERIOD};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Account {
     pub balance: u64,
     pub nonce: u64,
 }
 
+#[derive(Clone)]
 pub struct WorldState {
-    accounts: Arc<RwLock<HashMap<PublicKey, Account>>>,
+    accounts: AccountTable,
+    staking: StakingState,
+    liveness: LivenessTracker,
+    randao: RandaoState,
+    rewards: RewardSchedule,
+    genesis_time: u64,
+    total_supply: u64,
+    height: u64,
     last_block_hash: Hash,
+    /// Hash of `staking`, `None` once it has changed. Staking changes far
+    /// less often than the rest of the state and can be large.
+    staking_hash: Option<Hash>,
+    /// The state root as of the last applied block.
+    root: Hash,
+}
+
+impl Default for WorldState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl WorldState {
     pub fn new() -> Self {
-        WorldState {
-            accounts: Arc::new(RwLock::new(HashMap::new())),
+        let mut world_state = WorldState {
+            accounts: AccountTable::new(),
+            staking: StakingState::new(),
+            liveness: LivenessTracker::new(),
+            randao: RandaoState::new(),
+            rewards: RewardSchedule::default(),
+            genesis_time: 0,
+            total_supply: 0,
+            height: 0,
             last_block_hash: Hash::default(),
+            staking_hash: None,
+            root: Hash::default(),
+        };
+        world_state
+            .update_root()
+            .expect("An empty state must be encodable");
+        world_state
+    }
+
+    /// Builds the state before the genesis block: initial balances, bonded
+    /// genesis validators and the issuance schedule.
+    pub fn from_genesis(genesis: &GenesisConfig) -> Result<Self, String> {
+        let mut world_state = WorldState::new();
+        world_state.rewards = genesis.rewards.clone();
+        world_state.genesis_time = genesis.timestamp;
+        for (public_key, balance) in &genesis.balances {
+            world_state.credit(public_key, *balance);
+        }
+        for validator in &genesis.validators {
+            world_state.staking.register_validator(
+                validator.public_key.clone(),
+                validator.bls_key.clone(),
+                &validator.proof_of_possession,
+                validator.stake,
+                validator.commission_bps,
+            )?;
+            world_state.total_supply += validator.stake;
         }
+        world_state.staking_hash = None;
+        world_state.update_root()?;
+        Ok(world_state)
+    }
+
+    /// Applies a block on top of this state. Either the whole block applies
+    /// or, if any part of it is invalid, the state is left as it was.
+    /// `missed` are the validators consensus scheduled for the slots skipped
+    /// before the block. Returns the validators the block jailed.
+    pub fn apply_block(
+        &mut self,
+        block: &Block,
+        missed: &[PublicKey],
+    ) -> Result<Vec<PublicKey>, String> {
+        let mut next = self.clone();
+        let jailed = next.execute_block(block, missed)?;
+        *self = next;
+        Ok(jailed)
     }
 
-    pub fn apply_block(&mut self, block: &Block) -> Result<(), String> {
+    /// Applies a block in place, leaving the state partly updated if it
+    /// fails part way.
+    fn execute_block(
+        &mut self,
+        block: &Block,
+        missed: &[PublicKey],
+    ) -> Result<Vec<PublicKey>, String> {
         if block.header.previous_hash != self.last_block_hash {
             return Err("Invalid previous block hash".to_string());
         }
+        if block.header.state_root != self.root {
+            return Err("Invalid state root".to_string());
+        }
+
+        // The reward is based on the supply before this block's transactions.
+        let expected_reward = if block.header.height > 0 {
+            self.expected_reward(block.header.timestamp)
+        } else {
+            0
+        };
+        if block.header.reward != expected_reward {
+            return Err("Invalid block reward".to_string());
+        }
+        self.randao.apply_block(block)?;
 
+        self.height = block.header.height;
         for tx in &block.transactions {
             self.apply_transaction(tx)?;
         }
+        for evidence in &block.evidence {
+            self.apply_evidence(evidence)?;
+        }
+        let jailed = self.record_liveness(&block.header.validator, missed);
+
+        self.mint_reward(&block.header.validator, expected_reward);
 
         self.last_block_hash = block.hash();
-        Ok(())
+        self.update_root()?;
+        Ok(jailed)
     }
 
-    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), String> {
-        let mut accounts = self.accounts.write().unwrap();
+    fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), String> {
+        if !tx.verify() {
+            return Err("Invalid transaction signature".to_string());
+        }
+        if tx.kind != TransactionKind::Transfer {
+            self.staking_hash = None;
+        }
 
-        let from_account = accounts.entry(tx.from.clone()).or_insert(Account {
-            balance: 0,
-            nonce: 0,
-        });
+        let from_account = self.accounts.get_or_create(&tx.from);
         if from_account.nonce != tx.nonce {
             return Err("Invalid nonce".to_string());
         }
-        if from_account.balance < tx.amount {
+        if from_account.balance < tx.spend() {
             return Err("Insufficient balance".to_string());
         }
 
-        from_account.balance -= tx.amount;
+        match &tx.kind {
+            TransactionKind::Transfer => {}
+            TransactionKind::RegisterValidator {
+                bls_key,
+                proof_of_possession,
+                commission_bps,
+            } => {
+                self.staking.register_validator(
+                    tx.from.clone(),
+                    bls_key.clone(),
+                    proof_of_possession,
+                    tx.amount,
+                    *commission_bps,
+                )?;
+            }
+            TransactionKind::Delegate => {
+                self.staking.delegate(tx.from.clone(), &tx.to, tx.amount)?;
+            }
+            TransactionKind::Undelegate => {
+                self.staking
+                    .undelegate(&tx.from, &tx.to, tx.amount, self.height)?;
+            }
+            TransactionKind::ClaimUnbonded => {
+                let claimed = self.staking.claim_unbonded(&tx.from, self.height);
+                if claimed == 0 {
+                    return Err("Nothing to claim".to_string());
+                }
+                from_account.balance += claimed;
+            }
+            TransactionKind::Unjail => {
+                self.staking.unjail(&tx.from, self.height)?;
+                self.liveness.reset(&tx.from);
+            }
+        }
+
+        from_account.balance -= tx.spend();
         from_account.nonce += 1;
 
-        let to_account = accounts.entry(tx.to.clone()).or_insert(Account {
-            balance: 0,
-            nonce: 0,
-        });
-        to_account.balance += tx.amount;
+        if tx.kind == TransactionKind::Transfer {
+            self.accounts.get_or_create(&tx.to).balance += tx.amount;
+        }
+
+        Ok(())
+    }
 
+    /// Records the producer of the latest block and the validators whose
+    /// slots were skipped before it. Returns validators jailed as a result.
+    fn record_liveness(&mut self, producer: &PublicKey, missed: &[PublicKey]) -> Vec<PublicKey> {
+        self.liveness.record(producer, true);
+
+        let mut active = self.staking.stakes().len();
+        let mut jailed = Vec::new();
+        for validator in missed {
+            if self.staking.is_jailed(validator) || self.staking.is_tombstoned(validator) {
+                continue;
+            }
+            if self.liveness.record(validator, false) > MAX_MISSED_SLOTS
+                && active > MIN_ACTIVE_VALIDATORS
+            {
+                active -= 1;
+                self.staking_hash = None;
+                self.staking.jail(validator, self.height + JAIL_PERIOD);
+                self.liveness.reset(validator);
+                jailed.push(validator.clone());
+            }
+        }
+        jailed
+    }
+
+    /// Verifies double-sign evidence and slashes the offender. Each validator
+    /// can only be slashed once; later evidence against it is rejected.
+    fn apply_evidence(&mut self, evidence: &Evidence) -> Result<(), String> {
+        self.check_evidence(evidence)?;
+        self.staking_hash = None;
+        let burned = self.staking.slash(
+            evidence.offender(),
+            DOUBLE_SIGN_SLASH_BPS,
+            evidence.height(),
+        );
+        self.total_supply -= burned;
+        Ok(())
+    }
+
+    /// Whether `evidence` would be accepted by `apply_evidence`.
+    pub fn check_evidence(&self, evidence: &Evidence) -> Result<(), String> {
+        let offender = evidence.offender();
+        evidence.verify(self.staking.bls_key(offender))?;
+        if !self.staking.is_validator(offender) {
+            return Err("Evidence against unknown validator".to_string());
+        }
+        if self.staking.is_tombstoned(offender) {
+            return Err("Validator already slashed".to_string());
+        }
+        // Past this, stake bonded at the time may have been withdrawn, so
+        // the offence can no longer be punished in full.
+        if self.height.saturating_sub(evidence.height()) > UNBONDING_PERIOD {
+            return Err("Evidence is older than the unbonding period".to_string());
+        }
         Ok(())
     }
 
+    /// Splits `candidates`, ordered by sender and nonce, into those a block
+    /// at `height` can include, in the order they apply, and those that can
+    /// never apply because their sender has already used the nonce. Each is
+    /// tried on a copy of the state after the ones before it; the rest are
+    /// left for a later block.
+    pub fn select_transactions(
+        &self,
+        height: u64,
+        candidates: Vec<Transaction>,
+    ) -> (Vec<Transaction>, Vec<Transaction>) {
+        let mut trial = self.clone();
+        trial.height = height;
+        let mut included = Vec::new();
+        let mut stale = Vec::new();
+        for tx in candidates {
+            if trial.apply_transaction(&tx).is_ok() {
+                included.push(tx);
+            } else if tx.nonce < self.nonce(&tx.from) {
+                stale.push(tx);
+            }
+        }
+        (included, stale)
+    }
+
+    /// The nonce `public_key`'s next transaction must carry.
+    pub fn nonce(&self, public_key: &PublicKey) -> u64 {
+        self.accounts
+            .get(public_key)
+            .map_or(0, |account| account.nonce)
+    }
+
     pub fn get_account(&self, public_key: &PublicKey) -> Option<Account> {
-        self.accounts.read().unwrap().get(public_key).cloned()
+        self.accounts.get(public_key).cloned()
+    }
+
+    /// The amount a block with `timestamp` must mint on top of this state.
+    pub fn expected_reward(&self, timestamp: u64) -> u64 {
+        let elapsed = timestamp.saturating_sub(self.genesis_time);
+        self.rewards.reward_at(elapsed, self.total_supply)
+    }
+
+    fn mint_reward(&mut self, producer: &PublicKey, reward: u64) {
+        if reward == 0 {
+            return;
+        }
+        for (recipient, share) in self.staking.reward_shares(producer, reward) {
+            self.credit(&recipient, share);
+        }
+    }
+
+    fn credit(&mut self, public_key: &PublicKey, amount: u64) {
+        self.accounts.get_or_create(public_key).balance += amount;
+        self.total_supply += amount;
+    }
+
+    pub fn randao(&self) -> &RandaoState {
+        &self.randao
+    }
+
+    /// The randomness beacon as of the last applied block.
+    pub fn randomness(&self) -> Hash {
+        self.randao.mix()
+    }
+
+    /// All tokens in existence: spendable, bonded and unbonding.
+    pub fn total_supply(&self) -> u64 {
+        self.total_supply
+    }
+
+    pub fn get_stake(&self, public_key: &PublicKey) -> u64 {
+        self.staking.stake_of(public_key)
+    }
+
+    /// The stake table consensus rotates validators from at epoch boundaries.
+    pub fn stakes(&self) -> HashMap<PublicKey, u64> {
+        self.staking.stakes()
+    }
+
+    /// BLS vote keys of the validators in the stake table.
+    pub fn bls_keys(&self) -> HashMap<PublicKey, BlsPublicKey> {
+        self.stakes()
+            .into_keys()
+            .filter_map(|validator| {
+                let bls_key = self.staking.bls_key(&validator)?.clone();
+                Some((validator, bls_key))
+            })
+            .collect()
+    }
+
+    pub fn staking(&self) -> &StakingState {
+        &self.staking
     }
 
     pub fn get_last_block_hash(&self) -> Hash {
-        self.last_block_hash.clone()
+        self.last_block_hash
+    }
+
+    /// Height of the last applied block.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Commits to the whole state as of the last applied block.
+    pub fn state_root(&self) -> Hash {
+        self.root
+    }
+
+    /// Recomputes the state root: a Merkle root over the account buckets'
+    /// root and the hashes of the other parts of the state. Only what
+    /// changed is rehashed; the liveness windows and the beacon are small.
+    fn update_root(&mut self) -> Result<(), String> {
+        let staking_hash = match self.staking_hash {
+            Some(hash) => hash,
+            None => encoded_hash(&self.staking)?,
+        };
+        self.staking_hash = Some(staking_hash);
+        let parts = [
+            self.accounts.root()?,
+            staking_hash,
+            encoded_hash(&self.liveness)?,
+            encoded_hash(&self.randao)?,
+            encoded_hash(&(
+                &self.rewards,
+                self.genesis_time,
+                self.total_supply,
+                self.height,
+                self.last_block_hash,
+            ))?,
+        ];
+        self.root = merkle_root(parts.into_iter());
+        Ok(())
+    }
+
+    pub(crate) fn to_snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            accounts: self
+                .accounts
+                .iter()
+                .map(|(public_key, account)| (public_key.clone(), account.clone()))
+                .collect(),
+            staking: self.staking.clone(),
+            liveness: self.liveness.clone(),
+            randao: self.randao.clone(),
+            rewards: self.rewards.clone(),
+            genesis_time: self.genesis_time,
+            total_supply: self.total_supply,
+            height: self.height,
+            last_block_hash: self.last_block_hash,
+        }
+    }
+
+    pub(crate) fn from_snapshot(snapshot: StateSnapshot) -> Result<Self, String> {
+        let mut world_state = WorldState {
+            accounts: snapshot.accounts.into_iter().collect(),
+            staking: snapshot.staking,
+            liveness: snapshot.liveness,
+            randao: snapshot.randao,
+            rewards: snapshot.rewards,
+            genesis_time: snapshot.genesis_time,
+            total_supply: snapshot.total_supply,
+            height: snapshot.height,
+            last_block_hash: snapshot.last_block_hash,
+            staking_hash: None,
+            root: Hash::default(),
+        };
+        world_state.update_root()?;
+        Ok(world_state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis::GenesisValidator;
+    use crate::consensus::dpos::BLOCK_TIME;
+    use crate::consensus::evidence::SignedHeader;
+    use crate::crypto::{BlsKeyPair, KeyPair};
+
+    /// A valid block on top of `state` from a producer that has not
+    /// committed to a RANDAO reveal yet.
+    fn next_block(
+        state: &WorldState,
+        producer: &PublicKey,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        let timestamp = state.genesis_time + BLOCK_TIME.as_secs() * (state.height() + 1);
+        Block::new(
+            state.get_last_block_hash(),
+            transactions,
+            state.height() + 1,
+            0,
+            timestamp,
+            producer.clone(),
+            state.expected_reward(timestamp),
+        )
+        .with_randao(Hash::default(), Hash::default(), state.randomness())
+        .with_state_root(state.state_root())
+    }
+
+    fn state_with_validators(count: usize) -> (WorldState, Vec<KeyPair>) {
+        let keypairs: Vec<KeyPair> = (0..count).map(|_| KeyPair::generate()).collect();
+        let validators: Vec<GenesisValidator> = keypairs
+            .iter()
+            .map(|keypair| {
+                let bls = BlsKeyPair::generate();
+                GenesisValidator {
+                    public_key: keypair.public_key(),
+                    bls_key: bls.public_key(),
+                    proof_of_possession: bls.proof_of_possession(),
+                    stake: 100,
+                    commission_bps: 0,
+                }
+            })
+            .collect();
+        let genesis = GenesisConfig::new("test", 0, Vec::new(), validators);
+        (WorldState::from_genesis(&genesis).unwrap(), keypairs)
+    }
+
+    /// Applies `count` blocks, each skipping a slot of `absent`, and returns
+    /// who they jailed.
+    fn miss_slots(state: &mut WorldState, absent: &PublicKey, count: usize) -> Vec<PublicKey> {
+        let mut jailed = Vec::new();
+        for _ in 0..count {
+            // A fresh producer each time, so no RANDAO reveal is due.
+            let producer = KeyPair::generate().public_key();
+            let block = next_block(state, &producer, Vec::new());
+            jailed.extend(
+                state
+                    .apply_block(&block, std::slice::from_ref(absent))
+                    .unwrap(),
+            );
+        }
+        jailed
+    }
+
+    fn signed(mut transaction: Transaction, keypair: &KeyPair) -> Transaction {
+        transaction.sign(keypair.sign(transaction.hash().as_bytes()));
+        transaction
+    }
+
+    fn funded_state(accounts: &[(PublicKey, u64)]) -> WorldState {
+        WorldState::from_genesis(&GenesisConfig::new(
+            "test",
+            0,
+            accounts.to_vec(),
+            Vec::new(),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn a_block_with_an_invalid_transaction_changes_nothing() {
+        let alice_key = KeyPair::generate();
+        let alice = alice_key.public_key();
+        let bob = KeyPair::generate().public_key();
+        let producer = KeyPair::generate().public_key();
+        let mut state = funded_state(&[(alice.clone(), 100)]);
+        let root = state.state_root();
+
+        let valid = signed(
+            Transaction::new(alice.clone(), bob.clone(), 60, 0),
+            &alice_key,
+        );
+        let overspend = signed(
+            Transaction::new(alice.clone(), bob.clone(), 60, 1),
+            &alice_key,
+        );
+        let block = next_block(&state, &producer, vec![valid.clone(), overspend]);
+        assert!(state.apply_block(&block, &[]).is_err());
+
+        assert_eq!(state.state_root(), root);
+        assert_eq!(state.height(), 0);
+        assert_eq!(state.get_account(&alice).unwrap().balance, 100);
+        assert!(state.get_account(&bob).is_none());
+
+        let block = next_block(&state, &producer, vec![valid]);
+        state.apply_block(&block, &[]).unwrap();
+        assert_eq!(state.height(), 1);
+        assert_eq!(state.get_account(&bob).unwrap().balance, 60);
+    }
+
+    #[test]
+    fn undelegated_tokens_return_to_the_balance_once_claimed() {
+        let keypair = KeyPair::generate();
+        let validator = keypair.public_key();
+        let bls = BlsKeyPair::generate();
+        let mut state = funded_state(&[(validator.clone(), 1_000)]);
+
+        let register = signed(
+            Transaction::register_validator(
+                validator.clone(),
+                bls.public_key(),
+                bls.proof_of_possession(),
+                600,
+                0,
+                0,
+            ),
+            &keypair,
+        );
+        state.apply_transaction(&register).unwrap();
+        assert_eq!(state.get_account(&validator).unwrap().balance, 400);
+
+        state.height = 10;
+        let undelegate = signed(
+            Transaction::undelegate(validator.clone(), validator.clone(), 600, 1),
+            &keypair,
+        );
+        state.apply_transaction(&undelegate).unwrap();
+        assert_eq!(state.get_stake(&validator), 0);
+
+        state.height = 10 + UNBONDING_PERIOD - 1;
+        let early = signed(Transaction::claim_unbonded(validator.clone(), 2), &keypair);
+        assert!(state.apply_transaction(&early).is_err());
+
+        state.height = 10 + UNBONDING_PERIOD;
+        state.apply_transaction(&early).unwrap();
+        let account = state.get_account(&validator).unwrap();
+        assert_eq!((account.balance, account.nonce), (1_000, 3));
+        assert_eq!(state.total_supply(), 1_000);
+    }
+
+    #[test]
+    fn validators_missing_too_many_slots_are_jailed() {
+        let (mut state, validators) = state_with_validators(MIN_ACTIVE_VALIDATORS + 1);
+        let absent = &validators[0].public_key();
+
+        assert!(miss_slots(&mut state, absent, MAX_MISSED_SLOTS).is_empty());
+        let root = state.state_root();
+        assert_eq!(miss_slots(&mut state, absent, 1), vec![absent.clone()]);
+        assert_ne!(state.state_root(), root);
+        assert!(state.staking().is_jailed(absent));
+        assert!(!state.stakes().contains_key(absent));
+
+        // Already jailed, so further misses are not counted.
+        assert!(miss_slots(&mut state, absent, MAX_MISSED_SLOTS + 1).is_empty());
+    }
+
+    #[test]
+    fn jailing_never_shrinks_the_stake_table_below_the_minimum() {
+        let (mut state, validators) = state_with_validators(MIN_ACTIVE_VALIDATORS);
+        let absent = validators[0].public_key();
+        assert!(miss_slots(&mut state, &absent, MAX_MISSED_SLOTS + 5).is_empty());
+        assert_eq!(state.stakes().len(), MIN_ACTIVE_VALIDATORS);
+    }
+
+    #[test]
+    fn unjailing_needs_the_jail_period_to_pass() {
+        let (mut state, validators) = state_with_validators(MIN_ACTIVE_VALIDATORS + 1);
+        let absent = validators[0].public_key();
+        miss_slots(&mut state, &absent, MAX_MISSED_SLOTS + 1);
+        let jailed_at = state.height();
+
+        let unjail = signed(Transaction::unjail(absent.clone(), 0), &validators[0]);
+        state.height = jailed_at + JAIL_PERIOD - 1;
+        assert!(state.apply_transaction(&unjail).is_err());
+        state.height = jailed_at + JAIL_PERIOD;
+        state.apply_transaction(&unjail).unwrap();
+        assert!(state.stakes().contains_key(&absent));
+        assert_eq!(state.liveness.missed(&absent), 0);
+    }
+
+    fn double_sign(keypair: &KeyPair, height: u64) -> Evidence {
+        let signed = |timestamp| {
+            let mut block = Block::new(
+                Hash::default(),
+                Vec::new(),
+                height,
+                0,
+                timestamp,
+                keypair.public_key(),
+                0,
+            );
+            block.sign(keypair);
+            Box::new(SignedHeader {
+                header: block.header,
+                signature: block.signature,
+            })
+        };
+        Evidence::DoubleSign {
+            first: signed(1),
+            second: signed(2),
+        }
+    }
+
+    #[test]
+    fn evidence_older_than_the_unbonding_period_is_rejected() {
+        let (mut state, validators) = state_with_validators(1);
+        let evidence = double_sign(&validators[0], 5);
+
+        state.height = 5 + UNBONDING_PERIOD;
+        state.check_evidence(&evidence).unwrap();
+        state.height += 1;
+        assert!(state.check_evidence(&evidence).is_err());
+    }
+
+    #[test]
+    fn slashed_validators_cannot_be_slashed_again() {
+        let (mut state, validators) = state_with_validators(1);
+        let offender = validators[0].public_key();
+        let supply = state.total_supply();
+
+        state
+            .apply_evidence(&double_sign(&validators[0], 1))
+            .unwrap();
+        assert_eq!(state.get_stake(&offender), 95);
+        assert_eq!(state.total_supply(), supply - 5);
+        assert!(!state.stakes().contains_key(&offender));
+        assert!(state
+            .apply_evidence(&double_sign(&validators[0], 2))
+            .is_err());
+    }
+
+    #[test]
+    fn the_state_root_follows_every_account() {
+        let alice_key = KeyPair::generate();
+        let alice = alice_key.public_key();
+        let bob = KeyPair::generate().public_key();
+        let mut state = funded_state(&[(alice.clone(), 100), (bob.clone(), 100)]);
+        // Same accounts in a different order give the same root.
+        let reordered = funded_state(&[(bob.clone(), 100), (alice.clone(), 100)]);
+        assert_eq!(state.state_root(), reordered.state_root());
+
+        let mut roots = vec![state.state_root()];
+        for amount in [1, 2] {
+            let transfer = signed(
+                Transaction::new(alice.clone(), bob.clone(), amount, amount - 1),
+                &alice_key,
+            );
+            let producer = KeyPair::generate().public_key();
+            let block = next_block(&state, &producer, vec![transfer]);
+            state.apply_block(&block, &[]).unwrap();
+            assert!(!roots.contains(&state.state_root()));
+            roots.push(state.state_root());
+        }
+
+        let snapshot = WorldState::from_snapshot(state.to_snapshot()).unwrap();
+        assert_eq!(snapshot.state_root(), state.state_root());
+    }
+
+    #[test]
+    fn clones_do_not_share_accounts() {
+        let alice = KeyPair::generate().public_key();
+        let state = funded_state(&[(alice.clone(), 100)]);
+        let mut copy = state.clone();
+        copy.credit(&alice, 1);
+        assert_eq!(state.get_account(&alice).unwrap().balance, 100);
+        assert_eq!(copy.get_account(&alice).unwrap().balance, 101);
+    }
+
+    #[test]
+    fn only_the_sender_can_authorise_a_transaction() {
+        let (mut state, validators) = state_with_validators(MIN_ACTIVE_VALIDATORS + 1);
+        let validator = validators[0].public_key();
+        let stranger = KeyPair::generate();
+
+        // Unbonding someone else's stake, unsigned or signed by the wrong key.
+        let undelegate = Transaction::undelegate(validator.clone(), validator.clone(), 50, 0);
+        assert!(state.apply_transaction(&undelegate).is_err());
+        assert!(state
+            .apply_transaction(&signed(undelegate.clone(), &stranger))
+            .is_err());
+        assert_eq!(state.get_stake(&validator), 100);
+
+        state
+            .apply_transaction(&signed(undelegate, &validators[0]))
+            .unwrap();
+        assert_eq!(state.get_stake(&validator), 50);
     }
 }
@@ -0,0 +1,267 @@
+//! Runs several nodes in one process over the in-memory transport, wired
+//! together explicitly, so multi-node behaviour can be tested without TCP or
+//! mDNS.
+
+use flux::blockchain::{Blockchain, GenesisConfig, GenesisValidator, Transaction};
+use flux::consensus::{unix_now, ConsensusEngine, ConsensusManager, InstantSeal, SealMode};
+use flux::crypto::{BlsKeyPair, Hashable, KeyPair, PublicKey};
+use flux::network::config::memory_address;
+use flux::network::{NetworkConfig, NetworkHandle, P2PNetwork, SyncMode};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+/// How long a test waits for nodes to agree before failing.
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+const ACCOUNT_COUNT: usize = 2;
+const ACCOUNT_BALANCE: u64 = 1_000_000;
+const VALIDATOR_STAKE: u64 = 1_000;
+
+/// Memory transport ports are shared by every test in the binary.
+static NEXT_PORT: AtomicU64 = AtomicU64::new(1);
+
+pub struct TestNode {
+    pub blockchain: Arc<RwLock<Blockchain>>,
+    pub network: NetworkHandle,
+    pub peer_id: PeerId,
+    /// `/memory/<port>/p2p/<peer id>`.
+    pub address: Multiaddr,
+    data_dir: PathBuf,
+}
+
+impl TestNode {
+    pub async fn height(&self) -> u64 {
+        self.blockchain
+            .read()
+            .await
+            .get_latest_block()
+            .await
+            .header
+            .height
+    }
+
+    /// Waits until the node's chain reaches `height`, returning whether it
+    /// did before `TIMEOUT`.
+    pub async fn wait_for_height(&self, height: u64) -> bool {
+        self.wait_for_height_within(height, TIMEOUT).await
+    }
+
+    /// Like `wait_for_height`, for catching up on more blocks than fit in
+    /// `TIMEOUT`.
+    pub async fn wait_for_height_within(&self, height: u64, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while tokio::time::Instant::now() < deadline {
+            if self.height().await >= height {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+}
+
+impl Drop for TestNode {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+/// Nodes sharing one development genesis. Node 0 holds the authority key
+/// and is the only one that can produce blocks, unless the network was
+/// started with `with_validators`.
+pub struct TestNetwork {
+    pub nodes: Vec<TestNode>,
+    /// Funded in genesis, for sending transactions.
+    pub accounts: Vec<KeyPair>,
+    authority: PublicKey,
+    /// Handed to node 0 when it is created.
+    authority_key: Option<KeyPair>,
+    /// Keys of the genesis validators not yet handed to a node.
+    validator_keys: Vec<(KeyPair, BlsKeyPair)>,
+    genesis: GenesisConfig,
+}
+
+impl TestNetwork {
+    /// Starts `count` unconnected nodes.
+    pub async fn new(count: usize) -> Self {
+        Self::with_chain_id(count, "flux-test").await
+    }
+
+    /// Like `new`, on the chain named `chain_id`. Every network gets its own
+    /// genesis, so nodes of two networks never share a chain.
+    pub async fn with_chain_id(count: usize, chain_id: &str) -> Self {
+        let authority = KeyPair::generate();
+        let accounts: Vec<KeyPair> = (0..ACCOUNT_COUNT).map(|_| KeyPair::generate()).collect();
+        let balances = accounts
+            .iter()
+            .map(|account| (account.public_key(), ACCOUNT_BALANCE))
+            .collect();
+        let genesis = GenesisConfig::new(chain_id, unix_now(), balances, vec![]);
+        let mut network = TestNetwork {
+            nodes: Vec::new(),
+            accounts,
+            authority: authority.public_key(),
+            authority_key: Some(authority),
+            validator_keys: Vec::new(),
+            genesis,
+        };
+        for _ in 0..count {
+            network.add_node().await;
+        }
+        network
+    }
+
+    /// Starts `count` unconnected nodes running DPoS and PBFT, each an
+    /// equally staked genesis validator that produces blocks and votes.
+    pub async fn with_validators(count: usize) -> Self {
+        let validator_keys: Vec<(KeyPair, BlsKeyPair)> = (0..count)
+            .map(|_| (KeyPair::generate(), BlsKeyPair::generate()))
+            .collect();
+        let validators = validator_keys
+            .iter()
+            .map(|(keypair, vote_key)| GenesisValidator {
+                public_key: keypair.public_key(),
+                bls_key: vote_key.public_key(),
+                proof_of_possession: vote_key.proof_of_possession(),
+                stake: VALIDATOR_STAKE,
+                commission_bps: 0,
+            })
+            .collect();
+        let genesis = GenesisConfig::new("flux-test", unix_now(), Vec::new(), validators);
+        let mut network = TestNetwork {
+            nodes: Vec::new(),
+            accounts: Vec::new(),
+            authority: PublicKey::genesis(),
+            authority_key: None,
+            validator_keys,
+            genesis,
+        };
+        for _ in 0..count {
+            network.add_node().await;
+        }
+        network
+    }
+
+    /// Starts one more unconnected node and returns its index.
+    pub async fn add_node(&mut self) -> usize {
+        self.add_node_with(SyncMode::Full).await
+    }
+
+    /// Like `add_node`, catching up with `sync_mode` once connected.
+    pub async fn add_node_with(&mut self, sync_mode: SyncMode) -> usize {
+        let index = self.nodes.len();
+        let consensus: Box<dyn ConsensusEngine> = if self.genesis.validators.is_empty() {
+            Box::new(InstantSeal::new(
+                self.authority.clone(),
+                SealMode::Interval(Duration::from_secs(3600)),
+            ))
+        } else {
+            Box::new(ConsensusManager::new())
+        };
+        let mut chain = Blockchain::new(consensus, self.genesis.clone());
+        if let Some(keypair) = self.authority_key.take() {
+            chain.set_signer(keypair);
+        }
+        if !self.validator_keys.is_empty() {
+            let (keypair, vote_key) = self.validator_keys.remove(0);
+            chain.set_signer(keypair);
+            chain.set_vote_key(vote_key);
+        }
+        let blockchain = Arc::new(RwLock::new(chain));
+
+        let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+        let data_dir =
+            std::env::temp_dir().join(format!("flux-test-{}-{}", std::process::id(), port));
+        let config = NetworkConfig {
+            sync_mode,
+            ..NetworkConfig::memory(port, data_dir.clone())
+        };
+        let network = P2PNetwork::new(blockchain.clone(), config)
+            .await
+            .expect("Failed to start test network");
+        let peer_id = network.local_peer_id();
+        let handle = network.handle();
+        blockchain.write().await.set_network(handle.clone());
+        tokio::spawn(async move {
+            if let Err(e) = network.run().await {
+                panic!("Test network failed: {}", e);
+            }
+        });
+
+        self.nodes.push(TestNode {
+            blockchain,
+            network: handle,
+            peer_id,
+            address: memory_address(port).with(Protocol::P2p(peer_id.into())),
+            data_dir,
+        });
+        index
+    }
+
+    /// Has node `from` dial node `to`.
+    pub fn connect(&self, from: usize, to: usize) {
+        self.nodes[from]
+            .network
+            .dial(self.nodes[to].address.clone())
+            .expect("Network task stopped");
+    }
+
+    pub fn connect_all(&self) {
+        for from in 0..self.nodes.len() {
+            for to in from + 1..self.nodes.len() {
+                self.connect(from, to);
+            }
+        }
+    }
+
+    /// Waits until every node has finished the handshake with every other,
+    /// returning whether they did before `TIMEOUT`.
+    pub async fn wait_for_full_mesh(&self) -> bool {
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        while tokio::time::Instant::now() < deadline {
+            let mut handshaken = 0;
+            for node in &self.nodes {
+                let peers = node.network.peers().await.expect("Network task stopped");
+                handshaken += peers.iter().filter(|peer| peer.handshaken).count();
+            }
+            if handshaken == self.nodes.len() * (self.nodes.len() - 1) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+
+    /// A signed transfer of `amount` from funded account `from`.
+    pub fn transfer(&self, from: usize, amount: u64, nonce: u64) -> Transaction {
+        let account = &self.accounts[from];
+        let mut transaction =
+            Transaction::new(account.public_key(), PublicKey::genesis(), amount, nonce);
+        transaction.sign(account.sign(transaction.hash().as_bytes()));
+        transaction
+    }
+
+    /// Has the authority node produce a block.
+    pub async fn produce_block(&self) {
+        self.nodes[0]
+            .blockchain
+            .read()
+            .await
+            .mine_block()
+            .await
+            .expect("Authority failed to produce a block");
+    }
+
+    /// Has every node of a `with_validators` network try to produce a
+    /// block. Only the one scheduled for the current slot can, once.
+    pub async fn produce_scheduled_block(&self) {
+        for node in &self.nodes {
+            let _ = node.blockchain.read().await.mine_block().await;
+        }
+    }
+}
@@ -0,0 +1,294 @@
+mod common;
+
+use flux::blockchain::Transaction;
+use flux::consensus::dpos::EPOCH_LENGTH;
+use flux::crypto::Hashable;
+use flux::network::{NetworkEvent, SyncMode};
+
+use common::{TestNetwork, TestNode, TIMEOUT};
+use std::time::Duration;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blocks_propagate_to_connected_nodes() {
+    let network = TestNetwork::new(3).await;
+    network.connect_all();
+
+    for _ in 0..3 {
+        network.produce_block().await;
+    }
+
+    for node in &network.nodes {
+        assert!(node.wait_for_height(3).await);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn late_node_syncs_missed_blocks() {
+    let mut network = TestNetwork::new(1).await;
+    for _ in 0..5 {
+        network.produce_block().await;
+    }
+
+    let late = network.add_node().await;
+    network.connect(late, 0);
+
+    assert!(network.nodes[late].wait_for_height(5).await);
+    assert_eq!(
+        network.nodes[late]
+            .blockchain
+            .read()
+            .await
+            .get_latest_block()
+            .await
+            .hash(),
+        network.nodes[0]
+            .blockchain
+            .read()
+            .await
+            .get_latest_block()
+            .await
+            .hash()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compact_blocks_fetch_transactions_missing_from_the_mempool() {
+    let network = TestNetwork::new(2).await;
+    network.connect_all();
+    network.produce_block().await;
+    assert!(network.nodes[1].wait_for_height(1).await);
+
+    // Gossiped, so node 1 can rebuild it from its mempool.
+    let known = network.transfer(0, 10, 0);
+    network.nodes[0]
+        .blockchain
+        .read()
+        .await
+        .submit_transaction(known.clone())
+        .await
+        .unwrap();
+    // Only ever seen by node 0, so node 1 has to ask for it.
+    let unknown = network.transfer(1, 20, 0);
+    network.nodes[0]
+        .blockchain
+        .read()
+        .await
+        .add_transaction(unknown.clone())
+        .await
+        .unwrap();
+    assert!(wait_for_mempool(&network.nodes[1], &known).await);
+
+    network.produce_block().await;
+    assert!(network.nodes[1].wait_for_height(2).await);
+    let block = network.nodes[1]
+        .blockchain
+        .read()
+        .await
+        .get_latest_block()
+        .await;
+    let hashes: Vec<_> = block.transactions.iter().map(|tx| tx.hash()).collect();
+    assert!(hashes.contains(&known.hash()));
+    assert!(hashes.contains(&unknown.hash()));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transactions_are_relayed_by_announcement() {
+    // A line, so node 2 only hears of transactions through node 1.
+    let network = TestNetwork::new(3).await;
+    network.connect(0, 1);
+    network.connect(1, 2);
+    network.produce_block().await;
+    assert!(network.nodes[2].wait_for_height(1).await);
+
+    let transactions = [network.transfer(0, 10, 0), network.transfer(1, 20, 0)];
+    for transaction in &transactions {
+        network.nodes[0]
+            .blockchain
+            .read()
+            .await
+            .submit_transaction(transaction.clone())
+            .await
+            .unwrap();
+    }
+
+    for transaction in &transactions {
+        assert!(wait_for_mempool(&network.nodes[2], transaction).await);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn snapshot_sync_skips_blocks_before_the_snapshot() {
+    let mut network = TestNetwork::new(1).await;
+    let transfer = network.transfer(0, 10, 0);
+    network.nodes[0]
+        .blockchain
+        .read()
+        .await
+        .submit_transaction(transfer.clone())
+        .await
+        .unwrap();
+    // Past the first epoch, where the first snapshot is taken.
+    let height = EPOCH_LENGTH + 5;
+    for _ in 0..height {
+        network.produce_block().await;
+    }
+
+    // The header after the snapshot block commits to the snapshot's state;
+    // its hash is what an operator would get from a trusted source.
+    let checkpoint = network.nodes[0]
+        .blockchain
+        .read()
+        .await
+        .block_hashes_by_height()
+        .await[&EPOCH_LENGTH];
+    let late = network
+        .add_node_with(SyncMode::Snapshot { checkpoint })
+        .await;
+    network.connect(late, 0);
+
+    assert!(network.nodes[late].wait_for_height(height).await);
+    let late = &network.nodes[late].blockchain;
+    assert!(late.read().await.get_blocks_by_range(1, 1).await.is_empty());
+    for account in &network.accounts {
+        assert_eq!(
+            late.read()
+                .await
+                .get_account_balance(&account.public_key())
+                .await,
+            network.nodes[0]
+                .blockchain
+                .read()
+                .await
+                .get_account_balance(&account.public_key())
+                .await
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_and_traffic_are_observable() {
+    let network = TestNetwork::new(2).await;
+    let mut events = network.nodes[0].network.subscribe();
+    network.connect(0, 1);
+
+    let connected = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            if let Ok(NetworkEvent::PeerConnected { peer, .. }) = events.recv().await {
+                return peer;
+            }
+        }
+    })
+    .await
+    .expect("No connection event");
+    assert_eq!(connected, network.nodes[1].peer_id);
+
+    // The handshake finishes shortly after the connection opens.
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    let peer = loop {
+        let peers = network.nodes[0].network.peers().await.unwrap();
+        match peers.into_iter().next() {
+            Some(peer) if peer.handshaken => break peer,
+            _ => assert!(tokio::time::Instant::now() < deadline),
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    assert_eq!(peer.peer_id, network.nodes[1].peer_id);
+    assert!(!peer.addresses.is_empty());
+    assert_eq!(peer.head_height, Some(0));
+
+    let traffic = network.nodes[0].network.traffic().await.unwrap();
+    let status = traffic.topic("sync/status");
+    assert!(status.messages_out > 0 && status.bytes_out > 0);
+    assert!(status.messages_in > 0 && status.bytes_in > 0);
+    assert_eq!(traffic.total().messages_in, status.messages_in);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_on_another_chain_are_disconnected() {
+    let ours = TestNetwork::new(1).await;
+    // The same chain ID with another genesis, and another chain ID.
+    let forked = TestNetwork::new(1).await;
+    let other = TestNetwork::with_chain_id(1, "flux-other").await;
+    let node = &ours.nodes[0];
+    let mut events = node.network.subscribe();
+
+    for stranger in [&forked.nodes[0], &other.nodes[0]] {
+        node.network.dial(stranger.address.clone()).unwrap();
+        let disconnected = tokio::time::timeout(TIMEOUT, async {
+            loop {
+                if let Ok(NetworkEvent::PeerDisconnected { peer }) = events.recv().await {
+                    return peer;
+                }
+            }
+        })
+        .await
+        .expect("Peer on another chain stayed connected");
+        assert_eq!(disconnected, stranger.peer_id);
+    }
+
+    let peers = node.network.peers().await.unwrap();
+    assert!(peers.iter().all(|peer| !peer.handshaken));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn validators_gossip_votes_into_a_commit_certificate() {
+    let network = TestNetwork::with_validators(3).await;
+    network.connect_all();
+    assert!(network.wait_for_full_mesh().await);
+
+    // More than two thirds of three is all of them, so a certificate on any
+    // node is made of votes from the other two.
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    let mut finalized = Vec::new();
+    while finalized.len() < network.nodes.len() {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "Not every node finalized a block"
+        );
+        network.produce_scheduled_block().await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        finalized.clear();
+        for node in &network.nodes {
+            let blockchain = node.blockchain.read().await;
+            let height = blockchain.consensus().await.finalized_height();
+            if let Some(height) = height {
+                finalized.push((height, blockchain.block_hashes_by_height().await[&height]));
+            }
+        }
+    }
+
+    let node_0 = network.nodes[0].blockchain.read().await;
+    for (height, hash) in finalized {
+        assert_eq!(
+            node_0.block_hashes_by_height().await.get(&height),
+            Some(&hash)
+        );
+    }
+    for node in &network.nodes {
+        let blockchain = node.blockchain.read().await;
+        let height = blockchain.consensus().await.finalized_height().unwrap();
+        let hash = blockchain.block_hashes_by_height().await[&height];
+        let certificate = blockchain.get_commit_certificate(&hash).await.unwrap();
+        assert_eq!(certificate.signer_count(), 3);
+    }
+}
+
+async fn wait_for_mempool(node: &TestNode, transaction: &Transaction) -> bool {
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    while tokio::time::Instant::now() < deadline {
+        let mempool = node
+            .blockchain
+            .read()
+            .await
+            .get_mempool_transactions()
+            .await;
+        if mempool
+            .iter()
+            .any(|pending| pending.hash() == transaction.hash())
+        {
+            return true;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    false
+}